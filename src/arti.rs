@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use arti_client::config::{BoolOrAuto, TorClientConfigBuilder};
+use arti_client::{HsId, KeystoreSelector, StreamPrefs, TorClient};
+use futures_util::stream::BoxStream;
+use futures_util::{FutureExt, Stream, StreamExt};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tor_cell::relaycell::msg::{Connected, End};
+use tor_hscrypto::pk::{HsClientDescEncKey, HsClientDescEncSecretKey, HsIdKeypair};
+use tor_hsservice::config::restricted_discovery::HsClientNickname;
+use tor_hsservice::config::OnionServiceConfigBuilder;
+use tor_hsservice::status::{Problem, State};
+use tor_hsservice::{HsNickname, RendRequest, RunningOnionService};
+use tor_llcrypto::pk::{curve25519, ed25519};
+use tor_proto::stream::IncomingStreamRequest;
+use torut::onion;
+
+use crate::backend::{self, TorStream};
+use crate::control::BootstrapPhase;
+use crate::metrics::ForwardMetrics;
+use crate::publish::{self, PublishStates};
+use crate::relay::{self, ConnectionOptions};
+use crate::{Export, LocalAddr, OnionPipeEvent, PipeError, PublishState, Result};
+
+// Arti runs in-process, so there is no control port: onions are launched as
+// tor-hsservice services whose streams onionpipe forwards itself, and imports
+// connect through the client directly rather than through SOCKS.
+
+pub(crate) type ArtiClient = Arc<TorClient<tor_rtcompat::PreferredRuntime>>;
+
+/// An Arti client, created when the pipe starts.
+pub(crate) struct Arti {
+    state_dir: path::PathBuf,
+    temp_dir: Option<tempfile::TempDir>,
+    client: Option<ArtiClient>,
+    bootstrap: Option<JoinHandle<arti_client::Result<()>>>,
+}
+
+impl Arti {
+    /// Keep Arti's state in `state_dir`, or in a new directory under
+    /// `temp_dir` that is removed on close.
+    pub fn new(state_dir: Option<&path::Path>, temp_dir: &path::Path) -> Result<Arti> {
+        let (state_dir, temp_dir) = match state_dir {
+            Some(state_dir) => (state_dir.to_path_buf(), None),
+            None => {
+                let temp_dir = tempfile::tempdir_in(temp_dir)?;
+                (temp_dir.path().to_path_buf(), Some(temp_dir))
+            }
+        };
+        Ok(Arti {
+            state_dir,
+            temp_dir,
+            client: None,
+            bootstrap: None,
+        })
+    }
+
+    /// Create the client and bootstrap it in the background.
+    pub fn start(&mut self) -> Result<()> {
+        let config = TorClientConfigBuilder::from_directories(
+            self.state_dir.join("state"),
+            self.state_dir.join("cache"),
+        )
+        .build()
+        .map_err(|err| PipeError::Config(err.to_string()))?;
+        let client = TorClient::builder()
+            .config(config)
+            .create_unbootstrapped()?;
+        let bootstrapping = client.clone();
+        self.bootstrap = Some(tokio::spawn(async move { bootstrapping.bootstrap().await }));
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn client(&self) -> Result<&ArtiClient> {
+        self.client.as_ref().ok_or(PipeError::NotRunning)
+    }
+
+    pub fn control(&self) -> Result<ArtiControl> {
+        Ok(ArtiControl {
+            client: self.client()?.clone(),
+            services: HashMap::new(),
+        })
+    }
+
+    pub fn connector(&self) -> Result<backend::Connector> {
+        Ok(backend::Connector::Arti(self.client()?.clone()))
+    }
+
+    /// Report whether bootstrapping has failed.
+    pub fn check(&mut self) -> Result<()> {
+        if !self
+            .bootstrap
+            .as_ref()
+            .is_some_and(|task| task.is_finished())
+        {
+            return Ok(());
+        }
+        match self.bootstrap.take().and_then(|task| task.now_or_never()) {
+            Some(Ok(Err(err))) => Err(err.into()),
+            Some(Err(err)) => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn close(&mut self) -> Result<()> {
+        if let Some(task) = self.bootstrap.take() {
+            task.abort();
+        }
+        self.client = None;
+        if let Some(temp_dir) = self.temp_dir.take() {
+            temp_dir.close()?;
+        }
+        Ok(())
+    }
+}
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Onion services launched on an Arti client, by service ID. Dropping it stops
+/// them.
+pub(crate) struct ArtiControl {
+    client: ArtiClient,
+    services: HashMap<String, Service>,
+}
+
+struct Service {
+    // The service stops when this is dropped.
+    _service: Arc<RunningOnionService>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+    }
+}
+
+impl ArtiControl {
+    pub fn bootstrap_phase(&self) -> BootstrapPhase {
+        let status = self.client.bootstrap_status();
+        let (progress, tag) = if status.ready_for_traffic() {
+            (100, "done")
+        } else if status.blocked().is_some() {
+            (bootstrap_progress(status.as_frac()), "blocked")
+        } else {
+            (bootstrap_progress(status.as_frac()), "starting")
+        };
+        BootstrapPhase {
+            progress,
+            tag: tag.to_string(),
+            summary: status.to_string(),
+        }
+    }
+
+    /// Launch an onion service for `export`, forwarding its streams to the
+    /// export's local address.
+    pub fn add_onion(
+        &mut self,
+        export: &Export,
+        publish_states: &PublishStates,
+        events: &broadcast::Sender<OnionPipeEvent>,
+        metrics: Option<Arc<ForwardMetrics>>,
+    ) -> Result<()> {
+        let onion_addr = export.remote_key.public().get_onion_address();
+        let service_id = onion_addr.get_address_without_dot_onion();
+        let hs_id = hs_id(&onion_addr.into())?;
+        let config = service_config(&service_id, &export.authorized_clients)?;
+        let keypair = ed25519::ExpandedKeypair::from_secret_key_bytes(export.remote_key.as_bytes())
+            .ok_or_else(|| PipeError::Config(format!("{}: invalid onion key", onion_addr)))?;
+        // The service is named after its onion, so a key left in a persistent
+        // keystore by an earlier run is the same key and can be reused.
+        let disabled = || PipeError::Config(format!("{}: onion service disabled", onion_addr));
+        let (service, requests): (_, BoxStream<'static, RendRequest>) = match self
+            .client
+            .launch_onion_service_with_hsid(config.clone(), HsIdKeypair::from(keypair))
+        {
+            Ok(Some((service, requests))) => (service, requests.boxed()),
+            Ok(None) => return Err(disabled()),
+            Err(err) => match self.client.launch_onion_service(config) {
+                Ok(Some((service, requests))) if service.onion_address() == Some(hs_id) => {
+                    (service, requests.boxed())
+                }
+                Ok(None) => return Err(disabled()),
+                _ => return Err(err.into()),
+            },
+        };
+        let tasks = vec![
+            tokio::spawn(watch_status(
+                service.status_events(),
+                onion_addr,
+                publish_states.clone(),
+                events.clone(),
+            )),
+            tokio::spawn(serve(
+                requests,
+                export.local_addr.clone(),
+                export.remote_ports.clone(),
+                export.connection.clone(),
+                metrics,
+            )),
+        ];
+        self.services.insert(
+            service_id,
+            Service {
+                _service: service,
+                tasks,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn del_onion(&mut self, onion_addr: &onion::OnionAddressV3) -> Result<()> {
+        match self
+            .services
+            .remove(&onion_addr.get_address_without_dot_onion())
+        {
+            Some(_) => Ok(()),
+            None => Err(PipeError::ForwardNotFound(onion_addr.to_string())),
+        }
+    }
+
+    pub fn client_auth_add(
+        &mut self,
+        onion_addr: &onion::OnionAddress,
+        client_key: &crypto_box::SecretKey,
+    ) -> Result<()> {
+        let hs_id = hs_id(onion_addr)?;
+        self.client
+            .remove_service_discovery_key(KeystoreSelector::Primary, hs_id)?;
+        self.client.insert_service_discovery_key(
+            KeystoreSelector::Primary,
+            hs_id,
+            HsClientDescEncSecretKey::from(curve25519::StaticSecret::from(*client_key.as_bytes())),
+        )?;
+        Ok(())
+    }
+}
+
+/// Connect to an onion through the client.
+pub(crate) async fn connect(
+    client: &ArtiClient,
+    onion_addr: &onion::OnionAddress,
+    port: u16,
+) -> Result<Box<dyn TorStream>> {
+    let mut prefs = StreamPrefs::new();
+    prefs.connect_to_onion_services(BoolOrAuto::Explicit(true));
+    let stream = client
+        .connect_with_prefs((onion_addr.to_string(), port), &prefs)
+        .await?;
+    Ok(Box::new(stream))
+}
+
+fn hs_id(onion_addr: &onion::OnionAddress) -> Result<HsId> {
+    HsId::from_str(&onion_addr.to_string())
+        .map_err(|err| PipeError::Config(format!("{}: {}", onion_addr, err)))
+}
+
+fn bootstrap_progress(frac: f32) -> u8 {
+    // Only a client ready for traffic is done.
+    ((frac * 100.0) as u8).min(99)
+}
+
+fn service_config(
+    service_id: &str,
+    authorized_clients: &[crypto_box::PublicKey],
+) -> Result<tor_hsservice::OnionServiceConfig> {
+    let config_err = |err: &dyn std::fmt::Display| PipeError::Config(err.to_string());
+    let mut config = OnionServiceConfigBuilder::default();
+    config.nickname(HsNickname::new(service_id.to_string()).map_err(|err| config_err(&err))?);
+    if !authorized_clients.is_empty() {
+        let restricted_discovery = config.restricted_discovery();
+        restricted_discovery.enabled(true);
+        for client in authorized_clients {
+            // Clients only need a unique name here.
+            let nickname = format!("client-{}", NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed));
+            restricted_discovery.static_keys().access().push((
+                HsClientNickname::from_str(&nickname).map_err(|err| config_err(&err))?,
+                HsClientDescEncKey::from(curve25519::PublicKey::from(*client.as_bytes())),
+            ));
+        }
+    }
+    config.build().map_err(|err| config_err(&err))
+}
+
+/// Forward each stream requested on an onion port in `ports` to `local_addr`.
+async fn serve<S>(
+    requests: S,
+    local_addr: LocalAddr,
+    ports: Vec<u16>,
+    options: ConnectionOptions,
+    metrics: Option<Arc<ForwardMetrics>>,
+) where
+    S: Stream<Item = RendRequest> + Send + 'static,
+{
+    let mut streams = Box::pin(tor_hsservice::handle_rend_requests(requests));
+    while let Some(request) = streams.next().await {
+        let allowed = matches!(
+            request.request(),
+            IncomingStreamRequest::Begin(begin) if ports.contains(&begin.port())
+        );
+        let (local_addr, options, metrics) = (local_addr.clone(), options.clone(), metrics.clone());
+        tokio::spawn(async move {
+            if !allowed {
+                let _ = request.reject(End::new_misc()).await;
+                return;
+            }
+            let _active = metrics.as_ref().map(|metrics| metrics.open());
+            let local_stream = match options.connect(&local_addr).await {
+                Ok(local_stream) => local_stream,
+                Err(err) => {
+                    if let Some(ref metrics) = metrics {
+                        metrics.failed();
+                    }
+                    tracing::debug!("failed to connect to {}: {}", local_addr, err);
+                    let _ = request.reject(End::new_misc()).await;
+                    return;
+                }
+            };
+            let remote_stream = match request.accept(Connected::new_empty()).await {
+                Ok(remote_stream) => remote_stream,
+                Err(err) => {
+                    tracing::debug!("failed to accept onion stream: {}", err);
+                    return;
+                }
+            };
+            relay::relay_export(local_stream, remote_stream, &local_addr, &options, metrics).await;
+        });
+    }
+}
+
+/// Track the publish state of an onion from its service status.
+async fn watch_status<S>(
+    mut statuses: S,
+    onion_addr: onion::OnionAddressV3,
+    publish_states: PublishStates,
+    events: broadcast::Sender<OnionPipeEvent>,
+) where
+    S: Stream<Item = tor_hsservice::status::OnionServiceStatus> + Unpin,
+{
+    while let Some(status) = statuses.next().await {
+        let state = publish_state(status.state(), status.current_problem());
+        if let Some(state) = publish_states.set(&onion_addr, state) {
+            if let Some(event) = publish::state_event(onion_addr, state) {
+                let _ = events.send(event);
+            }
+        }
+    }
+}
+
+fn publish_state(state: State, problem: Option<&Problem>) -> PublishState {
+    match state {
+        // Arti doesn't say how many HSDirs have the descriptor, only that
+        // the service is reachable.
+        State::Running | State::DegradedReachable => PublishState::Uploaded { hs_dirs: 1 },
+        State::Broken | State::DegradedUnreachable => PublishState::Failed {
+            reason: match problem {
+                Some(problem) => format!("{:?}", problem),
+                None => format!("{:?}", state),
+            },
+        },
+        _ => PublishState::Pending,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_config_restricted_discovery() {
+        let service_id = onion::TorSecretKeyV3::from([1u8; 64])
+            .public()
+            .get_onion_address()
+            .get_address_without_dot_onion();
+        let config = service_config(&service_id, &[]).unwrap();
+        assert_eq!(config.nickname().to_string(), service_id);
+
+        let clients = [
+            crypto_box::PublicKey::from([9u8; 32]),
+            crypto_box::PublicKey::from([10u8; 32]),
+        ];
+        service_config(&service_id, &clients).unwrap();
+    }
+
+    #[test]
+    fn publish_states() {
+        assert_eq!(
+            publish_state(State::Running, None),
+            PublishState::Uploaded { hs_dirs: 1 }
+        );
+        assert_eq!(
+            publish_state(State::Bootstrapping, None),
+            PublishState::Pending
+        );
+        assert_eq!(
+            publish_state(State::Broken, None),
+            PublishState::Failed {
+                reason: "Broken".to_string()
+            }
+        );
+        assert_eq!(bootstrap_progress(0.5), 50);
+        assert_eq!(bootstrap_progress(1.0), 99);
+    }
+}
@@ -0,0 +1,823 @@
+#[cfg(feature = "libtor")]
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::{io, path};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use torut::onion;
+
+#[cfg(feature = "arti")]
+use crate::arti;
+use crate::control::{BootstrapPhase, ControlConn, TorTraffic};
+use crate::{
+    config, metrics, publish, relay, Export, LocalAddr, OnionPipeEvent, PipeError, Result,
+};
+#[cfg(feature = "libtor")]
+use crate::{tor_log, STARTUP_POLL_INTERVAL};
+
+// onionpipe drives Tor through its control port and reaches imported onions
+// through its SOCKS port. The backend decides where that Tor comes from: run
+// in-process with libtor, an existing Tor such as a system daemon, or Arti,
+// which onionpipe drives directly instead.
+
+/// Where onionpipe's Tor comes from.
+#[derive(Clone, PartialEq)]
+pub enum TorBackend {
+    /// Run Tor in-process, in a temporary data directory.
+    #[cfg(feature = "libtor")]
+    Embedded,
+    /// Use a Tor that is already running, through its control and SOCKS
+    /// ports. It is left running when onionpipe exits.
+    External {
+        control_addr: LocalAddr,
+        socks_addr: LocalAddr,
+        auth: ControlAuth,
+    },
+    /// Run Arti in-process, keeping its state in `state_dir` or a temporary
+    /// directory.
+    #[cfg(feature = "arti")]
+    Arti { state_dir: Option<path::PathBuf> },
+}
+
+impl Default for TorBackend {
+    #[cfg(feature = "libtor")]
+    fn default() -> TorBackend {
+        TorBackend::Embedded
+    }
+
+    #[cfg(all(feature = "arti", not(feature = "libtor")))]
+    fn default() -> TorBackend {
+        TorBackend::Arti { state_dir: None }
+    }
+
+    /// The usual ports of a system Tor.
+    #[cfg(not(any(feature = "libtor", feature = "arti")))]
+    fn default() -> TorBackend {
+        TorBackend::External {
+            control_addr: LocalAddr::TCP(([127, 0, 0, 1], 9051).into()),
+            socks_addr: LocalAddr::TCP(([127, 0, 0, 1], 9050).into()),
+            auth: ControlAuth::Auto,
+        }
+    }
+}
+
+/// How to authenticate to Tor's control port.
+#[derive(Clone, PartialEq)]
+pub enum ControlAuth {
+    /// The strongest method Tor offers that doesn't need a password.
+    Auto,
+    /// Send the cookie from the given file, or the one Tor reports.
+    Cookie(Option<path::PathBuf>),
+    /// Prove knowledge of the cookie from the given file, or the one Tor
+    /// reports, without sending it.
+    SafeCookie(Option<path::PathBuf>),
+    Password(String),
+}
+
+#[cfg(not(all(feature = "libtor", feature = "arti")))]
+fn not_built(feature: &str) -> PipeError {
+    PipeError::Config(format!(
+        "onionpipe was built without the {} feature",
+        feature
+    ))
+}
+
+impl TryInto<TorBackend> for config::Tor {
+    type Error = PipeError;
+
+    fn try_into(self) -> Result<TorBackend> {
+        match self {
+            #[cfg(feature = "libtor")]
+            config::Tor::Embedded => Ok(TorBackend::Embedded),
+            #[cfg(not(feature = "libtor"))]
+            config::Tor::Embedded => Err(not_built("libtor")),
+            #[cfg(feature = "arti")]
+            config::Tor::Arti { state_dir } => Ok(TorBackend::Arti {
+                state_dir: state_dir.map(path::PathBuf::from),
+            }),
+            #[cfg(not(feature = "arti"))]
+            config::Tor::Arti { .. } => Err(not_built("arti")),
+            config::Tor::External {
+                control_addr,
+                socks_addr,
+                auth,
+                cookie_file,
+                password,
+            } => {
+                let cookie_file = cookie_file.map(path::PathBuf::from);
+                let auth = match (auth.as_deref(), password) {
+                    (None, None) => ControlAuth::Auto,
+                    (None | Some("password"), Some(password)) => ControlAuth::Password(password),
+                    (Some("password"), None) => {
+                        return Err(PipeError::Config(
+                            "tor password authentication requires a password".to_string(),
+                        ))
+                    }
+                    (Some("cookie"), None) => ControlAuth::Cookie(cookie_file),
+                    (Some("safecookie"), None) => ControlAuth::SafeCookie(cookie_file),
+                    (Some(auth), _) => {
+                        return Err(PipeError::Config(format!(
+                            "unsupported tor authentication {:?}, expected \"cookie\", \"safecookie\" or \"password\"",
+                            auth
+                        )))
+                    }
+                };
+                Ok(TorBackend::External {
+                    control_addr: control_addr.parse()?,
+                    socks_addr: socks_addr.parse()?,
+                    auth,
+                })
+            }
+        }
+    }
+}
+
+impl TorBackend {
+    /// Set up Tor's addresses, and its data directory in `temp_dir` if
+    /// onionpipe runs it.
+    #[cfg_attr(
+        not(any(feature = "libtor", feature = "arti")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn prepare(&self, temp_dir: &path::Path) -> Result<Tor> {
+        match self {
+            #[cfg(feature = "libtor")]
+            TorBackend::Embedded => {
+                let temp_dir = tempfile::tempdir_in(temp_dir)?;
+                let data_dir = temp_dir.path().join("data");
+                std::fs::create_dir(&data_dir)?;
+                std::fs::set_permissions(&data_dir, std::fs::Permissions::from_mode(0o700))?;
+                Ok(Tor::Embedded(Embedded {
+                    ports: Ports {
+                        control_addr: LocalAddr::Unix(data_dir.join("control.sock")),
+                        socks_addr: LocalAddr::Unix(data_dir.join("socks.sock")),
+                        auth: ControlAuth::Auto,
+                    },
+                    temp_dir: Some(temp_dir),
+                    data_dir,
+                    thread: None,
+                }))
+            }
+            TorBackend::External {
+                control_addr,
+                socks_addr,
+                auth,
+            } => Ok(Tor::External(Ports {
+                control_addr: control_addr.clone(),
+                socks_addr: socks_addr.clone(),
+                auth: auth.clone(),
+            })),
+            #[cfg(feature = "arti")]
+            TorBackend::Arti { state_dir } => {
+                Ok(Tor::Arti(arti::Arti::new(state_dir.as_deref(), temp_dir)?))
+            }
+        }
+    }
+}
+
+/// The Tor used by a pipe.
+pub(crate) enum Tor {
+    #[cfg(feature = "libtor")]
+    Embedded(Embedded),
+    External(Ports),
+    #[cfg(feature = "arti")]
+    Arti(arti::Arti),
+}
+
+/// The control and SOCKS ports of a C Tor.
+pub(crate) struct Ports {
+    control_addr: LocalAddr,
+    socks_addr: LocalAddr,
+    auth: ControlAuth,
+}
+
+#[cfg(feature = "libtor")]
+pub(crate) struct Embedded {
+    ports: Ports,
+    temp_dir: Option<tempfile::TempDir>,
+    data_dir: path::PathBuf,
+    thread: Option<TorThread>,
+}
+
+impl Ports {
+    /// Open an authenticated control connection.
+    async fn connect(&self) -> Result<ControlConn<Box<dyn TorStream>>> {
+        let mut conn = ControlConn::new(connect(&self.control_addr).await?);
+        authenticate(&mut conn, &self.auth).await?;
+        Ok(conn)
+    }
+}
+
+impl Tor {
+    fn ports(&self) -> Option<&Ports> {
+        match self {
+            #[cfg(feature = "libtor")]
+            Tor::Embedded(embedded) => Some(&embedded.ports),
+            Tor::External(ports) => Some(ports),
+            #[cfg(feature = "arti")]
+            Tor::Arti(_) => None,
+        }
+    }
+
+    /// Whether onionpipe runs a C Tor, which should stop with onionpipe.
+    pub fn is_embedded(&self) -> bool {
+        #[cfg(feature = "libtor")]
+        if let Tor::Embedded(_) = self {
+            return true;
+        }
+        false
+    }
+
+    /// Start Tor if onionpipe runs it, and wait for it to take commands.
+    #[cfg_attr(not(feature = "libtor"), allow(unused_variables))]
+    pub async fn start(
+        &mut self,
+        log_level: tracing::Level,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<()> {
+        match self {
+            #[cfg(feature = "libtor")]
+            Tor::Embedded(embedded) => embedded.start(log_level, deadline).await,
+            Tor::External(_) => Ok(()),
+            #[cfg(feature = "arti")]
+            Tor::Arti(arti) => arti.start(),
+        }
+    }
+
+    /// Open a connection to control Tor.
+    pub async fn connect(&self) -> Result<TorControl> {
+        match self {
+            #[cfg(feature = "libtor")]
+            Tor::Embedded(embedded) => Ok(TorControl::Port(embedded.ports.connect().await?)),
+            Tor::External(ports) => Ok(TorControl::Port(ports.connect().await?)),
+            #[cfg(feature = "arti")]
+            Tor::Arti(arti) => Ok(TorControl::Arti(arti.control()?)),
+        }
+    }
+
+    /// Track descriptor uploads for Tors that report them as control port
+    /// events. HS_DESC events are read on their own connection, so that
+    /// waiting for them doesn't hold up commands.
+    pub async fn watch_uploads(
+        &self,
+        publish_states: &publish::PublishStates,
+        events: &tokio::sync::broadcast::Sender<OnionPipeEvent>,
+    ) -> Result<Option<tokio::task::JoinHandle<Result<()>>>> {
+        let ports = match self.ports() {
+            Some(ports) => ports,
+            None => return Ok(None),
+        };
+        Ok(Some(tokio::spawn(publish::watch_uploads(
+            ports.connect().await?,
+            publish_states.clone(),
+            events.clone(),
+        ))))
+    }
+
+    /// How to reach imported onions.
+    pub fn connector(&self) -> Result<Connector> {
+        match self {
+            #[cfg(feature = "libtor")]
+            Tor::Embedded(embedded) => Ok(Connector::Socks(embedded.ports.socks_addr.clone())),
+            Tor::External(ports) => Ok(Connector::Socks(ports.socks_addr.clone())),
+            #[cfg(feature = "arti")]
+            Tor::Arti(arti) => arti.connector(),
+        }
+    }
+
+    /// Report whether Tor is still running.
+    pub fn check(&mut self) -> Result<()> {
+        match self {
+            #[cfg(feature = "libtor")]
+            Tor::Embedded(Embedded {
+                thread: Some(thread),
+                ..
+            }) => thread.check(),
+            #[cfg(feature = "arti")]
+            Tor::Arti(arti) => arti.check(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove the data directory of a Tor onionpipe runs.
+    pub async fn close(&mut self) -> Result<()> {
+        match self {
+            #[cfg(feature = "libtor")]
+            Tor::Embedded(embedded) => {
+                tokio::fs::remove_dir_all(&embedded.data_dir).await?;
+                if let Some(temp_dir) = embedded.temp_dir.take() {
+                    temp_dir.close()?;
+                }
+                Ok(())
+            }
+            Tor::External(_) => Ok(()),
+            #[cfg(feature = "arti")]
+            Tor::Arti(arti) => arti.close(),
+        }
+    }
+}
+
+#[cfg(feature = "libtor")]
+impl Embedded {
+    async fn start(
+        &mut self,
+        log_level: tracing::Level,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<()> {
+        let (control_sock, socks_sock) = match (&self.ports.control_addr, &self.ports.socks_addr) {
+            (LocalAddr::Unix(control_sock), LocalAddr::Unix(socks_sock)) => {
+                (control_sock, socks_sock)
+            }
+            _ => unreachable!("embedded tor listens on unix sockets"),
+        };
+        let tor_log = self.data_dir.join("tor.log");
+        tor_log::capture(&tor_log)?;
+        let handle = libtor::Tor::new()
+            .flag(libtor::TorFlag::ControlSocket(
+                control_sock.to_str().unwrap().into(),
+            ))
+            .flag(libtor::TorFlag::DataDirectory(
+                self.data_dir.to_str().unwrap().into(),
+            ))
+            .flag(libtor::TorFlag::LogTo(
+                tor_log::tor_log_level(log_level),
+                libtor::log::LogDestination::File(tor_log.to_str().unwrap().to_string()),
+            ))
+            .flag(libtor::TorFlag::Custom(format!(
+                "SocksPort unix:{} OnionTrafficOnly ExtendedErrors",
+                socks_sock.display()
+            )))
+            .start_background();
+        let thread = self.thread.insert(TorThread {
+            handle: Some(handle),
+        });
+        wait_for_file(control_sock, deadline, thread).await
+    }
+}
+
+/// A connection for controlling Tor: a control port, or Arti itself.
+pub(crate) enum TorControl {
+    Port(ControlConn<Box<dyn TorStream>>),
+    #[cfg(feature = "arti")]
+    Arti(arti::ArtiControl),
+}
+
+impl TorControl {
+    /// Have Tor exit when this connection closes, should onionpipe die
+    /// without stopping it.
+    pub async fn take_ownership(&mut self) -> Result<()> {
+        match self {
+            TorControl::Port(conn) => Ok(conn.take_ownership().await?),
+            #[cfg(feature = "arti")]
+            TorControl::Arti(_) => Ok(()),
+        }
+    }
+
+    pub async fn bootstrap_phase(&mut self) -> Result<BootstrapPhase> {
+        match self {
+            TorControl::Port(conn) => Ok(conn.bootstrap_phase().await?),
+            #[cfg(feature = "arti")]
+            TorControl::Arti(control) => Ok(control.bootstrap_phase()),
+        }
+    }
+
+    /// Bytes read and written by Tor, if the backend reports them. Arti
+    /// doesn't.
+    pub async fn traffic(&mut self) -> Result<Option<TorTraffic>> {
+        match self {
+            TorControl::Port(conn) => Ok(Some(conn.traffic().await?)),
+            #[cfg(feature = "arti")]
+            TorControl::Arti(_) => Ok(None),
+        }
+    }
+
+    /// Publish an export. The control port reports its descriptor uploads
+    /// through [`Tor::watch_uploads`]; Arti reports them to `publish_states`
+    /// directly. Returns the relay applying the export's connection options,
+    /// if one was needed, which listens in `relay_dir`; the onion stops
+    /// working when it is dropped.
+    #[cfg_attr(not(feature = "arti"), allow(unused_variables))]
+    pub async fn add_onion(
+        &mut self,
+        export: &Export,
+        relay_dir: &path::Path,
+        publish_states: &publish::PublishStates,
+        events: &tokio::sync::broadcast::Sender<OnionPipeEvent>,
+        metrics: Option<Arc<metrics::ForwardMetrics>>,
+    ) -> Result<Option<relay::ExportRelay>> {
+        match self {
+            TorControl::Port(conn) => {
+                // Tor connects to the local address itself, unless onionpipe
+                // must relay to apply connection options or count connections.
+                let relay = if export.connection.is_default() && metrics.is_none() {
+                    None
+                } else {
+                    Some(
+                        relay::ExportRelay::start(
+                            relay_dir,
+                            &export.local_addr,
+                            &export.connection,
+                            metrics,
+                        )
+                        .await?,
+                    )
+                };
+                let target = relay
+                    .as_ref()
+                    .map_or(&export.local_addr, |relay| relay.addr());
+                conn.add_onion_v3(
+                    &export.remote_key,
+                    &export
+                        .remote_ports
+                        .iter()
+                        .map(|port| (port.to_owned(), target.to_string()))
+                        .collect::<Vec<_>>(),
+                    &export.authorized_clients,
+                )
+                .await?;
+                Ok(relay)
+            }
+            #[cfg(feature = "arti")]
+            TorControl::Arti(control) => control
+                .add_onion(export, publish_states, events, metrics)
+                .map(|_| None),
+        }
+    }
+
+    pub async fn del_onion(&mut self, onion_addr: &onion::OnionAddressV3) -> Result<()> {
+        match self {
+            TorControl::Port(conn) => Ok(conn
+                .del_onion(&onion_addr.get_address_without_dot_onion())
+                .await?),
+            #[cfg(feature = "arti")]
+            TorControl::Arti(control) => control.del_onion(onion_addr),
+        }
+    }
+
+    /// Add the key used to discover an onion with restricted discovery.
+    pub async fn client_auth_add(
+        &mut self,
+        onion_addr: &onion::OnionAddress,
+        client_key: &crypto_box::SecretKey,
+    ) -> Result<()> {
+        match self {
+            TorControl::Port(conn) => Ok(conn
+                .onion_client_auth_add(&onion_addr.get_address_without_dot_onion(), client_key)
+                .await?),
+            #[cfg(feature = "arti")]
+            TorControl::Arti(control) => control.client_auth_add(onion_addr, client_key),
+        }
+    }
+}
+
+/// How imports reach onions: through a SOCKS port, or Arti itself.
+#[derive(Clone)]
+pub(crate) enum Connector {
+    Socks(LocalAddr),
+    #[cfg(feature = "arti")]
+    Arti(arti::ArtiClient),
+}
+
+impl Connector {
+    pub async fn connect(
+        &self,
+        onion_addr: &onion::OnionAddress,
+        port: u16,
+    ) -> Result<Box<dyn TorStream>> {
+        match self {
+            Connector::Socks(socks_addr) => {
+                let proxy_stream = connect(socks_addr).await?;
+                Ok(crate::socks::connect(proxy_stream, &onion_addr.to_string(), port).await?)
+            }
+            #[cfg(feature = "arti")]
+            Connector::Arti(client) => arti::connect(client, onion_addr, port).await,
+        }
+    }
+}
+
+/// A stream to one of Tor's ports, over TCP or a unix socket.
+pub(crate) trait TorStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S> TorStream for S where S: AsyncRead + AsyncWrite + Send + Unpin {}
+
+pub(crate) async fn connect(addr: &LocalAddr) -> io::Result<Box<dyn TorStream>> {
+    Ok(match addr {
+        LocalAddr::TCP(addr) => Box::new(tokio::net::TcpStream::connect(addr).await?),
+        LocalAddr::Unix(socket_path) => {
+            Box::new(tokio::net::UnixStream::connect(socket_path).await?)
+        }
+    })
+}
+
+async fn authenticate<S>(conn: &mut ControlConn<S>, auth: &ControlAuth) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match auth {
+        ControlAuth::Password(password) => conn.authenticate_password(password).await?,
+        ControlAuth::Cookie(cookie_file) => {
+            let cookie = read_cookie(conn, cookie_file.as_deref()).await?;
+            conn.authenticate_cookie(&cookie).await?;
+        }
+        ControlAuth::SafeCookie(cookie_file) => {
+            let cookie = read_cookie(conn, cookie_file.as_deref()).await?;
+            conn.authenticate_safe_cookie(&cookie).await?;
+        }
+        ControlAuth::Auto => {
+            let info = conn.protocol_info().await?;
+            let cookie_file = info.cookie_file.as_ref().map(path::Path::new);
+            if info.has_method("NULL") {
+                conn.authenticate().await?;
+            } else if info.has_method("SAFECOOKIE") {
+                let cookie = load_cookie(cookie_file).await?;
+                conn.authenticate_safe_cookie(&cookie).await?;
+            } else if info.has_method("COOKIE") {
+                let cookie = load_cookie(cookie_file).await?;
+                conn.authenticate_cookie(&cookie).await?;
+            } else {
+                return Err(PipeError::Config(
+                    "tor control port requires a password".to_string(),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read the cookie from `cookie_file`, or from the file Tor reports.
+async fn read_cookie<S>(
+    conn: &mut ControlConn<S>,
+    cookie_file: Option<&path::Path>,
+) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match cookie_file {
+        Some(cookie_file) => load_cookie(Some(cookie_file)).await,
+        None => {
+            let info = conn.protocol_info().await?;
+            load_cookie(info.cookie_file.as_ref().map(path::Path::new)).await
+        }
+    }
+}
+
+async fn load_cookie(cookie_file: Option<&path::Path>) -> Result<Vec<u8>> {
+    let cookie_file = cookie_file.ok_or_else(|| {
+        PipeError::Config("tor did not report a control auth cookie file".to_string())
+    })?;
+    let cookie = tokio::fs::read(cookie_file).await?;
+    if cookie.len() != 32 {
+        return Err(PipeError::Config(format!(
+            "{}: tor control auth cookie should be 32 bytes",
+            cookie_file.display()
+        )));
+    }
+    Ok(cookie)
+}
+
+/// The thread running Tor, which reports Tor exiting.
+#[cfg(feature = "libtor")]
+struct TorThread {
+    handle: Option<std::thread::JoinHandle<std::result::Result<u8, libtor::Error>>>,
+}
+
+#[cfg(feature = "libtor")]
+impl TorThread {
+    fn check(&mut self) -> Result<()> {
+        match self.handle.take() {
+            Some(handle) if handle.is_finished() => {
+                Err(PipeError::TorExited(match handle.join() {
+                    Ok(Ok(code)) => format!("exit code {}", code),
+                    Ok(Err(err)) => err.to_string(),
+                    Err(_) => "tor thread panicked".to_string(),
+                }))
+            }
+            Some(handle) => {
+                self.handle = Some(handle);
+                Ok(())
+            }
+            None => Err(PipeError::TorExited("already exited".to_string())),
+        }
+    }
+}
+
+pub(crate) fn past_deadline(deadline: Option<tokio::time::Instant>) -> bool {
+    deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline)
+}
+
+#[cfg(feature = "libtor")]
+async fn wait_for_file(
+    path: &path::Path,
+    deadline: Option<tokio::time::Instant>,
+    tor: &mut TorThread,
+) -> Result<()> {
+    loop {
+        if tokio::fs::metadata(path).await.is_ok() {
+            return Ok(());
+        }
+        tor.check()?;
+        if past_deadline(deadline) {
+            return Err(PipeError::ConnTimeout);
+        }
+        tokio::time::sleep(STARTUP_POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[cfg(feature = "libtor")]
+    #[tokio::test]
+    async fn startup_errors() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let socket_path = tmp_dir.path().join("control.sock");
+        let mut tor = TorThread {
+            handle: Some(std::thread::spawn(|| loop {
+                std::thread::park();
+            })),
+        };
+        let deadline = Some(tokio::time::Instant::now());
+        assert!(matches!(
+            wait_for_file(&socket_path, deadline, &mut tor).await,
+            Err(PipeError::ConnTimeout)
+        ));
+
+        let mut tor = TorThread {
+            handle: Some(std::thread::spawn(|| Ok(1))),
+        };
+        while !tor.handle.as_ref().unwrap().is_finished() {
+            tokio::task::yield_now().await;
+        }
+        assert!(matches!(
+            wait_for_file(&socket_path, None, &mut tor).await,
+            Err(PipeError::TorExited(_))
+        ));
+    }
+
+    #[test]
+    fn try_into_backend() {
+        let backend: TorBackend = config::Tor::External {
+            control_addr: "127.0.0.1:9051".to_string(),
+            socks_addr: "unix:/run/tor/socks".to_string(),
+            auth: Some("safecookie".to_string()),
+            cookie_file: Some("/run/tor/control.authcookie".to_string()),
+            password: None,
+        }
+        .try_into()
+        .unwrap();
+        assert!(matches!(
+            backend,
+            TorBackend::External {
+                control_addr: LocalAddr::TCP(_),
+                socks_addr: LocalAddr::Unix(_),
+                auth: ControlAuth::SafeCookie(Some(_)),
+            }
+        ));
+
+        let external = |auth: Option<&str>, password: Option<&str>| config::Tor::External {
+            control_addr: "unix:/run/tor/control".to_string(),
+            socks_addr: "127.0.0.1:9050".to_string(),
+            auth: auth.map(String::from),
+            cookie_file: None,
+            password: password.map(String::from),
+        };
+        let backend: TorBackend = external(None, Some("hunter2")).try_into().unwrap();
+        assert!(matches!(
+            backend,
+            TorBackend::External { auth: ControlAuth::Password(ref password), .. } if password == "hunter2"
+        ));
+        let backend: TorBackend = external(None, None).try_into().unwrap();
+        assert!(matches!(
+            backend,
+            TorBackend::External {
+                auth: ControlAuth::Auto,
+                ..
+            }
+        ));
+        for (auth, password) in [
+            (Some("password"), None),
+            (Some("cookie"), Some("hunter2")),
+            (Some("hmac"), None),
+        ] {
+            let result: Result<TorBackend> = external(auth, password).try_into();
+            assert!(matches!(result, Err(PipeError::Config(_))));
+        }
+
+        let result: Result<TorBackend> = config::Tor::Arti { state_dir: None }.try_into();
+        assert_eq!(result.is_ok(), cfg!(feature = "arti"));
+    }
+
+    /// Serve a control connection that offers `methods`, answering each
+    /// command in turn, and return the commands received.
+    fn control_server(
+        methods: &'static str,
+        cookie_file: &path::Path,
+        cookie: [u8; 32],
+    ) -> (
+        ControlConn<tokio::io::DuplexStream>,
+        tokio::task::JoinHandle<Vec<String>>,
+    ) {
+        use hmac::{Hmac, Mac, NewMac};
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (client, server) = tokio::io::duplex(4096);
+        let cookie_file = cookie_file.to_str().unwrap().to_string();
+        let server_task = tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(server);
+            let mut lines = BufReader::new(read).lines();
+            let mut received = vec![];
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply = if line == "PROTOCOLINFO 1" {
+                    format!(
+                        "250-PROTOCOLINFO 1\r\n250-AUTH METHODS={} COOKIEFILE=\"{}\"\r\n250-VERSION Tor=\"0.4.7.13\"\r\n250 OK\r\n",
+                        methods, cookie_file
+                    )
+                } else if let Some(client_nonce) = line.strip_prefix("AUTHCHALLENGE SAFECOOKIE ") {
+                    let server_nonce = [9u8; 32];
+                    let mut hmac = Hmac::<sha2::Sha256>::new_from_slice(
+                        b"Tor safe cookie authentication server-to-controller hash",
+                    )
+                    .unwrap();
+                    hmac.update(&cookie);
+                    hmac.update(&hex::decode(client_nonce).unwrap());
+                    hmac.update(&server_nonce);
+                    format!(
+                        "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}\r\n",
+                        hex::encode_upper(hmac.finalize().into_bytes()),
+                        hex::encode_upper(server_nonce)
+                    )
+                } else {
+                    "250 OK\r\n".to_string()
+                };
+                received.push(line);
+                write.write_all(reply.as_bytes()).await.unwrap();
+            }
+            received
+        });
+        (ControlConn::new(client), server_task)
+    }
+
+    #[tokio::test]
+    async fn authenticate_methods() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let cookie_file = tmp_dir.path().join("control_auth_cookie");
+        let cookie = [7u8; 32];
+        fs::write(&cookie_file, cookie).unwrap();
+
+        let (mut conn, server_task) = control_server("NULL", &cookie_file, cookie);
+        authenticate(&mut conn, &ControlAuth::Auto).await.unwrap();
+        drop(conn);
+        assert_eq!(
+            server_task.await.unwrap(),
+            vec!["PROTOCOLINFO 1", "AUTHENTICATE"]
+        );
+
+        let (mut conn, server_task) = control_server("COOKIE,SAFECOOKIE", &cookie_file, cookie);
+        authenticate(&mut conn, &ControlAuth::Auto).await.unwrap();
+        drop(conn);
+        let received = server_task.await.unwrap();
+        assert_eq!(received.len(), 3);
+        assert!(received[1].starts_with("AUTHCHALLENGE SAFECOOKIE "));
+        assert!(received[2].starts_with("AUTHENTICATE "));
+
+        let (mut conn, server_task) = control_server("COOKIE", &cookie_file, cookie);
+        authenticate(&mut conn, &ControlAuth::Cookie(None))
+            .await
+            .unwrap();
+        drop(conn);
+        assert_eq!(
+            server_task.await.unwrap()[1],
+            format!("AUTHENTICATE {}", hex::encode_upper(cookie))
+        );
+
+        // A server that doesn't know the cookie is refused.
+        let (mut conn, _) = control_server("SAFECOOKIE", &cookie_file, [8u8; 32]);
+        assert!(
+            authenticate(&mut conn, &ControlAuth::SafeCookie(Some(cookie_file)))
+                .await
+                .is_err()
+        );
+
+        let (mut conn, server_task) = control_server("HASHEDPASSWORD", tmp_dir.path(), cookie);
+        authenticate(
+            &mut conn,
+            &ControlAuth::Password("pass \"word\"".to_string()),
+        )
+        .await
+        .unwrap();
+        drop(conn);
+        assert_eq!(
+            server_task.await.unwrap(),
+            vec![r#"AUTHENTICATE "pass \"word\"""#]
+        );
+
+        let (mut conn, _) = control_server("HASHEDPASSWORD", tmp_dir.path(), cookie);
+        assert!(matches!(
+            authenticate(&mut conn, &ControlAuth::Auto).await,
+            Err(PipeError::Config(_))
+        ));
+    }
+}
@@ -1,9 +1,20 @@
 use std::fs::File;
-use std::io::Read;
+use std::io::{IsTerminal, Read};
+use std::str::FromStr;
 
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use zeroize::Zeroizing;
 
-use onionpipe::{config, OnionPipe, PipeError, Result};
+use onionpipe::daemon::{self, Request, Response};
+use onionpipe::secrets::{ExecSecretStore, FileSecretStore, SecretStore, SecretsError};
+use onionpipe::systemd;
+use onionpipe::{config, parse, ForwardHandle, ForwardStatus, OnionPipe, PipeError, Result};
+
+/// Environment variable holding the secret store passphrase.
+const PASSPHRASE_ENV: &str = "ONIONPIPE_PASSPHRASE";
 
 #[derive(Parser)]
 #[command(name = "onionpipe")]
@@ -12,35 +23,205 @@ struct Cli {
     #[arg(long)]
     config: Option<std::path::PathBuf>,
 
+    /// Daemon control socket, defaults to onionpipe/control.sock in the
+    /// user's runtime dir.
+    #[arg(long, global = true)]
+    control_socket: Option<std::path::PathBuf>,
+
+    /// Log more detail, repeat for more.
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Log less detail, repeat for less.
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    quiet: u8,
+
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Seconds to wait for Tor to start and bootstrap, 0 to wait
+    /// indefinitely.
+    #[arg(long)]
+    startup_timeout: Option<u64>,
+
+    /// Serve Prometheus metrics on this address, such as 127.0.0.1:9100.
+    #[arg(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
+
+    /// Serve health checks on this address, such as 127.0.0.1:9101.
+    #[arg(long)]
+    health_addr: Option<std::net::SocketAddr>,
+
+    /// Wait until exports are reachable, then print their onion addresses.
+    #[arg(long, global = true)]
+    wait_published: bool,
+
+    /// Read the secret store passphrase from this file, rather than from
+    /// $ONIONPIPE_PASSPHRASE or a prompt.
+    #[arg(long, global = true)]
+    passphrase_file: Option<std::path::PathBuf>,
+
+    /// Fetch and store keys by running this helper command, rather than
+    /// keeping them in files. Its arguments are split on whitespace.
+    #[arg(long, global = true)]
+    secrets_helper: Option<String>,
+
+    /// Append logs to this file rather than stderr.
+    #[arg(long, global = true)]
+    log_file: Option<std::path::PathBuf>,
+
     #[clap(subcommand)]
     commands: Option<Commands>,
 
     forwards: Vec<String>,
+
+    /// The passphrase taken from $ONIONPIPE_PASSPHRASE.
+    #[arg(skip)]
+    env_passphrase: Option<Zeroizing<String>>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     #[clap(subcommand)]
     Service(ServiceCommands),
+    #[clap(subcommand)]
+    Client(ClientCommands),
+    #[clap(subcommand)]
+    Secrets(SecretsCommands),
+    /// Run forwards and accept control requests on the control socket.
+    Daemon { forwards: Vec<String> },
+    /// Manage forwards on a running daemon.
+    #[clap(subcommand)]
+    Forward(ForwardCommands),
+    /// Show the Tor bootstrap status of a running daemon.
+    Status,
+}
+
+#[derive(Subcommand)]
+enum ForwardCommands {
+    Add {
+        forward: String,
+    },
+    /// Remove an export by onion address or an import by local address.
+    Rm {
+        addr: String,
+    },
+    Ls,
 }
 
 #[derive(Subcommand)]
 enum ServiceCommands {
-    Add { name: String },
-    Delete { name: String },
+    Add {
+        name: String,
+    },
+    Delete {
+        name: String,
+    },
     List,
+    /// Add a service with the key of a C Tor HiddenServiceDir.
+    Import {
+        #[arg(long)]
+        from_hs_dir: std::path::PathBuf,
+        name: String,
+    },
+    /// Write a service's key to a new C Tor HiddenServiceDir.
+    Export {
+        #[arg(long)]
+        to_hs_dir: std::path::PathBuf,
+        name: String,
+    },
 }
 
-#[tokio::main]
-async fn main() {
-    let cli = Cli::parse();
+#[derive(Subcommand)]
+enum ClientCommands {
+    Add {
+        name: String,
+    },
+    Delete {
+        name: String,
+    },
+    List,
+    /// Show a client's public key, and its private key for accessing a
+    /// service.
+    Show {
+        name: String,
+        /// The service to access, by name or onion address.
+        #[arg(long)]
+        service: Option<String>,
+    },
+}
 
-    let result = match &cli.commands {
-        Some(Commands::Service(ServiceCommands::Add { ref name })) => add_service(name).await,
-        Some(Commands::Service(ServiceCommands::Delete { ref name })) => delete_service(name).await,
-        Some(Commands::Service(ServiceCommands::List)) => list_services().await,
-        None => run(cli).await,
-    };
+#[derive(Subcommand)]
+enum SecretsCommands {
+    /// Encrypt a plaintext secret store in place with a passphrase.
+    Migrate,
+}
+
+fn main() {
+    let mut cli = Cli::parse();
+    if let Some(Commands::Daemon { ref mut forwards }) = cli.commands {
+        cli.forwards.append(forwards);
+    }
+    // Take any sockets passed by systemd before anything else opens files.
+    // This clears their environment variables, which is only safe before
+    // the runtime starts its threads.
+    let listen_fds = systemd::ListenFds::from_env();
+    // Likewise the passphrase, so that child processes such as a secrets
+    // helper don't inherit it.
+    cli.env_passphrase = std::env::var(PASSPHRASE_ENV).ok().map(Zeroizing::new);
+    std::env::remove_var(PASSPHRASE_ENV);
+
+    let runtime = tokio::runtime::Runtime::new().expect("failed to start the tokio runtime");
+    let result = runtime.block_on(async move {
+        match &cli.commands {
+            Some(Commands::Service(ServiceCommands::Add { ref name })) => {
+                add_service(&cli, name).await
+            }
+            Some(Commands::Service(ServiceCommands::Delete { ref name })) => {
+                delete_service(&cli, name).await
+            }
+            Some(Commands::Service(ServiceCommands::List)) => list_services(&cli).await,
+            Some(Commands::Service(ServiceCommands::Import {
+                ref from_hs_dir,
+                ref name,
+            })) => import_service(&cli, name, from_hs_dir).await,
+            Some(Commands::Service(ServiceCommands::Export {
+                ref to_hs_dir,
+                ref name,
+            })) => export_service(&cli, name, to_hs_dir).await,
+            Some(Commands::Client(ClientCommands::Add { ref name })) => {
+                add_client(&cli, name).await
+            }
+            Some(Commands::Client(ClientCommands::Delete { ref name })) => {
+                delete_client(&cli, name).await
+            }
+            Some(Commands::Client(ClientCommands::List)) => list_clients(&cli).await,
+            Some(Commands::Secrets(SecretsCommands::Migrate)) => migrate_secrets(&cli).await,
+            Some(Commands::Client(ClientCommands::Show {
+                ref name,
+                ref service,
+            })) => show_client(&cli, name, service.as_deref()).await,
+            Some(Commands::Daemon { .. }) => match control_socket_path(&cli) {
+                Ok(socket_path) => run(cli, Some(socket_path), listen_fds).await,
+                Err(err) => Err(err),
+            },
+            Some(Commands::Forward(ForwardCommands::Add { ref forward })) => {
+                add_forward(&cli, forward).await
+            }
+            Some(Commands::Forward(ForwardCommands::Rm { ref addr })) => {
+                remove_forward(&cli, addr).await
+            }
+            Some(Commands::Forward(ForwardCommands::Ls)) => list_forwards(&cli).await,
+            Some(Commands::Status) => status(&cli).await,
+            None => run(cli, None, listen_fds).await,
+        }
+    });
     let rc = match result {
         Ok(_) => 0,
         Err(e) => {
@@ -51,15 +232,8 @@ async fn main() {
     std::process::exit(rc)
 }
 
-async fn add_service(name: &str) -> Result<()> {
-    let config_dir = match dirs::config_dir() {
-        Some(dir) => dir,
-        None => {
-            return Err(PipeError::CLI("failed to locate config dir".to_string()));
-        }
-    };
-    let secrets_dir = config_dir.join("onionpipe");
-    let mut secret_store = onionpipe::secrets::SecretStore::new(secrets_dir.to_str().unwrap());
+async fn add_service(cli: &Cli, name: &str) -> Result<()> {
+    let mut secret_store = secret_store(cli)?;
     let key_bytes = secret_store.ensure_service(name)?;
     let onion_addr = torut::onion::TorSecretKeyV3::from(key_bytes)
         .public()
@@ -69,15 +243,8 @@ async fn add_service(name: &str) -> Result<()> {
     Ok(())
 }
 
-async fn delete_service(name: &str) -> Result<()> {
-    let config_dir = match dirs::config_dir() {
-        Some(dir) => dir,
-        None => {
-            return Err(PipeError::CLI("failed to locate config dir".to_string()));
-        }
-    };
-    let secrets_dir = config_dir.join("onionpipe");
-    let mut secret_store = onionpipe::secrets::SecretStore::new(secrets_dir.to_str().unwrap());
+async fn delete_service(cli: &Cli, name: &str) -> Result<()> {
+    let mut secret_store = secret_store(cli)?;
     match secret_store.delete_service(name)? {
         Some(()) => {
             println!("service {} deleted", name);
@@ -89,15 +256,8 @@ async fn delete_service(name: &str) -> Result<()> {
     }
 }
 
-async fn list_services() -> Result<()> {
-    let config_dir = match dirs::config_dir() {
-        Some(dir) => dir,
-        None => {
-            return Err(PipeError::CLI("failed to locate config dir".to_string()));
-        }
-    };
-    let secrets_dir = config_dir.join("onionpipe");
-    let secret_store = onionpipe::secrets::SecretStore::new(secrets_dir.to_str().unwrap());
+async fn list_services(cli: &Cli) -> Result<()> {
+    let secret_store = secret_store(cli)?;
     let services = secret_store.list_services()?;
     for service_name in services {
         let key_bytes = secret_store.get_service(&service_name)?.unwrap();
@@ -110,19 +270,268 @@ async fn list_services() -> Result<()> {
     Ok(())
 }
 
-async fn run(cli: Cli) -> Result<()> {
-    unsafe {
-        libc::umask(0o077);
+async fn import_service(cli: &Cli, name: &str, hs_dir: &std::path::Path) -> Result<()> {
+    let key_bytes = secret_store(cli)?.import_hs_dir(name, hs_dir)?;
+    let onion_addr = torut::onion::TorSecretKeyV3::from(key_bytes)
+        .public()
+        .get_onion_address();
+    println!("{}\t{}", name, onion_addr);
+    Ok(())
+}
+
+async fn export_service(cli: &Cli, name: &str, hs_dir: &std::path::Path) -> Result<()> {
+    match secret_store(cli)?.export_hs_dir(name, hs_dir)? {
+        Some(key_bytes) => {
+            let onion_addr = torut::onion::TorSecretKeyV3::from(key_bytes)
+                .public()
+                .get_onion_address();
+            println!("{}\t{}", name, onion_addr);
+            Ok(())
+        }
+        None => Err(PipeError::CLI(format!("{}: service not found", name))),
+    }
+}
+
+/// The secret store of --secrets-helper, or else the one in the user's config
+/// dir, unlocked if it is encrypted.
+fn secret_store(cli: &Cli) -> Result<Box<dyn SecretStore>> {
+    match cli.secrets_helper {
+        Some(ref secrets_helper) => Ok(Box::new(exec_secret_store(
+            secrets_helper
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+        )?)),
+        None => Ok(Box::new(file_secret_store(cli)?)),
+    }
+}
+
+fn file_secret_store(cli: &Cli) -> Result<FileSecretStore> {
+    match dirs::config_dir() {
+        Some(config_dir) => open_secret_store(cli, &config_dir.join("onionpipe")),
+        None => Err(PipeError::CLI("failed to locate config dir".to_string())),
+    }
+}
+
+fn exec_secret_store(secrets_helper: Vec<String>) -> Result<ExecSecretStore> {
+    match secrets_helper.split_first() {
+        Some((program, args)) => Ok(ExecSecretStore::new(program, args)),
+        None => Err(PipeError::CLI("empty secrets helper".to_string())),
+    }
+}
+
+/// Open the secret store in `secrets_dir`. An encrypted store is unlocked if
+/// a passphrase is available, otherwise it is left locked.
+fn open_secret_store(cli: &Cli, secrets_dir: &std::path::Path) -> Result<FileSecretStore> {
+    let mut secret_store = FileSecretStore::new(secrets_dir.to_str().unwrap());
+    if secret_store.is_encrypted() {
+        match passphrase(cli, false)? {
+            Some(passphrase) => secret_store.unlock(&passphrase)?,
+            None => tracing::warn!("secret store is encrypted, but no passphrase was given"),
+        }
+    }
+    Ok(secret_store)
+}
+
+/// The secret store passphrase from --passphrase-file or $ONIONPIPE_PASSPHRASE,
+/// or else prompted for on a terminal. A new passphrase is prompted for twice.
+fn passphrase(cli: &Cli, new: bool) -> Result<Option<Zeroizing<String>>> {
+    if let Some(ref passphrase_file) = cli.passphrase_file {
+        let mut passphrase = Zeroizing::new(std::fs::read_to_string(passphrase_file)?);
+        // The file may end with a line ending, LF or CRLF.
+        let len = passphrase.trim_end_matches(['\r', '\n']).len();
+        passphrase.truncate(len);
+        return Ok(Some(passphrase));
+    }
+    if let Some(ref passphrase) = cli.env_passphrase {
+        return Ok(Some(passphrase.clone()));
+    }
+    if !std::io::stdin().is_terminal() {
+        return Ok(None);
+    }
+    let passphrase = Zeroizing::new(rpassword::prompt_password("Secret store passphrase: ")?);
+    if new && *passphrase != *Zeroizing::new(rpassword::prompt_password("Repeat passphrase: ")?) {
+        return Err(PipeError::CLI("passphrases do not match".to_string()));
+    }
+    Ok(Some(passphrase))
+}
+
+async fn migrate_secrets(cli: &Cli) -> Result<()> {
+    let mut secret_store = file_secret_store(cli)?;
+    if secret_store.is_encrypted() {
+        return Err(SecretsError::AlreadyEncrypted.into());
+    }
+    match passphrase(cli, true)? {
+        Some(passphrase) if !passphrase.is_empty() => secret_store.encrypt(&passphrase)?,
+        _ => return Err(PipeError::CLI("a passphrase is required".to_string())),
+    }
+    println!("secret store encrypted");
+    Ok(())
+}
+
+async fn add_client(cli: &Cli, name: &str) -> Result<()> {
+    // Forwards couldn't authorize a client by any other name.
+    if !parse::is_client_name(name) {
+        return Err(PipeError::CLI(format!(
+            "invalid client name {}: use letters, digits, '-' and '_'",
+            name
+        )));
+    }
+    let key_bytes = secret_store(cli)?.ensure_client(name)?;
+    let public_key = crypto_box::SecretKey::from(key_bytes).public_key();
+    println!(
+        "{}\t{}",
+        name,
+        onionpipe::client_public_key_descriptor(&public_key)
+    );
+    Ok(())
+}
+
+async fn delete_client(cli: &Cli, name: &str) -> Result<()> {
+    match secret_store(cli)?.delete_client(name)? {
+        Some(()) => {
+            println!("client {} deleted", name);
+            Ok(())
+        }
+        None => Err(PipeError::CLI(format!("{}: client not found", name))),
+    }
+}
+
+async fn list_clients(cli: &Cli) -> Result<()> {
+    let secret_store = secret_store(cli)?;
+    for client_name in secret_store.list_clients()? {
+        let key_bytes = secret_store.get_client(&client_name)?.unwrap();
+        let public_key = crypto_box::SecretKey::from(key_bytes).public_key();
+        println!(
+            "{}\t{}",
+            client_name,
+            onionpipe::client_public_key_descriptor(&public_key)
+        );
+    }
+    Ok(())
+}
+
+/// Print the public key to authorize the client with, and if `service` is
+/// given, the `.auth_private` line the client needs to access it.
+async fn show_client(cli: &Cli, name: &str, service: Option<&str>) -> Result<()> {
+    let secret_store = secret_store(cli)?;
+    let key_bytes = match secret_store.get_client(name)? {
+        Some(key_bytes) => key_bytes,
+        None => return Err(PipeError::CLI(format!("{}: client not found", name))),
+    };
+    let secret_key = crypto_box::SecretKey::from(key_bytes);
+    println!(
+        "public\t{}",
+        onionpipe::client_public_key_descriptor(&secret_key.public_key())
+    );
+    if let Some(service) = service {
+        let onion_addr = match service.strip_suffix(".onion") {
+            Some(service_id) => torut::onion::OnionAddressV3::from_str(service_id)?,
+            None => match secret_store.get_service(service)? {
+                Some(key_bytes) => torut::onion::TorSecretKeyV3::from(key_bytes)
+                    .public()
+                    .get_onion_address(),
+                None => return Err(PipeError::CLI(format!("{}: service not found", service))),
+            },
+        };
+        println!(
+            "auth_private\t{}",
+            onionpipe::client_auth_private(&onion_addr, &secret_key)
+        );
+    }
+    Ok(())
+}
+
+fn control_socket_path(cli: &Cli) -> Result<std::path::PathBuf> {
+    match cli
+        .control_socket
+        .clone()
+        .or_else(daemon::default_socket_path)
+    {
+        Some(socket_path) => Ok(socket_path),
+        None => Err(PipeError::CLI(
+            "failed to locate control socket".to_string(),
+        )),
+    }
+}
+
+/// Device and inode of the socket at `socket_path`, to tell whether the path
+/// still refers to the socket this process bound.
+fn socket_id(socket_path: &std::path::Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::symlink_metadata(socket_path)
+        .ok()
+        .map(|meta| (meta.dev(), meta.ino()))
+}
+
+async fn daemon_request(cli: &Cli, request: Request) -> Result<Response> {
+    let socket_path = control_socket_path(cli)?;
+    match daemon::request(&socket_path, &request).await? {
+        Response::Error { message } => Err(PipeError::CLI(message)),
+        response => Ok(response),
+    }
+}
+
+async fn add_forward(cli: &Cli, forward: &str) -> Result<()> {
+    let request = match config::Forward::from(forward.parse::<parse::Forward>()?) {
+        config::Forward::Export(export) => Request::AddExport {
+            export,
+            wait_published: cli.wait_published,
+        },
+        config::Forward::Import(import) => Request::AddImport { import },
+    };
+    if let Response::Exported { onion_addr } = daemon_request(cli, request).await? {
+        println!("{}", onion_addr);
     }
+    Ok(())
+}
 
-    let mut pipe_builder = OnionPipe::defaults();
+async fn remove_forward(cli: &Cli, addr: &str) -> Result<()> {
+    let request = if addr.ends_with(".onion") {
+        Request::RemoveExport {
+            onion_addr: addr.to_string(),
+        }
+    } else {
+        Request::RemoveImport {
+            local_addr: addr.to_string(),
+        }
+    };
+    daemon_request(cli, request).await?;
+    Ok(())
+}
 
-    if let Some(config_dir) = dirs::config_dir() {
-        let secrets_dir = config_dir.join("onionpipe");
-        pipe_builder = pipe_builder.secrets_dir(secrets_dir.to_str().unwrap());
+async fn list_forwards(cli: &Cli) -> Result<()> {
+    if let Response::Forwards { forwards } = daemon_request(cli, Request::ListForwards).await? {
+        for forward in forwards {
+            println!("{}", forward);
+        }
     }
+    Ok(())
+}
 
-    let cfg: config::Config;
+async fn status(cli: &Cli) -> Result<()> {
+    if let Response::Status { bootstrap } = daemon_request(cli, Request::Status).await? {
+        println!(
+            "bootstrapped {}% ({}): {}",
+            bootstrap.progress, bootstrap.tag, bootstrap.summary
+        );
+    }
+    Ok(())
+}
+
+async fn run(
+    cli: Cli,
+    control_socket: Option<std::path::PathBuf>,
+    listen_fds: systemd::ListenFds,
+) -> Result<()> {
+    unsafe {
+        libc::umask(0o077);
+    }
+    let notifier = systemd::Notifier::from_env();
+
+    let mut pipe_builder = OnionPipe::defaults().listen_fds(listen_fds);
+
+    let mut cfg: config::Config;
     if let Some(config_path) = cli.config.as_ref() {
         let mut config_file = File::open(config_path)?;
         let mut config_json = String::new();
@@ -131,12 +540,186 @@ async fn run(cli: Cli) -> Result<()> {
 
         cfg = serde_json::from_str(&config_json)?;
     } else {
-        cfg = cli.forwards.try_into()?;
+        cfg = cli.forwards.clone().try_into()?;
     }
 
-    pipe_builder = pipe_builder.config(cfg)?;
+    let log_level = log_level(&cli, cfg.log_level.as_deref())?;
+    init_logging(&cli, log_level)?;
+
+    // The pipe and the control socket each get a handle on the same store.
+    // The config's helper is always taken out, so that the builder cannot
+    // replace the store chosen here; --secrets-helper wins over it.
+    let cfg_secrets_helper = cfg.secrets_helper.take();
+    let secrets_helper = match cli.secrets_helper {
+        Some(ref secrets_helper) => Some(
+            secrets_helper
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+        ),
+        None => cfg_secrets_helper,
+    };
+    if secrets_helper.is_some() && cfg.secrets_dir.is_some() {
+        // Keys are kept by one or the other, never both.
+        return Err(PipeError::Config(
+            "a secrets helper and secrets_dir cannot both be set".to_string(),
+        ));
+    }
+    let secrets_dir = cfg
+        .secrets_dir
+        .take()
+        .map(std::path::PathBuf::from)
+        .or_else(|| dirs::config_dir().map(|dir| dir.join("onionpipe")));
+    let secret_store: Option<Box<dyn SecretStore>> = match (secrets_helper, secrets_dir) {
+        (Some(secrets_helper), _) => {
+            let secret_store = exec_secret_store(secrets_helper)?;
+            pipe_builder = pipe_builder.secret_store(secret_store.clone());
+            Some(Box::new(secret_store))
+        }
+        (None, Some(secrets_dir)) => {
+            let secret_store = open_secret_store(&cli, &secrets_dir)?;
+            pipe_builder = pipe_builder.secret_store(secret_store.clone());
+            Some(Box::new(secret_store))
+        }
+        (None, None) => None,
+    };
+    pipe_builder = pipe_builder.config(cfg)?.log_level(log_level);
+    if let Some(startup_timeout) = cli.startup_timeout {
+        pipe_builder = pipe_builder.startup_timeout(match startup_timeout {
+            0 => None,
+            secs => Some(std::time::Duration::from_secs(secs)),
+        });
+    }
+    if let Some(metrics_addr) = cli.metrics_addr {
+        pipe_builder = pipe_builder.metrics_addr(metrics_addr);
+    }
+    if let Some(health_addr) = cli.health_addr {
+        pipe_builder = pipe_builder.health_addr(health_addr);
+    }
 
     let mut onion_pipe = pipe_builder.new().await?;
-    onion_pipe.run().await?;
+    let mut events = onion_pipe.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) if event.is_error() => tracing::warn!("{}", event),
+                Ok(event) => tracing::info!("{}", event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("{} events dropped", n)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+    let mut control_socket_id = None;
+    if let Some(ref socket_path) = control_socket {
+        let listener = daemon::Server::bind(socket_path)?;
+        control_socket_id = socket_id(socket_path);
+        let server = daemon::Server::new(onion_pipe.forward_handle(), secret_store);
+        tokio::spawn(async move {
+            if let Err(err) = server.serve(listener).await {
+                tracing::error!("control socket failed: {}", err);
+            }
+        });
+        tracing::info!(
+            "listening for control requests on {}",
+            socket_path.display()
+        );
+    }
+    if let Some(ref notifier) = notifier {
+        tokio::spawn(systemd::supervise(
+            notifier.clone(),
+            onion_pipe.forward_handle(),
+            onion_pipe.subscribe(),
+        ));
+    }
+    let shutdown = onion_pipe.shutdown_handle();
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => tracing::info!("interrupt received, shutting down"),
+            _ = sigterm.recv() => tracing::info!("terminate received, shutting down"),
+        }
+        if let Some(notifier) = notifier {
+            notifier.notify("STOPPING=1");
+        }
+        shutdown.shutdown();
+    });
+    // Tor keeps retrying uploads, so the pipe runs on until they succeed.
+    let wait_task = if cli.wait_published {
+        let forward_handle = onion_pipe.forward_handle();
+        Some(tokio::spawn(async move {
+            if let Err(err) = wait_published(&forward_handle).await {
+                tracing::debug!("stopped waiting for exports to be published: {}", err);
+            }
+        }))
+    } else {
+        None
+    };
+    let result = onion_pipe.run().await;
+    if let Some(ref socket_path) = control_socket {
+        // Leave the path alone if it no longer refers to our socket.
+        if control_socket_id.is_some() && socket_id(socket_path) == control_socket_id {
+            let _ = std::fs::remove_file(socket_path);
+        }
+    }
+    if let Some(wait_task) = wait_task {
+        wait_task.abort();
+    }
+    result
+}
+
+/// Wait for the descriptors of all exports to be uploaded, printing each
+/// onion address as it becomes reachable.
+async fn wait_published(forward_handle: &ForwardHandle) -> Result<()> {
+    for forward in forward_handle.forwards().await? {
+        if let ForwardStatus::Export { onion_addr, .. } = forward {
+            let onion_addr = torut::onion::OnionAddressV3::from_str(
+                onion_addr.strip_suffix(".onion").unwrap_or(&onion_addr),
+            )?;
+            forward_handle.wait_published(&onion_addr).await?;
+            println!("{}", onion_addr);
+        }
+    }
     Ok(())
 }
+
+/// The configured log level, adjusted by the verbosity flags.
+fn log_level(cli: &Cli, cfg_log_level: Option<&str>) -> Result<tracing::Level> {
+    const LEVELS: [tracing::Level; 5] = [
+        tracing::Level::ERROR,
+        tracing::Level::WARN,
+        tracing::Level::INFO,
+        tracing::Level::DEBUG,
+        tracing::Level::TRACE,
+    ];
+    let base = match cfg_log_level {
+        Some(log_level) => onionpipe::parse_log_level(log_level)?,
+        None => tracing::Level::INFO,
+    };
+    let i = LEVELS.iter().position(|level| *level == base).unwrap() as i32 + cli.verbose as i32
+        - cli.quiet as i32;
+    Ok(LEVELS[i.clamp(0, LEVELS.len() as i32 - 1) as usize])
+}
+
+fn init_logging(cli: &Cli, log_level: tracing::Level) -> Result<()> {
+    let writer = match cli.log_file {
+        Some(ref log_path) => {
+            let log_file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_path)?;
+            BoxMakeWriter::new(std::sync::Mutex::new(log_file))
+        }
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let builder = tracing_subscriber::fmt().with_max_level(log_level);
+    match cli.log_format {
+        LogFormat::Text => builder
+            .with_ansi(cli.log_file.is_none())
+            .with_writer(writer)
+            .try_init(),
+        LogFormat::Json => builder.json().with_writer(writer).try_init(),
+    }
+    .map_err(|err| PipeError::CLI(format!("failed to initialize logging: {}", err)))
+}
@@ -6,6 +6,24 @@ use crate as onionpipe;
 pub struct Config {
     pub temp_dir: Option<String>,
     pub secrets_dir: Option<String>,
+    /// Fetch and store keys by running this helper program, with its
+    /// arguments, instead of keeping them in `secrets_dir`, which may then
+    /// not be set.
+    pub secrets_helper: Option<Vec<String>>,
+    /// Log level for onionpipe and Tor: "error", "warn", "info", "debug" or
+    /// "trace". Defaults to "info".
+    pub log_level: Option<String>,
+    /// Seconds to wait for Tor to start and bootstrap before giving up, 0 to
+    /// wait indefinitely. Defaults to 120.
+    pub startup_timeout: Option<u64>,
+    /// The Tor to use. Defaults to running Tor inside onionpipe, or Arti if
+    /// onionpipe was built with only the `arti` feature.
+    pub tor: Option<Tor>,
+    /// Serve Prometheus metrics over HTTP at `/metrics` on this "host:port".
+    pub metrics_addr: Option<String>,
+    /// Serve liveness and readiness probes over HTTP at `/healthz` and
+    /// `/readyz` on this "host:port".
+    pub health_addr: Option<String>,
     pub exports: Vec<Export>,
     pub imports: Vec<Import>,
 }
@@ -17,6 +35,12 @@ impl TryInto<Config> for Vec<String> {
         let mut cfg: Config = Config {
             temp_dir: None,
             secrets_dir: None,
+            secrets_helper: None,
+            log_level: None,
+            startup_timeout: None,
+            tor: None,
+            metrics_addr: None,
+            health_addr: None,
             exports: vec![],
             imports: vec![],
         };
@@ -37,12 +61,93 @@ pub struct Export {
     pub local_addr: String,
     pub service_name: Option<String>,
     pub remote_ports: Vec<u16>,
+    /// Clients allowed to discover the onion, either client names in the
+    /// secret store or public keys in `descriptor:x25519:<base32>` form.
+    /// When set, the onion is published with restricted discovery.
+    pub authorized_clients: Option<Vec<String>>,
+    /// Limits and socket options for connections to `local_addr`.
+    pub connection: Option<Connection>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Import {
     pub remote_addr: String,
     pub local_addr: String,
+    /// Client key used to access an onion with restricted discovery, either a
+    /// client name in the secret store or a private key in
+    /// `descriptor:x25519:<base32>` form.
+    pub client_key: Option<String>,
+    /// Octal file mode for a `unix:` import socket, such as "0660".
+    pub unix_mode: Option<String>,
+    /// Owner of a `unix:` import socket, a user name or uid.
+    pub unix_owner: Option<String>,
+    /// Group of a `unix:` import socket, a group name or gid.
+    pub unix_group: Option<String>,
+    /// Listen on the socket of this name passed by systemd socket activation,
+    /// as named by `FileDescriptorName=`, rather than one bound to
+    /// `local_addr`.
+    pub listen_fd_name: Option<String>,
+    /// Retry connections to the onion that fail. Defaults to a single
+    /// attempt.
+    pub retry: Option<Retry>,
+    /// Limits and socket options for connections accepted on `local_addr`.
+    pub connection: Option<Connection>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Retry {
+    /// Connection attempts before giving up, including the first.
+    pub attempts: u32,
+    /// Milliseconds to wait before the first retry, doubled for each retry
+    /// after it. Defaults to 500.
+    pub backoff_ms: Option<u64>,
+    /// Longest wait between retries in milliseconds. Defaults to 10000.
+    pub max_backoff_ms: Option<u64>,
+    /// Milliseconds after a local connection is accepted to stop retrying
+    /// it. Defaults to no deadline.
+    pub deadline_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Connection {
+    /// Close a connection after this many milliseconds without data in
+    /// either direction.
+    pub idle_timeout_ms: Option<u64>,
+    /// Close a connection this many milliseconds after it was opened.
+    pub max_lifetime_ms: Option<u64>,
+    /// Enable TCP keepalive on the local socket, probing after this many
+    /// idle milliseconds.
+    pub keepalive_ms: Option<u64>,
+    /// Set TCP_NODELAY on the local socket.
+    pub nodelay: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum Tor {
+    /// Run Tor inside onionpipe, with a temporary data directory.
+    Embedded,
+    /// Use a Tor that is already running, such as a system daemon.
+    External {
+        /// Control port address, "host:port" or "unix:/path".
+        control_addr: String,
+        /// SOCKS port address, "host:port" or "unix:/path".
+        socks_addr: String,
+        /// Control port authentication: "cookie", "safecookie" or
+        /// "password". Defaults to "password" if a password is given,
+        /// otherwise the strongest method Tor offers.
+        auth: Option<String>,
+        /// Cookie file for "cookie" and "safecookie" authentication, if not
+        /// the one Tor reports.
+        cookie_file: Option<String>,
+        password: Option<String>,
+    },
+    /// Run Arti inside onionpipe. Requires the `arti` feature.
+    Arti {
+        /// Directory for Arti's state and cache, which keeps its guards and
+        /// directory between runs. Defaults to a temporary directory.
+        state_dir: Option<String>,
+    },
 }
 
 pub enum Forward {
@@ -62,14 +167,37 @@ mod tests {
             {
               "temp_dir": "/tmp/foo",
               "secrets_dir": "/tmp/secrets",
+              "secrets_helper": ["pass-helper", "--prefix", "onionpipe"],
+              "log_level": "debug",
+              "startup_timeout": 300,
+              "metrics_addr": "127.0.0.1:9100",
+              "health_addr": "127.0.0.1:9101",
+              "tor": {
+                "backend": "external",
+                "control_addr": "unix:/run/tor/control",
+                "socks_addr": "127.0.0.1:9050",
+                "auth": "safecookie"
+              },
               "exports": [{
                 "local_addr": "127.0.0.1:4566",
                 "service_name": "some_service",
-                "remote_ports": [4567]
+                "remote_ports": [4567],
+                "authorized_clients": ["alice"],
+                "connection": {"idle_timeout_ms": 300000, "nodelay": true}
               }],
               "imports": [{
                 "remote_addr": "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:80",
-                "local_addr": "127.0.0.1:8080"
+                "local_addr": "127.0.0.1:8080",
+                "client_key": "bob",
+                "listen_fd_name": "web",
+                "retry": {"attempts": 5, "backoff_ms": 250, "deadline_ms": 30000}
+              }, {
+                "remote_addr": "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:22",
+                "local_addr": "unix:/run/onion-ssh.sock",
+                "unix_mode": "0660",
+                "unix_owner": "root",
+                "unix_group": "ssh-users",
+                "connection": {"max_lifetime_ms": 3600000, "keepalive_ms": 60000}
               }]
             }"#;
         let config: Config = serde_json::from_str(json_str).unwrap();
@@ -78,17 +206,72 @@ mod tests {
             Config {
                 temp_dir: Some("/tmp/foo".to_string()),
                 secrets_dir: Some("/tmp/secrets".to_string()),
+                secrets_helper: Some(vec![
+                    "pass-helper".to_string(),
+                    "--prefix".to_string(),
+                    "onionpipe".to_string(),
+                ]),
+                log_level: Some("debug".to_string()),
+                startup_timeout: Some(300),
+                tor: Some(Tor::External {
+                    control_addr: "unix:/run/tor/control".to_string(),
+                    socks_addr: "127.0.0.1:9050".to_string(),
+                    auth: Some("safecookie".to_string()),
+                    cookie_file: None,
+                    password: None,
+                }),
+                metrics_addr: Some("127.0.0.1:9100".to_string()),
+                health_addr: Some("127.0.0.1:9101".to_string()),
                 exports: vec![Export {
                     local_addr: "127.0.0.1:4566".to_string(),
                     service_name: Some("some_service".to_string()),
                     remote_ports: vec![4567],
+                    authorized_clients: Some(vec!["alice".to_string()]),
+                    connection: Some(Connection {
+                        idle_timeout_ms: Some(300000),
+                        max_lifetime_ms: None,
+                        keepalive_ms: None,
+                        nodelay: Some(true),
+                    }),
                 }],
-                imports: vec![Import {
-                    remote_addr:
-                        "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:80"
-                            .to_string(),
-                    local_addr: "127.0.0.1:8080".to_string(),
-                }],
+                imports: vec![
+                    Import {
+                        remote_addr:
+                            "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:80"
+                                .to_string(),
+                        local_addr: "127.0.0.1:8080".to_string(),
+                        client_key: Some("bob".to_string()),
+                        unix_mode: None,
+                        unix_owner: None,
+                        unix_group: None,
+                        listen_fd_name: Some("web".to_string()),
+                        retry: Some(Retry {
+                            attempts: 5,
+                            backoff_ms: Some(250),
+                            max_backoff_ms: None,
+                            deadline_ms: Some(30000),
+                        }),
+                        connection: None,
+                    },
+                    Import {
+                        remote_addr:
+                            "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:22"
+                                .to_string(),
+                        local_addr: "unix:/run/onion-ssh.sock".to_string(),
+                        client_key: None,
+                        unix_mode: Some("0660".to_string()),
+                        unix_owner: Some("root".to_string()),
+                        unix_group: Some("ssh-users".to_string()),
+                        listen_fd_name: None,
+                        retry: None,
+                        connection: Some(Connection {
+                            idle_timeout_ms: None,
+                            max_lifetime_ms: Some(3600000),
+                            keepalive_ms: Some(60000),
+                            nodelay: None,
+                        }),
+                    }
+                ],
             }
         );
     }
@@ -0,0 +1,511 @@
+use hmac::{Hmac, Mac, NewMac};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use torut::control::{Conn, ConnError, UnauthenticatedConnError};
+
+// torut's AuthenticatedConn doesn't support client authorization, so
+// onionpipe sends the few control commands it needs directly, reusing torut's
+// reply parser.
+pub struct ControlConn<S> {
+    conn: Conn<S>,
+}
+
+#[derive(Error, Debug)]
+pub enum ControlError {
+    #[error("tor control connection failed: {0}")]
+    Conn(#[from] ConnError),
+    /// Tor answered a command with an error reply, kept so that the user can
+    /// see why.
+    #[error("tor rejected {command}: {code} {reply}")]
+    Rejected {
+        command: String,
+        code: u16,
+        reply: String,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, ControlError>;
+
+impl<S> ControlConn<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S) -> ControlConn<S> {
+        ControlConn {
+            conn: Conn::new(stream),
+        }
+    }
+
+    pub async fn authenticate(&mut self) -> Result<()> {
+        self.command("AUTHENTICATE").await?;
+        Ok(())
+    }
+
+    /// Ask Tor how it accepts authentication. Tor only answers this once
+    /// before the connection is authenticated.
+    pub async fn protocol_info(&mut self) -> Result<ProtocolInfo> {
+        let lines = self.command("PROTOCOLINFO 1").await?;
+        lines
+            .iter()
+            .find_map(|line| line.strip_prefix("AUTH "))
+            .and_then(ProtocolInfo::parse)
+            .ok_or(ConnError::InvalidFormat.into())
+    }
+
+    pub async fn authenticate_password(&mut self, password: &str) -> Result<()> {
+        self.command(&format!("AUTHENTICATE {}", quote(password)))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn authenticate_cookie(&mut self, cookie: &[u8]) -> Result<()> {
+        self.command(&format!("AUTHENTICATE {}", hex::encode_upper(cookie)))
+            .await?;
+        Ok(())
+    }
+
+    /// Authenticate with the cookie without revealing it, checking that Tor
+    /// knows the cookie too.
+    pub async fn authenticate_safe_cookie(&mut self, cookie: &[u8]) -> Result<()> {
+        use crypto_box::aead::rand_core::RngCore;
+
+        let mut client_nonce = [0u8; 32];
+        crypto_box::aead::OsRng.fill_bytes(&mut client_nonce);
+        let lines = self
+            .command(&format!(
+                "AUTHCHALLENGE SAFECOOKIE {}",
+                hex::encode_upper(client_nonce)
+            ))
+            .await?;
+        let re =
+            Regex::new(r"^AUTHCHALLENGE SERVERHASH=([0-9A-Fa-f]+) SERVERNONCE=([0-9A-Fa-f]+)$")
+                .map_err(|_| ConnError::InvalidFormat)?;
+        let captures = lines
+            .first()
+            .and_then(|line| re.captures(line))
+            .ok_or(ConnError::InvalidFormat)?;
+        let server_hash = hex::decode(&captures[1]).map_err(|_| ConnError::InvalidFormat)?;
+        let server_nonce = hex::decode(&captures[2]).map_err(|_| ConnError::InvalidFormat)?;
+
+        safe_cookie_hmac(SAFECOOKIE_SERVER_KEY, cookie, &client_nonce, &server_nonce)
+            .verify(&server_hash)
+            .map_err(|_| {
+                ConnError::UnauthenticatedConnError(UnauthenticatedConnError::ServerHashMismatch)
+            })?;
+        let client_hash =
+            safe_cookie_hmac(SAFECOOKIE_CLIENT_KEY, cookie, &client_nonce, &server_nonce)
+                .finalize()
+                .into_bytes();
+        self.authenticate_cookie(&client_hash).await
+    }
+
+    pub async fn take_ownership(&mut self) -> Result<()> {
+        self.command("TAKEOWNERSHIP").await?;
+        Ok(())
+    }
+
+    pub async fn add_onion_v3(
+        &mut self,
+        key: &torut::onion::TorSecretKeyV3,
+        ports: &[(u16, String)],
+        client_auth: &[crypto_box::PublicKey],
+    ) -> Result<()> {
+        let mut cmd = format!(
+            "ADD_ONION ED25519-V3:{} Flags=DiscardPK",
+            base64::encode(key.as_bytes())
+        );
+        if !client_auth.is_empty() {
+            cmd.push_str(",V3Auth");
+        }
+        for (port, target) in ports {
+            cmd.push_str(&format!(" Port={},{}", port, target));
+        }
+        for client_key in client_auth {
+            cmd.push_str(&format!(
+                " ClientAuthV3={}",
+                encode_x25519(client_key.as_bytes())
+            ));
+        }
+        self.command(&cmd).await?;
+        Ok(())
+    }
+
+    pub async fn onion_client_auth_add(
+        &mut self,
+        service_id: &str,
+        key: &crypto_box::SecretKey,
+    ) -> Result<()> {
+        check_service_id(service_id)?;
+        self.command(&format!(
+            "ONION_CLIENT_AUTH_ADD {} x25519:{}",
+            service_id,
+            base64::encode(key.as_bytes())
+        ))
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_info(&mut self, key: &str) -> Result<String> {
+        if !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/-_.".contains(c))
+        {
+            return Err(ConnError::InvalidFormat.into());
+        }
+        let lines = self.command(&format!("GETINFO {}", key)).await?;
+        let prefix = format!("{}=", key);
+        lines
+            .iter()
+            .find_map(|line| line.strip_prefix(&prefix))
+            .map(|value| value.trim_start_matches("\r\n").to_string())
+            .ok_or(ConnError::InvalidFormat.into())
+    }
+
+    pub async fn bootstrap_phase(&mut self) -> Result<BootstrapPhase> {
+        let status = self.get_info("status/bootstrap-phase").await?;
+        BootstrapPhase::parse(&status).ok_or(ConnError::InvalidFormat.into())
+    }
+
+    pub async fn traffic(&mut self) -> Result<TorTraffic> {
+        let count = |value: String| value.parse().map_err(|_| ConnError::InvalidFormat);
+        Ok(TorTraffic {
+            read: count(self.get_info("traffic/read").await?)?,
+            written: count(self.get_info("traffic/written").await?)?,
+        })
+    }
+
+    /// Subscribe this connection to asynchronous events, which are then read
+    /// with [`ControlConn::next_event`].
+    pub async fn set_events(&mut self, events: &[&str]) -> Result<()> {
+        self.command(&format!("SETEVENTS {}", events.join(" ")))
+            .await?;
+        Ok(())
+    }
+
+    /// Wait for the next asynchronous event. Events are only delivered in
+    /// between command replies, so a connection reading events should not be
+    /// used for anything else.
+    pub async fn next_event(&mut self) -> Result<Vec<String>> {
+        loop {
+            let (code, lines) = self.conn.receive_data().await?;
+            if code == 650 {
+                return Ok(lines);
+            }
+        }
+    }
+
+    pub async fn del_onion(&mut self, service_id: &str) -> Result<()> {
+        check_service_id(service_id)?;
+        self.command(&format!("DEL_ONION {}", service_id)).await?;
+        Ok(())
+    }
+
+    async fn command(&mut self, cmd: &str) -> Result<Vec<String>> {
+        self.conn
+            .write_data(format!("{}\r\n", cmd).as_bytes())
+            .await?;
+        loop {
+            let (code, lines) = self.conn.receive_data().await?;
+            match code {
+                // Asynchronous events are read on a separate connection.
+                650 => continue,
+                200..=299 => return Ok(lines),
+                _ => {
+                    // Only the keyword: arguments may hold keys.
+                    return Err(ControlError::Rejected {
+                        command: cmd.split(' ').next().unwrap_or_default().to_string(),
+                        code,
+                        reply: lines.join(" "),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Tor bootstrap progress, as reported in `status/bootstrap-phase` and
+/// `STATUS_CLIENT BOOTSTRAP` events.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapPhase {
+    pub progress: u8,
+    pub tag: String,
+    pub summary: String,
+}
+
+impl BootstrapPhase {
+    pub fn parse(status: &str) -> Option<BootstrapPhase> {
+        let re = Regex::new(r#"BOOTSTRAP PROGRESS=(\d+) TAG=(\S+) SUMMARY="([^"]*)""#).ok()?;
+        let captures = re.captures(status)?;
+        Some(BootstrapPhase {
+            progress: captures[1].parse().ok()?,
+            tag: captures[2].to_string(),
+            summary: captures[3].to_string(),
+        })
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.progress >= 100
+    }
+}
+
+/// Bytes Tor has read and written since it started, as reported in
+/// `traffic/read` and `traffic/written`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TorTraffic {
+    pub read: u64,
+    pub written: u64,
+}
+
+/// Authentication methods accepted by Tor, as reported in `PROTOCOLINFO`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolInfo {
+    pub auth_methods: Vec<String>,
+    pub cookie_file: Option<String>,
+}
+
+impl ProtocolInfo {
+    fn parse(auth: &str) -> Option<ProtocolInfo> {
+        let re = Regex::new(r#"^METHODS=(\S+)(?: COOKIEFILE="((?:[^"\\]|\\.)*)")?"#).ok()?;
+        let captures = re.captures(auth)?;
+        Some(ProtocolInfo {
+            auth_methods: captures[1].split(',').map(String::from).collect(),
+            cookie_file: captures.get(2).map(|file| unquote(file.as_str())),
+        })
+    }
+
+    pub fn has_method(&self, method: &str) -> bool {
+        self.auth_methods.iter().any(|m| m == method)
+    }
+}
+
+const SAFECOOKIE_SERVER_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+const SAFECOOKIE_CLIENT_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+
+fn safe_cookie_hmac(
+    key: &[u8],
+    cookie: &[u8],
+    client_nonce: &[u8],
+    server_nonce: &[u8],
+) -> Hmac<Sha256> {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    hmac.update(cookie);
+    hmac.update(client_nonce);
+    hmac.update(server_nonce);
+    hmac
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn unquote(s: &str) -> String {
+    let mut unquoted = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+fn check_service_id(service_id: &str) -> Result<()> {
+    if service_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Ok(())
+    } else {
+        Err(ConnError::InvalidFormat.into())
+    }
+}
+
+/// Encode an x25519 key the way Tor expects it in client authorization
+/// files, unpadded RFC4648 base32.
+pub fn encode_x25519(key: &[u8; 32]) -> String {
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, key)
+}
+
+/// Decode an unpadded RFC4648 base32 x25519 key.
+pub fn decode_x25519(s: &str) -> Option<[u8; 32]> {
+    let bytes = base32::decode(base32::Alphabet::RFC4648 { padding: false }, s)?;
+    bytes.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_add_onion_client_auth() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut conn = ControlConn::new(client);
+        let key = torut::onion::TorSecretKeyV3::generate();
+        let client_key = crypto_box::PublicKey::from([7u8; 32]);
+
+        let server_task = tokio::spawn(async move {
+            use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+            let (read, mut write) = tokio::io::split(&mut server);
+            let mut lines = BufReader::new(read).lines();
+            let line = lines.next_line().await.unwrap().unwrap();
+            write
+                .write_all(b"650 STATUS_CLIENT NOTICE ignored\r\n250-ServiceID=x\r\n250 OK\r\n")
+                .await
+                .unwrap();
+            line
+        });
+
+        conn.add_onion_v3(
+            &key,
+            &[(80, "127.0.0.1:8080".to_string())],
+            std::slice::from_ref(&client_key),
+        )
+        .await
+        .unwrap();
+        let line = server_task.await.unwrap();
+        assert!(line.starts_with("ADD_ONION ED25519-V3:"));
+        assert!(line.contains(" Flags=DiscardPK,V3Auth "));
+        assert!(line.contains(" Port=80,127.0.0.1:8080"));
+        assert!(line.ends_with(&format!(
+            " ClientAuthV3={}",
+            encode_x25519(client_key.as_bytes())
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_onion_client_auth_add() {
+        let (client, server) = tokio::io::duplex(4096);
+        let mut conn = ControlConn::new(client);
+        let key = crypto_box::SecretKey::from([3u8; 32]);
+
+        let server_task = tokio::spawn(async move {
+            use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+            let (read, mut write) = tokio::io::split(server);
+            let mut lines = BufReader::new(read).lines();
+            let line = lines.next_line().await.unwrap().unwrap();
+            write
+                .write_all(b"251 Client for onion existed and replaced\r\n")
+                .await
+                .unwrap();
+            line
+        });
+
+        conn.onion_client_auth_add("abc234", &key).await.unwrap();
+        assert_eq!(
+            server_task.await.unwrap(),
+            format!(
+                "ONION_CLIENT_AUTH_ADD abc234 x25519:{}",
+                base64::encode([3u8; 32])
+            )
+        );
+        assert!(conn.onion_client_auth_add("abc.onion", &key).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rejected() {
+        let (client, server) = tokio::io::duplex(4096);
+        let mut conn = ControlConn::new(client);
+        let key = torut::onion::TorSecretKeyV3::generate();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+            let (read, mut write) = tokio::io::split(server);
+            let mut lines = BufReader::new(read).lines();
+            lines.next_line().await.unwrap().unwrap();
+            write
+                .write_all(b"512 Bad arguments to ADD_ONION: Cannot add ports\r\n")
+                .await
+                .unwrap();
+        });
+        let err = conn
+            .add_onion_v3(&key, &[(80, "127.0.0.1:8080".to_string())], &[])
+            .await
+            .unwrap_err();
+        // Tor's reply is kept, but not the key sent with the command.
+        assert_eq!(
+            err.to_string(),
+            "tor rejected ADD_ONION: 512 Bad arguments to ADD_ONION: Cannot add ports"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_traffic() {
+        let (client, server) = tokio::io::duplex(4096);
+        let mut conn = ControlConn::new(client);
+        tokio::spawn(async move {
+            use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+            let (read, mut write) = tokio::io::split(server);
+            let mut lines = BufReader::new(read).lines();
+            for (key, value) in [("traffic/read", 1234), ("traffic/written", 5678)] {
+                let line = lines.next_line().await.unwrap().unwrap();
+                assert_eq!(line, format!("GETINFO {}", key));
+                write
+                    .write_all(format!("250-{}={}\r\n250 OK\r\n", key, value).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+        assert_eq!(
+            conn.traffic().await.unwrap(),
+            TorTraffic {
+                read: 1234,
+                written: 5678,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_phase() {
+        let (client, server) = tokio::io::duplex(4096);
+        let mut conn = ControlConn::new(client);
+        tokio::spawn(async move {
+            use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+            let (read, mut write) = tokio::io::split(server);
+            let mut lines = BufReader::new(read).lines();
+            let line = lines.next_line().await.unwrap().unwrap();
+            assert_eq!(line, "GETINFO status/bootstrap-phase");
+            write
+                .write_all(b"250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=45 TAG=requesting_descriptors SUMMARY=\"Asking for relay descriptors\"\r\n250 OK\r\n")
+                .await
+                .unwrap();
+        });
+        let phase = conn.bootstrap_phase().await.unwrap();
+        assert_eq!(
+            phase,
+            BootstrapPhase {
+                progress: 45,
+                tag: "requesting_descriptors".to_string(),
+                summary: "Asking for relay descriptors".to_string(),
+            }
+        );
+        assert!(!phase.is_done());
+        assert_eq!(BootstrapPhase::parse("garbage"), None);
+    }
+
+    #[test]
+    fn test_protocol_info_parse() {
+        assert_eq!(
+            ProtocolInfo::parse(r#"METHODS=COOKIE,SAFECOOKIE COOKIEFILE="/run/tor/\"cookie\"""#),
+            Some(ProtocolInfo {
+                auth_methods: vec!["COOKIE".to_string(), "SAFECOOKIE".to_string()],
+                cookie_file: Some(r#"/run/tor/"cookie""#.to_string()),
+            })
+        );
+        assert_eq!(
+            ProtocolInfo::parse("METHODS=NULL"),
+            Some(ProtocolInfo {
+                auth_methods: vec!["NULL".to_string()],
+                cookie_file: None,
+            })
+        );
+        assert_eq!(ProtocolInfo::parse("garbage"), None);
+    }
+
+    #[test]
+    fn test_x25519_roundtrip() {
+        let encoded = encode_x25519(&[42u8; 32]);
+        assert_eq!(encoded.len(), 52);
+        assert_eq!(decode_x25519(&encoded), Some([42u8; 32]));
+        assert_eq!(decode_x25519("not a key"), None);
+    }
+}
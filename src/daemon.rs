@@ -0,0 +1,427 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{io, path};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use torut::onion;
+
+use crate::{
+    config, secrets, unix, BootstrapPhase, Export, ForwardHandle, ForwardStatus, Import, LocalAddr,
+    PipeError, PublishState, Result, UnixSocketOptions,
+};
+
+// The control protocol is newline-delimited JSON over a unix socket: each
+// request is a single line, answered by a single line response.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    ListForwards,
+    AddExport {
+        export: config::Export,
+        /// Respond once the export's descriptor has been uploaded, or with an
+        /// error once the server's publish timeout has passed.
+        #[serde(default)]
+        wait_published: bool,
+    },
+    RemoveExport {
+        onion_addr: String,
+    },
+    AddImport {
+        import: config::Import,
+    },
+    RemoveImport {
+        local_addr: String,
+    },
+    Status,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Forwards { forwards: Vec<ForwardStatus> },
+    Exported { onion_addr: String },
+    Status { bootstrap: BootstrapPhase },
+    Error { message: String },
+}
+
+/// How long an `add_export` request waits for the export to be published, by
+/// default.
+pub const DEFAULT_PUBLISH_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Default location of the daemon control socket, in the user's runtime dir
+/// if there is one, otherwise alongside the secret store.
+pub fn default_socket_path() -> Option<path::PathBuf> {
+    dirs::runtime_dir()
+        .or_else(dirs::config_dir)
+        .map(|dir| dir.join("onionpipe").join("control.sock"))
+}
+
+/// Serves the control protocol, carrying out requests on a running
+/// [`crate::OnionPipe`] through its [`ForwardHandle`].
+pub struct Server {
+    forwards: ForwardHandle,
+    secret_store: Option<Arc<Mutex<Box<dyn secrets::SecretStore>>>>,
+    publish_timeout: Duration,
+}
+
+impl Server {
+    pub fn new(
+        forwards: ForwardHandle,
+        secret_store: Option<Box<dyn secrets::SecretStore>>,
+    ) -> Server {
+        Server {
+            forwards,
+            secret_store: secret_store.map(|store| Arc::new(Mutex::new(store))),
+            publish_timeout: DEFAULT_PUBLISH_TIMEOUT,
+        }
+    }
+
+    /// How long an `add_export` request waits for the export to be published
+    /// before responding with an error.
+    pub fn publish_timeout(mut self, publish_timeout: Duration) -> Server {
+        self.publish_timeout = publish_timeout;
+        self
+    }
+
+    /// Bind the control socket, accessible only to the current user. Fails
+    /// if another daemon is still serving the socket.
+    pub fn bind(socket_path: &path::Path) -> Result<UnixListener> {
+        if let Some(parent) = socket_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        unix::bind(
+            socket_path,
+            &UnixSocketOptions {
+                mode: Some(0o600),
+                ..Default::default()
+            },
+        )
+        .map_err(|err| match err {
+            PipeError::IO { source } if source.kind() == io::ErrorKind::AddrInUse => {
+                PipeError::from(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("daemon already running on {}", socket_path.display()),
+                ))
+            }
+            err => err,
+        })
+    }
+
+    /// Accept control connections until the listener fails.
+    pub async fn serve(self, listener: UnixListener) -> Result<()> {
+        let server = Arc::new(self);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.handle_conn(stream).await {
+                    tracing::warn!("control connection failed: {}", err);
+                }
+            });
+        }
+    }
+
+    async fn handle_conn(&self, stream: UnixStream) -> Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => self.handle(request).await,
+                Err(err) => Err(PipeError::from(err)),
+            }
+            .unwrap_or_else(|err| Response::Error {
+                message: err.to_string(),
+            });
+            let mut response_json = serde_json::to_string(&response)?;
+            response_json.push('\n');
+            write.write_all(response_json.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Run `f` with the secret store on a blocking thread, as looking up keys
+    /// may run a secrets helper command.
+    async fn with_secret_store<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(Option<&mut dyn secrets::SecretStore>) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let secret_store = self.secret_store.clone();
+        tokio::task::spawn_blocking(move || match secret_store {
+            Some(secret_store) => f(Some(&mut **secret_store.lock().unwrap())),
+            None => f(None),
+        })
+        .await?
+    }
+
+    async fn handle(&self, request: Request) -> Result<Response> {
+        match request {
+            Request::ListForwards => Ok(Response::Forwards {
+                forwards: self.forwards.forwards().await?,
+            }),
+            Request::AddExport {
+                export,
+                wait_published,
+            } => {
+                let export: Export = self
+                    .with_secret_store(|secret_store| (export, secret_store).try_into())
+                    .await?;
+                let onion_addr = self.forwards.add_export(export).await?;
+                if wait_published {
+                    let published = tokio::time::timeout(
+                        self.publish_timeout,
+                        self.forwards.wait_published(&onion_addr),
+                    )
+                    .await;
+                    match published {
+                        Ok(result) => {
+                            result?;
+                        }
+                        Err(_) => {
+                            let mut message = format!(
+                                "{} was added, but not published within {}s",
+                                onion_addr,
+                                self.publish_timeout.as_secs_f64()
+                            );
+                            if let Some(PublishState::Failed { reason }) =
+                                self.forwards.publish_states.get(&onion_addr)
+                            {
+                                message.push_str(&format!("; last upload failed: {}", reason));
+                            }
+                            return Ok(Response::Error { message });
+                        }
+                    }
+                }
+                Ok(Response::Exported {
+                    onion_addr: onion_addr.to_string(),
+                })
+            }
+            Request::RemoveExport { onion_addr } => {
+                let onion_addr = onion::OnionAddressV3::from_str(
+                    onion_addr.strip_suffix(".onion").unwrap_or(&onion_addr),
+                )?;
+                self.forwards.remove_export(&onion_addr).await?;
+                Ok(Response::Ok)
+            }
+            Request::AddImport { import } => {
+                let import: Import = self
+                    .with_secret_store(|secret_store| {
+                        (import, secret_store.map(|secret_store| &*secret_store)).try_into()
+                    })
+                    .await?;
+                self.forwards.add_import(import).await?;
+                Ok(Response::Ok)
+            }
+            Request::RemoveImport { local_addr } => {
+                let local_addr = LocalAddr::from_str(&local_addr)?;
+                self.forwards.remove_import(&local_addr).await?;
+                Ok(Response::Ok)
+            }
+            Request::Status => Ok(Response::Status {
+                bootstrap: self.forwards.bootstrap_phase().await?,
+            }),
+        }
+    }
+}
+
+/// Send a request to the daemon listening on `socket_path` and wait for its
+/// response.
+pub async fn request(socket_path: &path::Path, request: &Request) -> Result<Response> {
+    let stream = UnixStream::connect(socket_path).await?;
+    let (read, mut write) = stream.into_split();
+    let mut request_json = serde_json::to_string(request)?;
+    request_json.push('\n');
+    write.write_all(request_json.as_bytes()).await?;
+    match BufReader::new(read).lines().next_line().await? {
+        Some(line) => Ok(serde_json::from_str(&line)?),
+        None => Err(PipeError::from(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "control connection closed without a response",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_pipe() -> ForwardHandle {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let publish_states = crate::publish::PublishStates::default();
+        let pipe_publish_states = publish_states.clone();
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                match request {
+                    // Exports are added, but uploads of them keep failing.
+                    crate::Request::AddExport(export, reply) => {
+                        let onion_addr = export.remote_key.public().get_onion_address();
+                        pipe_publish_states.register(&onion_addr);
+                        pipe_publish_states.set(
+                            &onion_addr,
+                            PublishState::Failed {
+                                reason: "UPLOAD_REJECTED".to_string(),
+                            },
+                        );
+                        let _ = reply.send(Ok(onion_addr));
+                    }
+                    crate::Request::ListForwards(reply) => {
+                        let _ = reply.send(Ok(vec![ForwardStatus::Import {
+                            onion_addr: "example.onion".to_string(),
+                            remote_port: 80,
+                            local_addr: "127.0.0.1:8080".to_string(),
+                            bound: true,
+                        }]));
+                    }
+                    crate::Request::BootstrapPhase(reply) => {
+                        let _ = reply.send(Ok(BootstrapPhase {
+                            progress: 100,
+                            tag: "done".to_string(),
+                            summary: "Done".to_string(),
+                        }));
+                    }
+                    crate::Request::RemoveImport(local_addr, reply) => {
+                        let _ = reply.send(Err(PipeError::ForwardNotFound(local_addr.to_string())));
+                    }
+                    request => request.reject(),
+                }
+            }
+        });
+        ForwardHandle { tx, publish_states }
+    }
+
+    #[test]
+    fn request_json() {
+        let request: Request =
+            serde_json::from_str(r#"{"command": "remove_import", "local_addr": "127.0.0.1:80"}"#)
+                .unwrap();
+        assert_eq!(
+            request,
+            Request::RemoveImport {
+                local_addr: "127.0.0.1:80".to_string()
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&Response::Exported {
+                onion_addr: "example.onion".to_string()
+            })
+            .unwrap(),
+            r#"{"result":"exported","onion_addr":"example.onion"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn serve_requests() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let socket_path = tmp_dir.path().join("run").join("control.sock");
+        let listener = Server::bind(&socket_path).unwrap();
+        tokio::spawn(Server::new(fake_pipe(), None).serve(listener));
+
+        // A second daemon does not take over the socket.
+        let err = Server::bind(&socket_path).unwrap_err();
+        assert!(err.to_string().contains("daemon already running"));
+
+        match request(&socket_path, &Request::ListForwards).await.unwrap() {
+            Response::Forwards { forwards } => assert_eq!(forwards.len(), 1),
+            response => panic!("unexpected response {:?}", response),
+        }
+        match request(&socket_path, &Request::Status).await.unwrap() {
+            Response::Status { bootstrap } => assert!(bootstrap.is_done()),
+            response => panic!("unexpected response {:?}", response),
+        }
+        match request(
+            &socket_path,
+            &Request::RemoveImport {
+                local_addr: "127.0.0.1:9999".to_string(),
+            },
+        )
+        .await
+        .unwrap()
+        {
+            Response::Error { message } => assert!(message.contains("127.0.0.1:9999")),
+            response => panic!("unexpected response {:?}", response),
+        }
+        match request(
+            &socket_path,
+            &Request::RemoveImport {
+                local_addr: "not an address".to_string(),
+            },
+        )
+        .await
+        .unwrap()
+        {
+            Response::Error { .. } => {}
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    #[tokio::test]
+    async fn add_export_secret_store() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let socket_path = tmp_dir.path().join("control.sock");
+        let listener = Server::bind(&socket_path).unwrap();
+        let secret_store = secrets::MemorySecretStore::new();
+        let server = Server::new(fake_pipe(), Some(Box::new(secret_store.clone())));
+        tokio::spawn(server.serve(listener));
+
+        let add_export = Request::AddExport {
+            export: config::Export {
+                local_addr: "127.0.0.1:8080".to_string(),
+                service_name: Some("web".to_string()),
+                remote_ports: vec![80],
+                authorized_clients: None,
+                connection: None,
+            },
+            wait_published: false,
+        };
+        let onion_addr = match request(&socket_path, &add_export).await.unwrap() {
+            Response::Exported { onion_addr } => onion_addr,
+            response => panic!("unexpected response {:?}", response),
+        };
+        // The service key was created in the store, and is used again.
+        assert!(secrets::SecretStore::get_service(&secret_store, "web")
+            .unwrap()
+            .is_some());
+        match request(&socket_path, &add_export).await.unwrap() {
+            Response::Exported { onion_addr: again } => assert_eq!(again, onion_addr),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    #[tokio::test]
+    async fn add_export_publish_timeout() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let socket_path = tmp_dir.path().join("control.sock");
+        let listener = Server::bind(&socket_path).unwrap();
+        let server = Server::new(fake_pipe(), None).publish_timeout(Duration::from_millis(50));
+        tokio::spawn(server.serve(listener));
+
+        let add_export = Request::AddExport {
+            export: config::Export {
+                local_addr: "127.0.0.1:8080".to_string(),
+                service_name: None,
+                remote_ports: vec![80],
+                authorized_clients: None,
+                connection: None,
+            },
+            wait_published: true,
+        };
+        let response =
+            tokio::time::timeout(Duration::from_secs(5), request(&socket_path, &add_export))
+                .await
+                .unwrap()
+                .unwrap();
+        match response {
+            Response::Error { message } => {
+                // A failed upload is retried, so is waited out.
+                assert!(message.contains(".onion was added, but not published"));
+                assert!(message.ends_with("last upload failed: UPLOAD_REJECTED"));
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+}
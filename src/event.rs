@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use torut::onion;
+
+use crate::{BootstrapPhase, LocalAddr, PipeError};
+
+/// Events reported by a running [`crate::OnionPipe`], received with
+/// [`crate::OnionPipe::subscribe`].
+#[derive(Debug, Clone)]
+pub enum OnionPipeEvent {
+    /// Tor's bootstrap progress changed while starting up.
+    Bootstrap {
+        phase: BootstrapPhase,
+    },
+    /// An export was added to Tor. It is not reachable until its
+    /// descriptor has been uploaded.
+    OnionPublished {
+        local_addr: LocalAddr,
+        onion_addr: onion::OnionAddressV3,
+        remote_ports: Vec<u16>,
+    },
+    /// An export's descriptor was uploaded to its first HSDir, making it
+    /// reachable.
+    DescriptorUploaded {
+        onion_addr: onion::OnionAddressV3,
+        hs_dirs: usize,
+    },
+    /// Every attempt to upload an export's descriptor has failed.
+    DescriptorFailed {
+        onion_addr: onion::OnionAddressV3,
+        reason: String,
+    },
+    OnionRemoved {
+        onion_addr: onion::OnionAddressV3,
+    },
+    /// An import is listening for local connections.
+    ImportBound {
+        onion_addr: onion::OnionAddress,
+        remote_port: u16,
+        local_addr: LocalAddr,
+    },
+    ImportRemoved {
+        local_addr: LocalAddr,
+    },
+    /// A local connection to an import was accepted.
+    ConnectionOpened {
+        id: u64,
+        local_addr: LocalAddr,
+        onion_addr: onion::OnionAddress,
+        remote_port: u16,
+    },
+    ConnectionClosed {
+        id: u64,
+        bytes_sent: u64,
+        bytes_received: u64,
+    },
+    /// A connection to an import failed before or while forwarding.
+    ConnectionError {
+        id: u64,
+        error: Arc<PipeError>,
+    },
+}
+
+impl OnionPipeEvent {
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            OnionPipeEvent::ConnectionError { .. } | OnionPipeEvent::DescriptorFailed { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for OnionPipeEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OnionPipeEvent::Bootstrap { phase } => {
+                write!(f, "tor bootstrapped {}%: {}", phase.progress, phase.summary)
+            }
+            OnionPipeEvent::OnionPublished {
+                local_addr,
+                onion_addr,
+                remote_ports,
+            } => {
+                let ports: Vec<String> = remote_ports.iter().map(|p| p.to_string()).collect();
+                write!(
+                    f,
+                    "forward {} => {}:{}",
+                    local_addr,
+                    onion_addr,
+                    ports.join(",")
+                )
+            }
+            OnionPipeEvent::DescriptorUploaded {
+                onion_addr,
+                hs_dirs,
+            } => write!(
+                f,
+                "{} published, descriptor uploaded to {} HSDirs",
+                onion_addr, hs_dirs
+            ),
+            OnionPipeEvent::DescriptorFailed { onion_addr, reason } => {
+                write!(f, "{} descriptor upload failed: {}", onion_addr, reason)
+            }
+            OnionPipeEvent::OnionRemoved { onion_addr } => write!(f, "removed {}", onion_addr),
+            OnionPipeEvent::ImportBound {
+                onion_addr,
+                remote_port,
+                local_addr,
+            } => write!(
+                f,
+                "forward {}:{} => {}",
+                onion_addr, remote_port, local_addr
+            ),
+            OnionPipeEvent::ImportRemoved { local_addr } => write!(f, "removed {}", local_addr),
+            OnionPipeEvent::ConnectionOpened {
+                id,
+                local_addr,
+                onion_addr,
+                remote_port,
+            } => write!(
+                f,
+                "connection {} opened: {} => {}:{}",
+                id, local_addr, onion_addr, remote_port
+            ),
+            OnionPipeEvent::ConnectionClosed {
+                id,
+                bytes_sent,
+                bytes_received,
+            } => write!(
+                f,
+                "connection {} closed: {} bytes sent, {} bytes received",
+                id, bytes_sent, bytes_received
+            ),
+            OnionPipeEvent::ConnectionError { id, error } => match error.as_ref() {
+                PipeError::Socks(err) if err.is_client_auth() => write!(
+                    f,
+                    "connection {} failed: remote onion client authorization failed: {}",
+                    id, err
+                ),
+                err => write!(f, "connection {} failed: {}", id, err),
+            },
+        }
+    }
+}
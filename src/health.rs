@@ -0,0 +1,174 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{http, BootstrapPhase, ForwardHandle, ForwardStatus};
+
+// Liveness and readiness probes for container orchestration. Readiness is
+// queried through the running pipe, and so over its own control connection.
+
+/// How long a probe waits for the pipe to answer. It doesn't answer until Tor
+/// has bootstrapped and the initial forwards are up.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Readiness of a running [`crate::OnionPipe`], broken down per forward.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Health {
+    /// Tor has bootstrapped and every forward is ready.
+    pub ready: bool,
+    /// Tor's bootstrap progress, if the pipe answered.
+    pub bootstrap: Option<BootstrapPhase>,
+    pub forwards: Vec<ForwardHealth>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ForwardHealth {
+    pub ready: bool,
+    #[serde(flatten)]
+    pub status: ForwardStatus,
+}
+
+impl Health {
+    /// Check the readiness of the pipe behind `forwards`. A pipe that doesn't
+    /// answer in time is not ready.
+    pub async fn check(forwards: &ForwardHandle) -> Health {
+        let bootstrap = tokio::time::timeout(QUERY_TIMEOUT, forwards.bootstrap_phase())
+            .await
+            .ok()
+            .and_then(|result| result.ok());
+        let statuses = tokio::time::timeout(QUERY_TIMEOUT, forwards.forwards())
+            .await
+            .ok()
+            .and_then(|result| result.ok());
+        let answered = statuses.is_some();
+        let forwards: Vec<ForwardHealth> = statuses
+            .unwrap_or_default()
+            .into_iter()
+            .map(|status| ForwardHealth {
+                ready: status.is_ready(),
+                status,
+            })
+            .collect();
+        Health {
+            ready: answered
+                && bootstrap.as_ref().is_some_and(BootstrapPhase::is_done)
+                && forwards.iter().all(|forward| forward.ready),
+            bootstrap,
+            forwards,
+        }
+    }
+}
+
+/// Serve `/healthz`, which answers while onionpipe is running, and `/readyz`,
+/// which answers 200 when ready and 503 otherwise, with a JSON [`Health`].
+pub(crate) async fn serve(listener: tokio::net::TcpListener, forwards: ForwardHandle) {
+    let handler = move |path: String| {
+        let forwards = forwards.clone();
+        async move {
+            match path.as_str() {
+                "/healthz" => Some(http::Response::new(200, "text/plain", "ok\n".to_string())),
+                "/readyz" => {
+                    let health = Health::check(&forwards).await;
+                    let status = if health.ready { 200 } else { 503 };
+                    let body = serde_json::to_string(&health).unwrap_or_default() + "\n";
+                    Some(http::Response::new(status, "application/json", body))
+                }
+                _ => None,
+            }
+        }
+    };
+    http::serve(listener, "health", handler).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PipeError, PublishState, Request};
+
+    /// A pipe answering with a fixed bootstrap progress and forwards.
+    fn fake_pipe(progress: Option<u8>, forwards: Vec<ForwardStatus>) -> ForwardHandle {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                match request {
+                    Request::BootstrapPhase(reply) => {
+                        let _ = reply.send(progress.ok_or(PipeError::NotRunning).map(|progress| {
+                            BootstrapPhase {
+                                progress,
+                                tag: "done".to_string(),
+                                summary: "Done".to_string(),
+                            }
+                        }));
+                    }
+                    Request::ListForwards(reply) => {
+                        let _ = reply.send(Ok(forwards.clone()));
+                    }
+                    request => request.reject(),
+                }
+            }
+        });
+        ForwardHandle {
+            tx,
+            publish_states: Default::default(),
+        }
+    }
+
+    fn export(publish_state: PublishState) -> ForwardStatus {
+        ForwardStatus::Export {
+            local_addr: "127.0.0.1:8080".to_string(),
+            onion_addr: "example.onion".to_string(),
+            remote_ports: vec![80],
+            publish_state,
+        }
+    }
+
+    fn import(bound: bool) -> ForwardStatus {
+        ForwardStatus::Import {
+            onion_addr: "example.onion".to_string(),
+            remote_port: 80,
+            local_addr: "127.0.0.1:8000".to_string(),
+            bound,
+        }
+    }
+
+    #[tokio::test]
+    async fn readiness() {
+        let uploaded = PublishState::Uploaded { hs_dirs: 1 };
+        let health = Health::check(&fake_pipe(
+            Some(100),
+            vec![export(uploaded.clone()), import(true)],
+        ))
+        .await;
+        assert!(health.ready);
+        assert_eq!(
+            serde_json::to_value(&health.forwards[1]).unwrap(),
+            serde_json::json!({
+                "ready": true,
+                "type": "import",
+                "onion_addr": "example.onion",
+                "remote_port": 80,
+                "local_addr": "127.0.0.1:8000",
+                "bound": true,
+            })
+        );
+
+        for (progress, forwards) in [
+            (Some(50), vec![export(uploaded.clone())]),
+            (None, vec![]),
+            (Some(100), vec![export(PublishState::Pending), import(true)]),
+            (Some(100), vec![export(uploaded.clone()), import(false)]),
+        ] {
+            let health = Health::check(&fake_pipe(progress, forwards)).await;
+            assert!(!health.ready, "{:?}", health);
+        }
+        assert_eq!(
+            Health::check(&fake_pipe(Some(100), vec![export(PublishState::Pending)]))
+                .await
+                .forwards
+                .iter()
+                .map(|forward| forward.ready)
+                .collect::<Vec<_>>(),
+            vec![false]
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+// A minimal HTTP/1.1 server for the metrics and health endpoints. Each
+// connection carries a single GET request, which is answered and closed.
+
+/// Longest request head read from a client.
+const MAX_REQUEST_LEN: usize = 8192;
+/// How long a client has to send its request head.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after a failed accept, such as when out of file descriptors, rather
+/// than retrying at once.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+pub(crate) struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub(crate) fn new(status: u16, content_type: &'static str, body: String) -> Response {
+        Response {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    fn not_found() -> Response {
+        Response::new(404, "text/plain", "not found\n".to_string())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Answer GET requests on `listener` with `handler`, given the request path.
+/// `handler` returns `None` for paths it doesn't serve.
+pub(crate) async fn serve<H, F>(listener: tokio::net::TcpListener, name: &'static str, handler: H)
+where
+    H: Fn(String) -> F + Clone + Send + 'static,
+    F: Future<Output = Option<Response>> + Send,
+{
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = respond(stream, handler, REQUEST_TIMEOUT).await {
+                        tracing::debug!("{} request failed: {}", name, err);
+                    }
+                });
+            }
+            Err(err) => {
+                tracing::warn!("failed to accept {} connection: {}", name, err);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn respond<S, H, F>(stream: S, handler: H, timeout: Duration) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    H: Fn(String) -> F,
+    F: Future<Output = Option<Response>>,
+{
+    let mut stream = BufReader::new(stream);
+    let request_line = match tokio::time::timeout(timeout, read_request(&mut stream)).await {
+        Ok(request_line) => match request_line? {
+            Some(request_line) => request_line,
+            None => return Ok(()),
+        },
+        Err(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "timed out reading request",
+            ))
+        }
+    };
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => handler(path.to_string())
+            .await
+            .unwrap_or_else(Response::not_found),
+        _ => Response::new(405, "text/plain", "method not allowed\n".to_string()),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len(),
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read the whole request head, so the client sees a clean close, returning
+/// its request line. Returns `None` if the head is cut short or too long.
+async fn read_request<S>(stream: &mut BufReader<S>) -> std::io::Result<Option<String>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut request_line = String::new();
+    let mut line = String::new();
+    let mut len = 0;
+    loop {
+        line.clear();
+        let n = (&mut *stream)
+            .take((MAX_REQUEST_LEN - len) as u64)
+            .read_line(&mut line)
+            .await?;
+        len += n;
+        if n == 0 || len >= MAX_REQUEST_LEN {
+            return Ok(None);
+        }
+        if request_line.is_empty() {
+            request_line = line.clone();
+        } else if line.trim_end().is_empty() {
+            return Ok(Some(request_line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn hello(path: String) -> Option<Response> {
+        (path == "/hello").then(|| Response::new(200, "text/plain", "hi\n".to_string()))
+    }
+
+    async fn request(request: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(respond(server, hello, REQUEST_TIMEOUT));
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        task.await.unwrap().unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn responses() {
+        assert_eq!(
+            request(b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n").await,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 3\r\nConnection: close\r\n\r\nhi\n"
+        );
+        assert!(request(b"GET /nope HTTP/1.1\r\n\r\n")
+            .await
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(request(b"POST /hello HTTP/1.1\r\n\r\n")
+            .await
+            .starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        // A request that never finishes its head gets no response.
+        assert_eq!(request(b"GET /hello HTTP/1.1\r\n").await, "");
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        // A client that stops partway through its request is cut off.
+        let (mut client, server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(respond(server, hello, Duration::from_millis(50)));
+        client.write_all(b"GET /hello HTTP/1.1\r\n").await.unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "");
+    }
+}
@@ -1,21 +1,53 @@
 use std::str::FromStr;
-use std::{env, fs, io, net, path, result};
+use std::{env, io, net, path, result};
 
 use regex::Regex;
-use std::os::unix::fs::PermissionsExt;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use torut::{control, onion};
+use torut::onion;
 
+#[cfg(feature = "arti")]
+mod arti;
+mod backend;
 pub mod config;
+mod control;
+pub mod daemon;
+pub mod event;
+mod health;
+mod http;
+mod metrics;
 pub mod parse;
+mod publish;
+mod relay;
+mod retry;
 pub mod secrets;
+pub mod socks;
+pub mod systemd;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "libtor")]
+mod tor_log;
+mod unix;
+
+pub use backend::{ControlAuth, TorBackend};
+pub use control::{BootstrapPhase, ControlError, TorTraffic};
+pub use event::OnionPipeEvent;
+pub use health::{ForwardHealth, Health};
+pub use publish::PublishState;
+pub use relay::ConnectionOptions;
+pub use retry::RetryPolicy;
+pub use unix::UnixSocketOptions;
 
 #[derive(Error, Debug)]
 pub enum PipeError {
-    #[error("timeout connecting to tor control socket")]
+    #[error("tor control socket did not appear before the startup deadline")]
     ConnTimeout,
-    #[error("failed to connect to tor control socket")]
-    Conn(#[from] control::ConnError),
+    #[error("tor bootstrap stalled at {0}%")]
+    BootstrapStalled(u8),
+    #[error("tor exited: {0}")]
+    TorExited(String),
+    #[error("{0}")]
+    Control(#[from] ControlError),
     #[error("i/o error: {0}", .source)]
     IO {
         #[from]
@@ -23,7 +55,7 @@ pub enum PipeError {
         //backtrace: std::backtrace::Backtrace,
     },
     #[error("socks error: {0}")]
-    Socks(#[from] tokio_socks::Error),
+    Socks(#[from] socks::SocksError),
     #[error("join error: {0}")]
     Join(#[from] tokio::task::JoinError),
     #[error("invalid socket address: {0}")]
@@ -40,6 +72,23 @@ pub enum PipeError {
     ForwardParse(#[from] parse::ParseError),
     #[error("onion address parse error: {0}")]
     OnionAddr(#[from] torut::onion::OnionAddressParseError),
+    #[error("authorized client {0}: key not found in secret store")]
+    ClientKeyNotFound(String),
+    #[error("invalid client public key: {0}")]
+    ClientKey(String),
+    #[error("forward not found: {0}")]
+    ForwardNotFound(String),
+    #[error("onion pipe is not running")]
+    NotRunning,
+    #[error("remote onion connection timed out")]
+    OnionConnectTimeout,
+    #[error("connection idle for {0:?}")]
+    IdleTimeout(std::time::Duration),
+    #[error("connection reached its maximum lifetime of {0:?}")]
+    LifetimeExceeded(std::time::Duration),
+    #[cfg(feature = "arti")]
+    #[error("arti error: {0}")]
+    Arti(#[from] arti_client::Error),
 }
 
 pub type Result<T> = result::Result<T, PipeError>;
@@ -48,7 +97,13 @@ pub struct OnionPipeBuilder {
     temp_dir: path::PathBuf,
     exports: Vec<Export>,
     imports: Vec<Import>,
-    secret_store: Option<secrets::SecretStore>,
+    secret_store: Option<Box<dyn secrets::SecretStore>>,
+    log_level: tracing::Level,
+    startup_timeout: Option<std::time::Duration>,
+    backend: TorBackend,
+    metrics_addr: Option<net::SocketAddr>,
+    health_addr: Option<net::SocketAddr>,
+    listen_fds: systemd::ListenFds,
 }
 
 impl OnionPipeBuilder {
@@ -59,7 +114,61 @@ impl OnionPipeBuilder {
 
     pub fn secrets_dir(mut self, secrets_dir: &str) -> OnionPipeBuilder {
         let secrets_dir = path::PathBuf::from(secrets_dir);
-        self.secret_store = Some(secrets::SecretStore::new(secrets_dir.to_str().unwrap()));
+        self.secret_store = Some(Box::new(secrets::FileSecretStore::new(
+            secrets_dir.to_str().unwrap(),
+        )));
+        self
+    }
+
+    /// Use `secret_store` for named services and clients, such as a file
+    /// store that has been unlocked, or another backend.
+    pub fn secret_store(
+        mut self,
+        secret_store: impl secrets::SecretStore + 'static,
+    ) -> OnionPipeBuilder {
+        self.secret_store = Some(Box::new(secret_store));
+        self
+    }
+
+    /// Level of detail logged by onionpipe and Tor.
+    pub fn log_level(mut self, log_level: tracing::Level) -> OnionPipeBuilder {
+        self.log_level = log_level;
+        self
+    }
+
+    /// Give up on starting if Tor has not finished bootstrapping within
+    /// `startup_timeout`. `None` waits indefinitely.
+    pub fn startup_timeout(
+        mut self,
+        startup_timeout: Option<std::time::Duration>,
+    ) -> OnionPipeBuilder {
+        self.startup_timeout = startup_timeout;
+        self
+    }
+
+    /// The Tor to use, by default [`TorBackend::default`].
+    pub fn tor_backend(mut self, backend: TorBackend) -> OnionPipeBuilder {
+        self.backend = backend;
+        self
+    }
+
+    /// Serve Prometheus metrics over HTTP at `/metrics` on `metrics_addr`.
+    pub fn metrics_addr(mut self, metrics_addr: net::SocketAddr) -> OnionPipeBuilder {
+        self.metrics_addr = Some(metrics_addr);
+        self
+    }
+
+    /// Serve liveness and readiness probes over HTTP at `/healthz` and
+    /// `/readyz` on `health_addr`.
+    pub fn health_addr(mut self, health_addr: net::SocketAddr) -> OnionPipeBuilder {
+        self.health_addr = Some(health_addr);
+        self
+    }
+
+    /// Sockets for imports to listen on rather than binding their local
+    /// address, such as those passed by systemd socket activation.
+    pub fn listen_fds(mut self, listen_fds: systemd::ListenFds) -> OnionPipeBuilder {
+        self.listen_fds = listen_fds;
         self
     }
 
@@ -74,66 +183,431 @@ impl OnionPipeBuilder {
     }
 
     pub fn config(mut self, cfg: config::Config) -> Result<OnionPipeBuilder> {
+        if cfg.secrets_dir.is_some() && cfg.secrets_helper.is_some() {
+            return Err(PipeError::Config(
+                "secrets_dir and secrets_helper cannot both be set".to_string(),
+            ));
+        }
         if let Some(secrets_dir) = cfg.secrets_dir {
             self = self.secrets_dir(&secrets_dir);
         }
+        if let Some(secrets_helper) = cfg.secrets_helper {
+            match secrets_helper.split_first() {
+                Some((program, args)) => {
+                    self = self.secret_store(secrets::ExecSecretStore::new(program, args))
+                }
+                None => return Err(PipeError::Config("empty secrets_helper".to_string())),
+            }
+        }
         for cfg_export in cfg.exports {
-            let export = match (cfg_export, self.secret_store.as_mut()).try_into() {
-                Ok(item) => item,
-                Err(err) => return Err(err),
-            };
+            let export = (cfg_export, self.secret_store.as_deref_mut()).try_into()?;
             self.exports.push(export);
         }
         for cfg_import in cfg.imports {
-            let import = match cfg_import.try_into() {
-                Ok(item) => item,
-                Err(err) => return Err(err),
-            };
+            let import = (cfg_import, self.secret_store.as_deref()).try_into()?;
             self.imports.push(import);
         }
+        if let Some(startup_timeout) = cfg.startup_timeout {
+            self = self.startup_timeout(match startup_timeout {
+                0 => None,
+                secs => Some(std::time::Duration::from_secs(secs)),
+            });
+        }
+        if let Some(tor) = cfg.tor {
+            self = self.tor_backend(tor.try_into()?);
+        }
+        if let Some(log_level) = cfg.log_level {
+            self = self.log_level(parse_log_level(&log_level)?);
+        }
         if let Some(temp_dir) = cfg.temp_dir {
             self = self.temp_dir(&temp_dir)
         }
+        if let Some(metrics_addr) = cfg.metrics_addr {
+            self = self.metrics_addr(metrics_addr.parse()?);
+        }
+        if let Some(health_addr) = cfg.health_addr {
+            self = self.health_addr(health_addr.parse()?);
+        }
         Ok(self)
     }
 
+    #[allow(clippy::new_ret_no_self)]
     pub async fn new(self) -> Result<OnionPipe> {
-        let temp_dir = tempfile::tempdir_in(self.temp_dir)?;
-        let data_dir = temp_dir.path().join("data");
-        tokio::fs::create_dir(data_dir.as_path()).await?;
-        tokio::fs::set_permissions(data_dir.as_path(), fs::Permissions::from_mode(0o700)).await?;
-        let control_sock = data_dir.join("control.sock").to_str().unwrap().into();
-        let socks_sock = data_dir.join("socks.sock").to_str().unwrap().into();
+        let tor = self.backend.prepare(&self.temp_dir)?;
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let (requests_tx, requests_rx) = tokio::sync::mpsc::channel(16);
+        let (events, _) = tokio::sync::broadcast::channel(256);
         Ok(OnionPipe {
-            temp_dir: Some(temp_dir),
-            data_dir: data_dir.to_str().unwrap().into(),
-            control_sock: control_sock,
-            socks_sock: socks_sock,
+            tor,
+            temp_dir: self.temp_dir,
+            log_level: self.log_level,
+            startup_timeout: self.startup_timeout,
+            metrics_addr: self.metrics_addr,
+            metrics: self.metrics_addr.map(|_| metrics::Metrics::default()),
+            health_addr: self.health_addr,
+            listen_fds: self.listen_fds,
             exports: self.exports,
             imports: self.imports,
+            shutdown: ShutdownHandle {
+                tx: std::sync::Arc::new(shutdown_tx),
+            },
+            shutdown_rx,
+            requests_tx,
+            requests_rx,
+            events,
+            publish_states: publish::PublishStates::default(),
+            export_relays: std::collections::HashMap::new(),
         })
     }
 }
 
 pub struct OnionPipe {
-    temp_dir: Option<tempfile::TempDir>,
-    data_dir: String,
-    control_sock: String,
-    socks_sock: String,
+    tor: backend::Tor,
+    /// Where export relays put their sockets.
+    temp_dir: path::PathBuf,
+    log_level: tracing::Level,
+    startup_timeout: Option<std::time::Duration>,
+    metrics_addr: Option<net::SocketAddr>,
+    metrics: Option<metrics::Metrics>,
+    health_addr: Option<net::SocketAddr>,
+    listen_fds: systemd::ListenFds,
     exports: Vec<Export>,
     imports: Vec<Import>,
+    shutdown: ShutdownHandle,
+    shutdown_rx: tokio::sync::watch::Receiver<bool>,
+    requests_tx: tokio::sync::mpsc::Sender<Request>,
+    requests_rx: tokio::sync::mpsc::Receiver<Request>,
+    events: tokio::sync::broadcast::Sender<OnionPipeEvent>,
+    publish_states: publish::PublishStates,
+    export_relays: std::collections::HashMap<String, relay::ExportRelay>,
+}
+
+/// What [`OnionPipe::run`] has started, to be stopped when it returns.
+#[derive(Default)]
+struct Running {
+    ac: Option<backend::TorControl>,
+    tasks: Vec<tokio::task::AbortHandle>,
+    publish_task: Option<tokio::task::JoinHandle<Result<()>>>,
+    import_tasks: Vec<tokio::task::JoinHandle<Result<()>>>,
+}
+
+/// Handle used to stop a running [`OnionPipe`]. Triggering shutdown causes
+/// [`OnionPipe::run`] to remove its onions, stop its imports, clean up and
+/// return. Shutdown requested before `run` is called takes effect as soon as
+/// the pipe has started.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    tx: std::sync::Arc<tokio::sync::watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.tx.send_replace(true);
+    }
+}
+
+type Reply<T> = tokio::sync::oneshot::Sender<Result<T>>;
+
+enum Request {
+    AddExport(Export, Reply<onion::OnionAddressV3>),
+    RemoveExport(onion::OnionAddressV3, Reply<()>),
+    AddImport(Import, Reply<()>),
+    RemoveImport(LocalAddr, Reply<()>),
+    ListForwards(Reply<Vec<ForwardStatus>>),
+    BootstrapPhase(Reply<BootstrapPhase>),
+    TorTraffic(Reply<Option<TorTraffic>>),
+}
+
+impl Request {
+    fn reject(self) {
+        match self {
+            Request::AddExport(_, reply) => {
+                let _ = reply.send(Err(PipeError::NotRunning));
+            }
+            Request::RemoveExport(_, reply)
+            | Request::AddImport(_, reply)
+            | Request::RemoveImport(_, reply) => {
+                let _ = reply.send(Err(PipeError::NotRunning));
+            }
+            Request::ListForwards(reply) => {
+                let _ = reply.send(Err(PipeError::NotRunning));
+            }
+            Request::BootstrapPhase(reply) => {
+                let _ = reply.send(Err(PipeError::NotRunning));
+            }
+            Request::TorTraffic(reply) => {
+                let _ = reply.send(Err(PipeError::NotRunning));
+            }
+        }
+    }
+}
+
+/// Handle used to add and remove forwards on a running [`OnionPipe`], reusing
+/// its Tor instance and control connection. Requests made before
+/// [`OnionPipe::run`] is called are carried out once the pipe has started.
+#[derive(Clone)]
+pub struct ForwardHandle {
+    tx: tokio::sync::mpsc::Sender<Request>,
+    publish_states: publish::PublishStates,
+}
+
+impl ForwardHandle {
+    /// Publish an export, returning its onion address.
+    pub async fn add_export(&self, export: Export) -> Result<onion::OnionAddressV3> {
+        self.request(|reply| Request::AddExport(export, reply))
+            .await
+    }
+
+    pub async fn remove_export(&self, onion_addr: &onion::OnionAddressV3) -> Result<()> {
+        let onion_addr = *onion_addr;
+        self.request(|reply| Request::RemoveExport(onion_addr, reply))
+            .await
+    }
+
+    pub async fn add_import(&self, import: Import) -> Result<()> {
+        self.request(|reply| Request::AddImport(import, reply))
+            .await
+    }
+
+    /// Stop the import listening on `local_addr`.
+    pub async fn remove_import(&self, local_addr: &LocalAddr) -> Result<()> {
+        let local_addr = local_addr.clone();
+        self.request(|reply| Request::RemoveImport(local_addr, reply))
+            .await
+    }
+
+    /// Wait until the descriptor for an export has been uploaded, returning
+    /// the number of HSDirs it has been uploaded to so far. Tor retries failed
+    /// uploads, so this keeps waiting through failures; bound the wait with a
+    /// timeout to give up.
+    pub async fn wait_published(&self, onion_addr: &onion::OnionAddressV3) -> Result<usize> {
+        let mut states = match self.publish_states.subscribe(onion_addr) {
+            Some(states) => states,
+            None => return Err(PipeError::ForwardNotFound(onion_addr.to_string())),
+        };
+        loop {
+            let state = states.borrow_and_update().clone();
+            match state {
+                PublishState::Uploaded { hs_dirs } => return Ok(hs_dirs),
+                PublishState::Pending | PublishState::Failed { .. } => {}
+            }
+            if states.changed().await.is_err() {
+                // The export was removed or the pipe stopped.
+                return Err(PipeError::ForwardNotFound(onion_addr.to_string()));
+            }
+        }
+    }
+
+    /// List the forwards currently active on the pipe.
+    pub async fn forwards(&self) -> Result<Vec<ForwardStatus>> {
+        self.request(Request::ListForwards).await
+    }
+
+    /// Query Tor's current bootstrap progress.
+    pub async fn bootstrap_phase(&self) -> Result<BootstrapPhase> {
+        self.request(Request::BootstrapPhase).await
+    }
+
+    /// Query the bytes Tor has read and written, if its backend reports them.
+    pub async fn tor_traffic(&self) -> Result<Option<TorTraffic>> {
+        self.request(Request::TorTraffic).await
+    }
+
+    async fn request<T>(&self, f: impl FnOnce(Reply<T>) -> Request) -> Result<T> {
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.tx
+            .send(f(reply_tx))
+            .await
+            .map_err(|_| PipeError::NotRunning)?;
+        reply_rx.await.map_err(|_| PipeError::NotRunning)?
+    }
+}
+
+/// Summary of an active forward, as reported by [`ForwardHandle::forwards`].
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ForwardStatus {
+    Export {
+        local_addr: String,
+        onion_addr: String,
+        remote_ports: Vec<u16>,
+        publish_state: PublishState,
+    },
+    Import {
+        onion_addr: String,
+        remote_port: u16,
+        local_addr: String,
+        /// Whether the import is still accepting local connections.
+        bound: bool,
+    },
+}
+
+impl ForwardStatus {
+    /// Whether the forward is usable: an export's descriptor has been
+    /// uploaded, or an import is accepting connections.
+    pub fn is_ready(&self) -> bool {
+        match self {
+            ForwardStatus::Export { publish_state, .. } => {
+                matches!(publish_state, PublishState::Uploaded { .. })
+            }
+            ForwardStatus::Import { bound, .. } => *bound,
+        }
+    }
+}
+
+impl std::fmt::Display for ForwardStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ForwardStatus::Export {
+                local_addr,
+                onion_addr,
+                remote_ports,
+                publish_state,
+            } => {
+                let ports: Vec<String> = remote_ports.iter().map(|p| p.to_string()).collect();
+                write!(
+                    f,
+                    "export {} => {}:{} ({})",
+                    local_addr,
+                    onion_addr,
+                    ports.join(","),
+                    publish_state
+                )
+            }
+            ForwardStatus::Import {
+                onion_addr,
+                remote_port,
+                local_addr,
+                bound,
+            } => {
+                write!(f, "import {}:{} => {}", onion_addr, remote_port, local_addr)?;
+                if !bound {
+                    write!(f, " (stopped)")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LocalAddr {
+    TCP(net::SocketAddr),
+    Unix(path::PathBuf),
+}
+
+const UNIX_ADDR_PREFIX: &str = "unix:";
+
+impl FromStr for LocalAddr {
+    type Err = PipeError;
+
+    fn from_str(s: &str) -> Result<LocalAddr> {
+        match s.strip_prefix(UNIX_ADDR_PREFIX) {
+            Some("") => Err(PipeError::Config(format!(
+                "invalid unix socket address {}",
+                s
+            ))),
+            Some(unix_path) => Ok(LocalAddr::Unix(path::PathBuf::from(unix_path))),
+            None => Ok(LocalAddr::TCP(net::SocketAddr::from_str(s)?)),
+        }
+    }
+}
+
+impl std::fmt::Display for LocalAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LocalAddr::TCP(addr) => write!(f, "{}", addr),
+            LocalAddr::Unix(unix_path) => write!(f, "{}{}", UNIX_ADDR_PREFIX, unix_path.display()),
+        }
+    }
 }
 
 pub struct Export {
-    pub local_addr: net::SocketAddr,
+    pub local_addr: LocalAddr,
     pub remote_key: onion::TorSecretKeyV3,
     pub remote_ports: Vec<u16>,
+    pub authorized_clients: Vec<crypto_box::PublicKey>,
+    pub connection: ConnectionOptions,
+}
+
+const CLIENT_KEY_PREFIX: &str = "descriptor:x25519:";
+
+/// A client's public key as an onion service authorizes it,
+/// `descriptor:x25519:<base32>`.
+pub fn client_public_key_descriptor(key: &crypto_box::PublicKey) -> String {
+    format!(
+        "{}{}",
+        CLIENT_KEY_PREFIX,
+        control::encode_x25519(key.as_bytes())
+    )
 }
 
-impl TryInto<Export> for (config::Export, Option<&mut secrets::SecretStore>) {
+/// A client's private key for `onion_addr`, as a line of a Tor client's
+/// `.auth_private` file: `<onion>:descriptor:x25519:<base32>`.
+pub fn client_auth_private(
+    onion_addr: &onion::OnionAddressV3,
+    key: &crypto_box::SecretKey,
+) -> String {
+    format!(
+        "{}:{}{}",
+        onion_addr.get_address_without_dot_onion(),
+        CLIENT_KEY_PREFIX,
+        control::encode_x25519(key.as_bytes())
+    )
+}
+
+/// Key bytes for a client, either given inline or looked up by name.
+enum ClientKeyBytes {
+    /// Decoded from a `descriptor:x25519:<base32>` key.
+    Inline([u8; 32]),
+    /// The client's secret key, from the secret store.
+    Stored([u8; 32]),
+}
+
+fn client_key_bytes(
+    client: &str,
+    secret_store: Option<&dyn secrets::SecretStore>,
+) -> Result<ClientKeyBytes> {
+    if let Some(encoded) = client.strip_prefix(CLIENT_KEY_PREFIX) {
+        return match control::decode_x25519(encoded) {
+            Some(key_bytes) => Ok(ClientKeyBytes::Inline(key_bytes)),
+            None => Err(PipeError::ClientKey(client.to_string())),
+        };
+    }
+    let secret_store = match secret_store {
+        Some(secret_store) => secret_store,
+        None => return Err(PipeError::Config("secret store not configured".to_string())),
+    };
+    match secret_store.get_client(client)? {
+        Some(key_bytes) => Ok(ClientKeyBytes::Stored(key_bytes)),
+        None => Err(PipeError::ClientKeyNotFound(client.to_string())),
+    }
+}
+
+/// An export's authorized client: an inline key is the client's public key.
+fn authorized_client_key(
+    client: &str,
+    secret_store: Option<&dyn secrets::SecretStore>,
+) -> Result<crypto_box::PublicKey> {
+    match client_key_bytes(client, secret_store)? {
+        ClientKeyBytes::Inline(key_bytes) => Ok(crypto_box::PublicKey::from(key_bytes)),
+        ClientKeyBytes::Stored(key_bytes) => {
+            Ok(crypto_box::SecretKey::from(key_bytes).public_key())
+        }
+    }
+}
+
+impl TryInto<Export> for (config::Export, Option<&mut (dyn secrets::SecretStore + '_)>) {
     type Error = PipeError;
 
     fn try_into(self) -> Result<Export> {
+        let authorized_clients = self
+            .0
+            .authorized_clients
+            .unwrap_or_default()
+            .iter()
+            .map(|client| authorized_client_key(client, self.1.as_deref()))
+            .collect::<Result<Vec<_>>>()?;
         let remote_key = match (self.0.service_name, self.1) {
             (Some(ref service_name), Some(secret_store)) => {
                 let key_bytes = secret_store.ensure_service(service_name)?;
@@ -144,10 +618,30 @@ impl TryInto<Export> for (config::Export, Option<&mut secrets::SecretStore>) {
             }
             (None, _) => torut::onion::TorSecretKeyV3::generate(),
         };
+        let local_addr = LocalAddr::from_str(self.0.local_addr.as_str())?;
+        if let LocalAddr::Unix(ref unix_path) = local_addr {
+            // Tor resolves the path itself, and the control protocol has no
+            // way to quote whitespace in a port target.
+            match unix_path.to_str() {
+                Some(p) if unix_path.is_absolute() && !p.contains(char::is_whitespace) => {}
+                _ => {
+                    return Err(PipeError::Config(format!(
+                        "unix socket export must be an absolute path without spaces: {}",
+                        unix_path.display()
+                    )))
+                }
+            }
+        }
+        let connection = match self.0.connection {
+            Some(connection) => connection.try_into()?,
+            None => ConnectionOptions::default(),
+        };
         Ok(Export {
-            local_addr: std::net::SocketAddr::from_str(self.0.local_addr.as_str())?,
-            remote_key: remote_key,
+            local_addr,
+            remote_key,
             remote_ports: self.0.remote_ports,
+            authorized_clients,
+            connection,
         })
     }
 }
@@ -155,24 +649,91 @@ impl TryInto<Export> for (config::Export, Option<&mut secrets::SecretStore>) {
 pub struct Import {
     pub remote_addr: onion::OnionAddress,
     pub remote_port: u16,
-    pub local_addr: net::SocketAddr,
+    pub local_addr: LocalAddr,
+    pub client_key: Option<crypto_box::SecretKey>,
+    pub unix_socket: UnixSocketOptions,
+    /// The name of the socket passed by systemd to listen on, if any.
+    pub listen_fd_name: Option<String>,
+    pub retry: RetryPolicy,
+    pub connection: ConnectionOptions,
+}
+
+/// An import's client key: an inline key is the client's secret key.
+fn client_secret_key(
+    client: &str,
+    secret_store: Option<&dyn secrets::SecretStore>,
+) -> Result<crypto_box::SecretKey> {
+    match client_key_bytes(client, secret_store)? {
+        ClientKeyBytes::Inline(key_bytes) | ClientKeyBytes::Stored(key_bytes) => {
+            Ok(crypto_box::SecretKey::from(key_bytes))
+        }
+    }
 }
 
-impl TryInto<Import> for config::Import {
+impl TryInto<Import> for (config::Import, Option<&(dyn secrets::SecretStore + '_)>) {
     type Error = PipeError;
 
     fn try_into(self) -> Result<Import> {
-        let (remote_addr, remote_port) = parse_onion_address(&self.remote_addr)?;
+        let (remote_addr, remote_port) = parse_onion_address(&self.0.remote_addr)?;
+        let client_key = match self.0.client_key {
+            Some(ref client) => Some(client_secret_key(client, self.1)?),
+            None => None,
+        };
+        let local_addr = LocalAddr::from_str(self.0.local_addr.as_str())?;
+        let unix_socket = UnixSocketOptions {
+            mode: self
+                .0
+                .unix_mode
+                .as_deref()
+                .map(unix::parse_mode)
+                .transpose()?,
+            owner: self
+                .0
+                .unix_owner
+                .as_deref()
+                .map(unix::lookup_user)
+                .transpose()?,
+            group: self
+                .0
+                .unix_group
+                .as_deref()
+                .map(unix::lookup_group)
+                .transpose()?,
+        };
+        let retry = match self.0.retry {
+            Some(retry) => retry.try_into()?,
+            None => RetryPolicy::default(),
+        };
+        let connection = match self.0.connection {
+            Some(connection) => connection.try_into()?,
+            None => ConnectionOptions::default(),
+        };
+        if !unix_socket.is_empty() && !matches!(local_addr, LocalAddr::Unix(_)) {
+            return Err(PipeError::Config(format!(
+                "unix socket options require a unix: local address, got {}",
+                local_addr
+            )));
+        }
         Ok(Import {
             remote_addr: torut::onion::OnionAddress::V3(remote_addr),
-            remote_port: remote_port,
-            local_addr: std::net::SocketAddr::from_str(self.local_addr.as_str())?,
+            remote_port,
+            local_addr,
+            client_key,
+            unix_socket,
+            listen_fd_name: self.0.listen_fd_name,
+            retry,
+            connection,
         })
     }
 }
 
+pub fn parse_log_level(log_level: &str) -> Result<tracing::Level> {
+    tracing::Level::from_str(log_level)
+        .map_err(|_| PipeError::Config(format!("invalid log level {}", log_level)))
+}
+
 fn parse_err(addr: &str) -> PipeError {
-    return PipeError::Config(format!("invalid onion address {}", addr).to_string());
+    PipeError::Config(format!("invalid onion address {}", addr))
 }
 
 fn parse_onion_address(addr: &str) -> Result<(torut::onion::OnionAddressV3, u16)> {
@@ -202,12 +763,6 @@ pub enum Forward {
     Import(Import),
 }
 
-async fn on_event_noop(
-    _: torut::control::AsyncEvent<'static>,
-) -> result::Result<(), torut::control::ConnError> {
-    Ok(())
-}
-
 impl OnionPipe {
     pub fn defaults() -> OnionPipeBuilder {
         OnionPipeBuilder {
@@ -215,189 +770,672 @@ impl OnionPipe {
             exports: vec![],
             imports: vec![],
             secret_store: None,
+            log_level: tracing::Level::INFO,
+            startup_timeout: Some(DEFAULT_STARTUP_TIMEOUT),
+            backend: TorBackend::default(),
+            metrics_addr: None,
+            health_addr: None,
+            listen_fds: systemd::ListenFds::default(),
         }
     }
 
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn forward_handle(&self) -> ForwardHandle {
+        ForwardHandle {
+            tx: self.requests_tx.clone(),
+            publish_states: self.publish_states.clone(),
+        }
+    }
+
+    /// Subscribe to events from the pipe. Subscribe before calling
+    /// [`OnionPipe::run`] to see the initial forwards start.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<OnionPipeEvent> {
+        self.events.subscribe()
+    }
+
     pub async fn run(&mut self) -> Result<()> {
-        self.start_tor();
-
-        wait_for_file(&self.control_sock).await?;
-        let s = tokio::net::UnixStream::connect(&self.control_sock).await?;
-        let mut utc = control::UnauthenticatedConn::new(s);
-        utc.authenticate(&control::TorAuthData::Null).await?;
-        let mut ac = utc.into_authenticated().await;
-        ac.set_async_event_handler(Some(on_event_noop));
-        ac.take_ownership().await?;
-
-        let mut active_onions = vec![];
-        for i in 0..self.exports.len() {
-            let export = &self.exports[i];
-            let remote_key = &export.remote_key;
-            println!(
-                "forward {} => {}:{}",
-                export.local_addr,
-                remote_key.public().get_onion_address(),
-                export
-                    .remote_ports
-                    .iter()
-                    .map(|port| port.to_string())
-                    .collect::<Vec<_>>()
-                    .join(","),
+        let mut running = Running::default();
+        let result = match self.start(&mut running).await {
+            Ok(()) => self.serve(&mut running).await,
+            Err(err) => Err(err),
+        };
+        // Whatever was started is stopped, even if starting failed.
+        let closed = self.stop(running).await;
+        result.and(closed)
+    }
+
+    /// Start Tor, and serve metrics and health checks alongside it.
+    async fn start(&mut self, running: &mut Running) -> Result<()> {
+        let deadline = self
+            .startup_timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout);
+        if let (Some(metrics_addr), Some(metrics)) = (self.metrics_addr, &self.metrics) {
+            let listener = tokio::net::TcpListener::bind(metrics_addr).await?;
+            tracing::info!(
+                "serving metrics on http://{}/metrics",
+                listener.local_addr()?
             );
-            ac.add_onion_v3(
-                remote_key,
-                false,
-                false,
-                false,
-                None,
-                &mut export
-                    .remote_ports
-                    .iter()
-                    .map(|port| (port.to_owned(), export.local_addr))
-                    .collect::<Vec<_>>()
-                    .iter(),
-            )
-            .await?;
-            active_onions.push(
-                remote_key
-                    .public()
-                    .get_onion_address()
-                    .get_address_without_dot_onion(),
+            let metrics_task = tokio::spawn(metrics::serve(
+                listener,
+                metrics.clone(),
+                self.forward_handle(),
+            ));
+            running.tasks.push(metrics_task.abort_handle());
+        }
+        if let Some(health_addr) = self.health_addr {
+            let listener = tokio::net::TcpListener::bind(health_addr).await?;
+            tracing::info!(
+                "serving health checks on http://{}/readyz",
+                listener.local_addr()?
             );
+            let health_task = tokio::spawn(health::serve(listener, self.forward_handle()));
+            running.tasks.push(health_task.abort_handle());
+        }
+        self.tor.start(self.log_level, deadline).await?;
+        let ac = running.ac.insert(self.tor.connect().await?);
+        if self.tor.is_embedded() {
+            // Tor exits when this connection closes, should onionpipe die
+            // without stopping it.
+            ac.take_ownership().await?;
         }
+        wait_for_bootstrap(ac, deadline, &mut self.tor, &self.events).await?;
 
-        self.forward_imports().await?;
+        running.publish_task = self
+            .tor
+            .watch_uploads(&self.publish_states, &self.events)
+            .await?;
+        Ok(())
+    }
 
-        tokio::signal::ctrl_c().await?;
-        eprintln!("interrupt received, shutting down");
+    /// Start the forwards, then carry out requests until shutdown.
+    async fn serve(&mut self, running: &mut Running) -> Result<()> {
+        let ac = running.ac.as_mut().expect("tor is connected");
+        for export in self.exports.iter() {
+            if let Some(relay) = publish_export(
+                ac,
+                export,
+                &self.temp_dir,
+                &self.publish_states,
+                &self.events,
+                self.metrics.as_ref(),
+            )
+            .await?
+            {
+                self.export_relays.insert(
+                    export.remote_key.public().get_onion_address().to_string(),
+                    relay,
+                );
+            }
+        }
+
+        for import in self.imports.iter() {
+            running
+                .import_tasks
+                .push(self.start_import(ac, import).await?);
+        }
+        // A passed socket no import listens on would accept connections that
+        // are never answered.
+        if let Some(name) = self.listen_fds.untaken().first() {
+            return Err(PipeError::Config(format!(
+                "socket {} passed by systemd matches no import",
+                name
+            )));
+        }
 
-        for i in 0..active_onions.len() {
-            match ac.del_onion(active_onions[i].as_str()).await {
-                Err(control::ConnError::IOError(io_err)) => {
-                    if io_err.kind() == std::io::ErrorKind::ConnectionReset {
-                        // Control connection may be lost here
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        let mut tor_check = tokio::time::interval(TOR_CHECK_INTERVAL);
+        while !*shutdown_rx.borrow_and_update() {
+            tokio::select! {
+                changed = shutdown_rx.changed() => {
+                    if changed.is_err() {
                         break;
                     }
-                    eprintln!("failed to delete onion: {:?}", io_err);
                 }
-                Err(err) => {
-                    eprintln!("failed to delete onion: {:?}", err);
+                Some(request) = self.requests_rx.recv() => {
+                    self.handle_request(ac, &mut running.import_tasks, request).await;
+                }
+                _ = tor_check.tick() => self.tor.check()?,
+                result = join_task(&mut running.publish_task) => {
+                    // Publish states would no longer change.
+                    result?;
+                    return Err(PipeError::from(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "stopped receiving descriptor upload events",
+                    )));
                 }
-                _ => {}
             }
         }
-        // TODO: poll w/timeout for a connection reset, ping w/ GETINFO
-
-        // Close connection
-        drop(ac);
-        // Delete data dir
-        tokio::fs::remove_dir_all(&self.data_dir).await?;
-        // Clean up temp dir
-        self.temp_dir.take().unwrap().close()?;
         Ok(())
     }
 
-    async fn forward_imports(&self) -> Result<()> {
-        for i in 0..self.imports.len() {
-            let import = &self.imports[i];
-            let import_addr = format!("{}:{}", import.remote_addr, import.remote_port);
-            let socks_addr = self.socks_sock.to_string();
+    /// Remove the onions and stop everything [`OnionPipe::start`] and
+    /// [`OnionPipe::serve`] started.
+    async fn stop(&mut self, running: Running) -> Result<()> {
+        // Refuse any requests made from here on.
+        self.requests_rx.close();
+        while let Ok(request) = self.requests_rx.try_recv() {
+            request.reject();
+        }
 
-            tokio::spawn(run_import(
-                import.local_addr.to_string(),
-                socks_addr,
-                import_addr.to_string(),
-            ));
+        for task in running.tasks {
+            task.abort();
+        }
+        if let Some(publish_task) = running.publish_task {
+            publish_task.abort();
+        }
+        for import_task in running.import_tasks.iter() {
+            import_task.abort();
+        }
+        // Only the onions that were added are removed, should publishing one
+        // have failed.
+        let onion_addrs: Vec<_> = self
+            .exports
+            .iter()
+            .map(|export| export.remote_key.public().get_onion_address())
+            .filter(|onion_addr| self.publish_states.get(onion_addr).is_some())
+            .collect();
+        self.publish_states.clear();
+
+        if let Some(mut ac) = running.ac {
+            for onion_addr in onion_addrs {
+                match ac.del_onion(&onion_addr).await {
+                    Err(PipeError::Control(ControlError::Conn(
+                        torut::control::ConnError::IOError(io_err),
+                    ))) => {
+                        if io_err.kind() == std::io::ErrorKind::ConnectionReset {
+                            // Control connection may be lost here
+                            break;
+                        }
+                        tracing::warn!("failed to delete onion: {:?}", io_err);
+                    }
+                    Err(err) => {
+                        tracing::warn!("failed to delete onion: {:?}", err);
+                    }
+                    Ok(()) => {
+                        let _ = self
+                            .events
+                            .send(OnionPipeEvent::OnionRemoved { onion_addr });
+                    }
+                }
+            }
+            // TODO: poll w/timeout for a connection reset, ping w/ GETINFO
+        }
+        self.export_relays.clear();
 
-            println!("forward {} => {}", import_addr, import.local_addr,);
+        // Remove the sockets of imports that were started
+        for import in self.imports.iter().take(running.import_tasks.len()) {
+            if let LocalAddr::Unix(ref socket_path) = import.local_addr {
+                let _ = tokio::fs::remove_file(socket_path).await;
+            }
         }
-        Ok(())
+        // Delete data dir of an embedded tor
+        self.tor.close().await
     }
 
-    fn start_tor(&self) -> () {
-        // TODO(long-term): replace with Arti when it supports onions!
-        libtor::Tor::new()
-            .flag(libtor::TorFlag::ControlSocket(
-                self.control_sock.as_str().into(),
-            ))
-            .flag(libtor::TorFlag::DataDirectory(
-                self.data_dir.as_str().into(),
-            ))
-            // TODO: configurable log level
-            .flag(libtor::TorFlag::LogTo(
-                libtor::log::LogLevel::Warn,
-                libtor::log::LogDestination::Stderr,
-            ))
-            .flag(libtor::TorFlag::Custom(
-                format!(
-                    "SocksPort unix:{} OnionTrafficOnly",
-                    self.socks_sock.as_str()
-                )
-                .into(),
-            ))
-            .start_background();
+    async fn handle_request(
+        &mut self,
+        ac: &mut backend::TorControl,
+        import_tasks: &mut Vec<tokio::task::JoinHandle<Result<()>>>,
+        request: Request,
+    ) {
+        match request {
+            Request::AddExport(export, reply) => {
+                let onion_addr = export.remote_key.public().get_onion_address();
+                let result = if self.find_export(&onion_addr).is_some() {
+                    Err(PipeError::Config(format!(
+                        "{} is already exported",
+                        onion_addr
+                    )))
+                } else {
+                    match publish_export(
+                        ac,
+                        &export,
+                        &self.temp_dir,
+                        &self.publish_states,
+                        &self.events,
+                        self.metrics.as_ref(),
+                    )
+                    .await
+                    {
+                        Ok(relay) => {
+                            if let Some(relay) = relay {
+                                self.export_relays.insert(onion_addr.to_string(), relay);
+                            }
+                            self.exports.push(export);
+                            Ok(onion_addr)
+                        }
+                        Err(err) => Err(err),
+                    }
+                };
+                let _ = reply.send(result);
+            }
+            Request::RemoveExport(onion_addr, reply) => {
+                let result = match self.find_export(&onion_addr) {
+                    Some(i) => ac.del_onion(&onion_addr).await.map(|_| {
+                        self.exports.remove(i);
+                        self.export_relays.remove(&onion_addr.to_string());
+                        if let Some(ref metrics) = self.metrics {
+                            metrics.remove_export(&onion_addr);
+                        }
+                        self.publish_states.remove(&onion_addr);
+                        let _ = self
+                            .events
+                            .send(OnionPipeEvent::OnionRemoved { onion_addr });
+                    }),
+                    None => Err(PipeError::ForwardNotFound(onion_addr.to_string())),
+                };
+                let _ = reply.send(result);
+            }
+            Request::AddImport(import, reply) => {
+                let result = if self.find_import(&import.local_addr).is_some() {
+                    Err(PipeError::Config(format!(
+                        "{} is already forwarded",
+                        import.local_addr
+                    )))
+                } else {
+                    match self.start_import(ac, &import).await {
+                        Ok(import_task) => {
+                            self.imports.push(import);
+                            import_tasks.push(import_task);
+                            Ok(())
+                        }
+                        Err(err) => Err(err),
+                    }
+                };
+                let _ = reply.send(result);
+            }
+            Request::RemoveImport(local_addr, reply) => {
+                let result = match self.find_import(&local_addr) {
+                    Some(i) => {
+                        import_tasks.remove(i).abort();
+                        let import = self.imports.remove(i);
+                        if let LocalAddr::Unix(ref socket_path) = import.local_addr {
+                            let _ = tokio::fs::remove_file(socket_path).await;
+                        }
+                        if let Some(ref metrics) = self.metrics {
+                            metrics.remove_import(&import.local_addr);
+                        }
+                        let _ = self
+                            .events
+                            .send(OnionPipeEvent::ImportRemoved { local_addr });
+                        Ok(())
+                    }
+                    None => Err(PipeError::ForwardNotFound(local_addr.to_string())),
+                };
+                let _ = reply.send(result);
+            }
+            Request::ListForwards(reply) => {
+                let _ = reply.send(Ok(self.forward_statuses(import_tasks)));
+            }
+            Request::BootstrapPhase(reply) => {
+                let _ = reply.send(ac.bootstrap_phase().await);
+            }
+            Request::TorTraffic(reply) => {
+                let _ = reply.send(ac.traffic().await);
+            }
+        }
     }
-}
 
-async fn run_import(local_addr: String, socks_addr: String, import_addr: String) -> Result<()> {
-    let local_listener = tokio::net::TcpListener::bind(local_addr).await?;
-    loop {
-        let (local_stream, _) = local_listener.accept().await?;
-        println!("got connection");
-        let proxy_stream = match tokio::net::UnixStream::connect(socks_addr.as_str()).await {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("socks proxy connection failed: {}", e);
-                continue;
+    fn forward_statuses(
+        &self,
+        import_tasks: &[tokio::task::JoinHandle<Result<()>>],
+    ) -> Vec<ForwardStatus> {
+        let exports = self.exports.iter().map(|export| {
+            let onion_addr = export.remote_key.public().get_onion_address();
+            ForwardStatus::Export {
+                local_addr: export.local_addr.to_string(),
+                onion_addr: onion_addr.to_string(),
+                remote_ports: export.remote_ports.clone(),
+                publish_state: self
+                    .publish_states
+                    .get(&onion_addr)
+                    .unwrap_or(PublishState::Pending),
             }
+        });
+        let imports = self
+            .imports
+            .iter()
+            .zip(import_tasks)
+            .map(|(import, import_task)| ForwardStatus::Import {
+                onion_addr: import.remote_addr.to_string(),
+                remote_port: import.remote_port,
+                local_addr: import.local_addr.to_string(),
+                bound: !import_task.is_finished(),
+            });
+        exports.chain(imports).collect()
+    }
+
+    fn find_export(&self, onion_addr: &onion::OnionAddressV3) -> Option<usize> {
+        self.exports
+            .iter()
+            .position(|export| export.remote_key.public().get_onion_address() == *onion_addr)
+    }
+
+    fn find_import(&self, local_addr: &LocalAddr) -> Option<usize> {
+        self.imports
+            .iter()
+            .position(|import| import.local_addr == *local_addr)
+    }
+
+    async fn start_import(
+        &self,
+        ac: &mut backend::TorControl,
+        import: &Import,
+    ) -> Result<tokio::task::JoinHandle<Result<()>>> {
+        if let Some(ref client_key) = import.client_key {
+            ac.client_auth_add(&import.remote_addr, client_key).await?;
+        }
+        let connector = self.tor.connector()?;
+        let listener = ImportListener::bind(import, &self.listen_fds).await?;
+        let proxy = ImportProxy {
+            connector,
+            remote_addr: import.remote_addr.clone(),
+            remote_port: import.remote_port,
+            local_addr: import.local_addr.clone(),
+            retry: import.retry.clone(),
+            connection: import.connection.clone(),
+            metrics: self.metrics.as_ref().map(|metrics| metrics.import(import)),
+            events: self.events.clone(),
         };
-        let remote_stream = match tokio_socks::tcp::Socks5Stream::connect_with_socket(
-            proxy_stream,
-            import_addr.as_str(),
-        )
+        let import_task = tokio::spawn(run_import(listener, proxy));
+        let _ = self.events.send(OnionPipeEvent::ImportBound {
+            onion_addr: import.remote_addr.clone(),
+            remote_port: import.remote_port,
+            local_addr: import.local_addr.clone(),
+        });
+        Ok(import_task)
+    }
+}
+
+async fn publish_export(
+    ac: &mut backend::TorControl,
+    export: &Export,
+    relay_dir: &path::Path,
+    publish_states: &publish::PublishStates,
+    events: &tokio::sync::broadcast::Sender<OnionPipeEvent>,
+    metrics: Option<&metrics::Metrics>,
+) -> Result<Option<relay::ExportRelay>> {
+    let onion_addr = export.remote_key.public().get_onion_address();
+    // Track uploads before adding the onion, so that none are missed.
+    publish_states.register(&onion_addr);
+    let forward_metrics = metrics.map(|metrics| metrics.export(export));
+    let relay = match ac
+        .add_onion(export, relay_dir, publish_states, events, forward_metrics)
         .await
-        {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("remote onion connection failed: {}", e);
-                continue;
+    {
+        Ok(relay) => relay,
+        Err(err) => {
+            publish_states.remove(&onion_addr);
+            if let Some(metrics) = metrics {
+                metrics.remove_export(&onion_addr);
             }
-        };
-        tokio::spawn(forward_stream(local_stream, remote_stream));
+            return Err(err);
+        }
+    };
+    let _ = events.send(OnionPipeEvent::OnionPublished {
+        local_addr: export.local_addr.clone(),
+        onion_addr,
+        remote_ports: export.remote_ports.clone(),
+    });
+    Ok(relay)
+}
+
+enum ImportListener {
+    Tcp(tokio::net::TcpListener),
+    Unix(tokio::net::UnixListener),
+}
+
+impl ImportListener {
+    async fn bind(import: &Import, listen_fds: &systemd::ListenFds) -> Result<ImportListener> {
+        if let Some(fd) = listen_fds.take(&import.local_addr, import.listen_fd_name.as_deref())? {
+            return ImportListener::from_fd(&import.local_addr, fd);
+        }
+        match import.local_addr {
+            LocalAddr::TCP(addr) => Ok(ImportListener::Tcp(
+                tokio::net::TcpListener::bind(addr).await?,
+            )),
+            LocalAddr::Unix(ref socket_path) => Ok(ImportListener::Unix(unix::bind(
+                socket_path,
+                &import.unix_socket,
+            )?)),
+        }
+    }
+
+    fn from_fd(local_addr: &LocalAddr, fd: std::os::fd::OwnedFd) -> Result<ImportListener> {
+        match local_addr {
+            LocalAddr::TCP(_) => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(ImportListener::Tcp(tokio::net::TcpListener::from_std(
+                    listener,
+                )?))
+            }
+            LocalAddr::Unix(_) => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(ImportListener::Unix(tokio::net::UnixListener::from_std(
+                    listener,
+                )?))
+            }
+        }
     }
 }
 
-async fn forward_stream(
-    mut local: tokio::net::TcpStream,
-    mut remote: tokio_socks::tcp::Socks5Stream<tokio::net::UnixStream>,
-) -> Result<()> {
-    let (mut local_read, mut local_write) = local.split();
-    let (mut remote_read, mut remote_write) = remote.split();
-    tokio::select! {
-        _ = async {
-            tokio::io::copy(&mut remote_read, &mut local_write).await?;
-            Ok::<_, PipeError>(())
-        } => {}
-        _ = async {
-            tokio::io::copy(&mut local_read, &mut remote_write).await?;
-            Ok::<_, PipeError>(())
-        } => {}
-        else => {}
-    };
-    Ok(())
+static NEXT_CONNECTION_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+/// Everything needed to carry a local connection to an imported onion.
+#[derive(Clone)]
+struct ImportProxy {
+    connector: backend::Connector,
+    remote_addr: onion::OnionAddress,
+    remote_port: u16,
+    local_addr: LocalAddr,
+    retry: RetryPolicy,
+    connection: ConnectionOptions,
+    metrics: Option<std::sync::Arc<metrics::ForwardMetrics>>,
+    events: tokio::sync::broadcast::Sender<OnionPipeEvent>,
+}
+
+/// Carry local connections to the onion until aborted, which also closes the
+/// connections still open.
+async fn run_import(listener: ImportListener, proxy: ImportProxy) -> Result<()> {
+    let mut connections = tokio::task::JoinSet::new();
+    match listener {
+        ImportListener::Tcp(local_listener) => loop {
+            tokio::select! {
+                accepted = local_listener.accept() => {
+                    let (local_stream, _) = accepted?;
+                    if let Err(err) = proxy.connection.configure(&local_stream) {
+                        tracing::warn!("failed to configure {}: {}", proxy.local_addr, err);
+                    }
+                    proxy.proxy(local_stream, &mut connections);
+                }
+                Some(_) = connections.join_next() => {}
+            }
+        },
+        ImportListener::Unix(local_listener) => loop {
+            tokio::select! {
+                accepted = local_listener.accept() => {
+                    let (local_stream, _) = accepted?;
+                    proxy.proxy(local_stream, &mut connections);
+                }
+                Some(_) = connections.join_next() => {}
+            }
+        },
+    }
 }
 
-async fn wait_for_file(path: &str) -> Result<()> {
-    for i in 0..10 {
-        match tokio::fs::metadata(path).await {
-            Ok(_) => {
-                return Ok(());
+impl ImportProxy {
+    /// Carry `local_stream` to the onion in a task of `connections`, holding
+    /// it open while connecting.
+    fn proxy<S>(&self, local_stream: S, connections: &mut tokio::task::JoinSet<()>)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static,
+    {
+        let id = NEXT_CONNECTION_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let _ = self.events.send(OnionPipeEvent::ConnectionOpened {
+            id,
+            local_addr: self.local_addr.clone(),
+            onion_addr: self.remote_addr.clone(),
+            remote_port: self.remote_port,
+        });
+        let active = self.metrics.as_ref().map(|metrics| metrics.open());
+        let proxy = self.clone();
+        connections.spawn(async move {
+            let _active = active;
+            let started = tokio::time::Instant::now();
+            let remote_stream = match proxy.connect(id).await {
+                Ok(s) => s,
+                Err(err) => {
+                    if let Some(ref metrics) = proxy.metrics {
+                        metrics.failed();
+                    }
+                    let _ = proxy.events.send(OnionPipeEvent::ConnectionError {
+                        id,
+                        error: std::sync::Arc::new(err),
+                    });
+                    return;
+                }
+            };
+            if let Some(ref metrics) = proxy.metrics {
+                metrics.connected(started.elapsed());
+            }
+            let mut bytes_sent = 0;
+            let mut bytes_received = 0;
+            let result = relay::forward_stream(
+                metrics::Metered::new(local_stream, proxy.metrics.clone()),
+                remote_stream,
+                &proxy.connection,
+                &mut bytes_sent,
+                &mut bytes_received,
+            )
+            .await;
+            match result {
+                Err(err) if relay::is_timeout(&err) => {
+                    tracing::info!("connection {} closed: {}", id, err);
+                }
+                Err(err) => {
+                    if let Some(ref metrics) = proxy.metrics {
+                        metrics.failed();
+                    }
+                    let _ = proxy.events.send(OnionPipeEvent::ConnectionError {
+                        id,
+                        error: std::sync::Arc::new(err),
+                    });
+                }
+                Ok(()) => {}
+            }
+            let _ = proxy.events.send(OnionPipeEvent::ConnectionClosed {
+                id,
+                bytes_sent,
+                bytes_received,
+            });
+        });
+    }
+
+    /// Connect to the onion, retrying as the import's policy allows.
+    async fn connect(&self, id: u64) -> Result<Box<dyn backend::TorStream>> {
+        let deadline = self
+            .retry
+            .deadline
+            .map(|deadline| tokio::time::Instant::now() + deadline);
+        let mut attempt = 1;
+        loop {
+            let connect = self.connector.connect(&self.remote_addr, self.remote_port);
+            let result = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, connect)
+                    .await
+                    .unwrap_or(Err(PipeError::OnionConnectTimeout)),
+                None => connect.await,
+            };
+            let err = match result {
+                Ok(stream) => {
+                    if attempt > 1 {
+                        tracing::info!(
+                            "connection {} to {}:{} succeeded on attempt {}",
+                            id,
+                            self.remote_addr,
+                            self.remote_port,
+                            attempt
+                        );
+                    }
+                    return Ok(stream);
+                }
+                Err(err) => err,
+            };
+            let delay = self.retry.delay(attempt);
+            if attempt >= self.retry.attempts
+                || !retry::is_retryable(&err)
+                || deadline.is_some_and(|deadline| tokio::time::Instant::now() + delay >= deadline)
+            {
+                if self.retry.attempts > 1 {
+                    tracing::warn!(
+                        "connection {} to {}:{} failed after {} attempts: {}",
+                        id,
+                        self.remote_addr,
+                        self.remote_port,
+                        attempt,
+                        err
+                    );
+                }
+                return Err(err);
             }
-            Err(_) => {}
+            tracing::info!(
+                "connection {} to {}:{} attempt {} of {} failed, retrying in {:?}: {}",
+                id,
+                self.remote_addr,
+                self.remote_port,
+                attempt,
+                self.retry.attempts,
+                delay,
+                err
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
         }
-        tokio::time::sleep(tokio::time::Duration::from_secs(i)).await;
     }
-    Err(PipeError::ConnTimeout)
+}
+
+const DEFAULT_STARTUP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+const STARTUP_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+const TOR_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Wait for Tor to finish bootstrapping, reporting progress as it goes.
+async fn wait_for_bootstrap(
+    ac: &mut backend::TorControl,
+    deadline: Option<tokio::time::Instant>,
+    tor: &mut backend::Tor,
+    events: &tokio::sync::broadcast::Sender<OnionPipeEvent>,
+) -> Result<()> {
+    let mut progress = None;
+    loop {
+        let phase = ac.bootstrap_phase().await?;
+        if progress != Some(phase.progress) {
+            progress = Some(phase.progress);
+            let _ = events.send(OnionPipeEvent::Bootstrap {
+                phase: phase.clone(),
+            });
+        }
+        if phase.is_done() {
+            return Ok(());
+        }
+        tor.check()?;
+        if backend::past_deadline(deadline) {
+            return Err(PipeError::BootstrapStalled(phase.progress));
+        }
+        tokio::time::sleep(STARTUP_POLL_INTERVAL).await;
+    }
+}
+
+/// Wait for a task to finish, or forever if there is none.
+async fn join_task(task: &mut Option<tokio::task::JoinHandle<Result<()>>>) -> Result<()> {
+    match task {
+        Some(task) => task.await?,
+        None => std::future::pending().await,
+    }
 }
 
 #[cfg(test)]
@@ -410,13 +1448,19 @@ mod tests {
             local_addr: "127.0.0.1:4566".to_string(),
             service_name: Some("some_service".to_string()),
             remote_ports: vec![4567],
+            authorized_clients: None,
+            connection: None,
         };
         let tmp_dir = tempfile::tempdir().unwrap();
         let secrets_dir = tmp_dir.path().join("secrets");
-        let mut store = secrets::SecretStore::new(secrets_dir.to_str().unwrap());
+        let mut store: Box<dyn secrets::SecretStore> =
+            Box::new(secrets::FileSecretStore::new(secrets_dir.to_str().unwrap()));
 
-        let export: Export = (export_config, Some(&mut store)).try_into().unwrap();
-        assert_eq!("127.0.0.1:4566".parse(), Ok(export.local_addr));
+        let export: Export = (export_config, Some(store.as_mut())).try_into().unwrap();
+        assert_eq!(
+            LocalAddr::TCP("127.0.0.1:4566".parse().unwrap()),
+            export.local_addr
+        );
         assert_eq!(
             export
                 .remote_key
@@ -435,8 +1479,10 @@ mod tests {
             local_addr: "127.0.0.1:4566".to_string(),
             service_name: Some("some_service".to_string()),
             remote_ports: vec![4567],
+            authorized_clients: None,
+            connection: None,
         };
-        let export2: Export = (export2_config, Some(&mut store)).try_into().unwrap();
+        let export2: Export = (export2_config, Some(store.as_mut())).try_into().unwrap();
         assert_eq!(export.remote_key, export2.remote_key);
         assert_eq!(store.list_services().unwrap(), vec!["some_service"]);
     }
@@ -447,13 +1493,19 @@ mod tests {
             local_addr: "127.0.0.1:4566".to_string(),
             service_name: None,
             remote_ports: vec![4567],
+            authorized_clients: None,
+            connection: None,
         };
         let tmp_dir = tempfile::tempdir().unwrap();
         let secrets_dir = tmp_dir.path().join("secrets");
-        let mut store = secrets::SecretStore::new(secrets_dir.to_str().unwrap());
+        let mut store: Box<dyn secrets::SecretStore> =
+            Box::new(secrets::FileSecretStore::new(secrets_dir.to_str().unwrap()));
 
-        let export: Export = (export_config, Some(&mut store)).try_into().unwrap();
-        assert_eq!("127.0.0.1:4566".parse(), Ok(export.local_addr));
+        let export: Export = (export_config, Some(store.as_mut())).try_into().unwrap();
+        assert_eq!(
+            LocalAddr::TCP("127.0.0.1:4566".parse().unwrap()),
+            export.local_addr
+        );
         assert_eq!(
             export
                 .remote_key
@@ -467,19 +1519,544 @@ mod tests {
         assert_eq!(export.remote_ports, vec![4567]);
     }
 
+    #[test]
+    fn try_into_export_authorized_clients() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let secrets_dir = tmp_dir.path().join("secrets");
+        let mut store: Box<dyn secrets::SecretStore> =
+            Box::new(secrets::FileSecretStore::new(secrets_dir.to_str().unwrap()));
+        let alice_key = store.ensure_client("alice").unwrap();
+        let bob_key = crypto_box::PublicKey::from([9u8; 32]);
+
+        let export_config = config::Export {
+            local_addr: "127.0.0.1:4566".to_string(),
+            service_name: None,
+            remote_ports: vec![4567],
+            authorized_clients: Some(vec![
+                "alice".to_string(),
+                format!(
+                    "descriptor:x25519:{}",
+                    control::encode_x25519(bob_key.as_bytes())
+                ),
+            ]),
+            connection: None,
+        };
+        let export: Export = (export_config, Some(store.as_mut())).try_into().unwrap();
+        assert_eq!(
+            export.authorized_clients,
+            vec![crypto_box::SecretKey::from(alice_key).public_key(), bob_key]
+        );
+
+        let export_config = config::Export {
+            local_addr: "127.0.0.1:4566".to_string(),
+            service_name: None,
+            remote_ports: vec![4567],
+            authorized_clients: Some(vec!["carol".to_string()]),
+            connection: None,
+        };
+        let result: Result<Export> = (export_config, Some(store.as_mut())).try_into();
+        assert!(matches!(result, Err(PipeError::ClientKeyNotFound(name)) if name == "carol"));
+
+        let export_config = config::Export {
+            local_addr: "127.0.0.1:4566".to_string(),
+            service_name: None,
+            remote_ports: vec![4567],
+            authorized_clients: Some(vec!["descriptor:x25519:nope".to_string()]),
+            connection: None,
+        };
+        let result: Result<Export> = (export_config, Some(store.as_mut())).try_into();
+        assert!(matches!(result, Err(PipeError::ClientKey(_))));
+    }
+
+    #[test]
+    fn try_into_import_client_key() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let secrets_dir = tmp_dir.path().join("secrets");
+        let mut store: Box<dyn secrets::SecretStore> =
+            Box::new(secrets::FileSecretStore::new(secrets_dir.to_str().unwrap()));
+        let bob_key = store.ensure_client("bob").unwrap();
+
+        let import_config = config::Import {
+            remote_addr: "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:80"
+                .to_string(),
+            local_addr: "127.0.0.1:8080".to_string(),
+            client_key: Some("bob".to_string()),
+            unix_mode: None,
+            unix_owner: None,
+            unix_group: None,
+            listen_fd_name: None,
+            retry: None,
+            connection: None,
+        };
+        let import: Import = (import_config, Some(store.as_ref())).try_into().unwrap();
+        assert_eq!(import.client_key.unwrap().as_bytes(), &bob_key);
+
+        let import_config = config::Import {
+            remote_addr: "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:80"
+                .to_string(),
+            local_addr: "127.0.0.1:8080".to_string(),
+            client_key: Some(format!(
+                "descriptor:x25519:{}",
+                control::encode_x25519(&[5u8; 32])
+            )),
+            unix_mode: None,
+            unix_owner: None,
+            unix_group: None,
+            listen_fd_name: None,
+            retry: None,
+            connection: None,
+        };
+        let import: Import = (import_config, None).try_into().unwrap();
+        assert_eq!(import.client_key.unwrap().as_bytes(), &[5u8; 32]);
+
+        let import_config = config::Import {
+            remote_addr: "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:80"
+                .to_string(),
+            local_addr: "127.0.0.1:8080".to_string(),
+            client_key: Some("carol".to_string()),
+            unix_mode: None,
+            unix_owner: None,
+            unix_group: None,
+            listen_fd_name: None,
+            retry: None,
+            connection: None,
+        };
+        let result: Result<Import> = (import_config, Some(store.as_ref())).try_into();
+        assert!(matches!(result, Err(PipeError::ClientKeyNotFound(name)) if name == "carol"));
+    }
+
+    #[test]
+    fn client_key_encodings() {
+        let secret_key = crypto_box::SecretKey::from([3u8; 32]);
+        let public_key = secret_key.public_key();
+        let descriptor = client_public_key_descriptor(&public_key);
+        assert_eq!(
+            authorized_client_key(&descriptor, None).unwrap(),
+            public_key
+        );
+
+        let onion_addr = onion::TorSecretKeyV3::from([1u8; 64])
+            .public()
+            .get_onion_address();
+        let auth_private = client_auth_private(&onion_addr, &secret_key);
+        let (service_id, private_key) = auth_private.split_once(':').unwrap();
+        assert_eq!(service_id, onion_addr.get_address_without_dot_onion());
+        assert_eq!(
+            client_secret_key(private_key, None).unwrap().as_bytes(),
+            &[3u8; 32]
+        );
+    }
+
+    #[test]
+    fn try_into_import_unix() {
+        let import_config = config::Import {
+            remote_addr: "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:22"
+                .to_string(),
+            local_addr: "unix:/run/onion-ssh.sock".to_string(),
+            client_key: None,
+            unix_mode: Some("0660".to_string()),
+            unix_owner: Some("root".to_string()),
+            unix_group: Some("0".to_string()),
+            listen_fd_name: None,
+            retry: None,
+            connection: None,
+        };
+        let import: Import = (import_config, None).try_into().unwrap();
+        assert_eq!(
+            import.local_addr,
+            LocalAddr::Unix(path::PathBuf::from("/run/onion-ssh.sock"))
+        );
+        assert_eq!(
+            import.unix_socket,
+            UnixSocketOptions {
+                mode: Some(0o660),
+                owner: Some(0),
+                group: Some(0),
+            }
+        );
+
+        let import_config = config::Import {
+            remote_addr: "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:22"
+                .to_string(),
+            local_addr: "127.0.0.1:2222".to_string(),
+            client_key: None,
+            unix_mode: Some("0660".to_string()),
+            unix_owner: None,
+            unix_group: None,
+            listen_fd_name: None,
+            retry: None,
+            connection: None,
+        };
+        let result: Result<Import> = (import_config, None).try_into();
+        assert!(matches!(result, Err(PipeError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn shutdown_handle() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let onion_pipe = OnionPipe::defaults()
+            .temp_dir(tmp_dir.path().to_str().unwrap())
+            .new()
+            .await
+            .unwrap();
+        let mut shutdown_rx = onion_pipe.shutdown_rx.clone();
+        assert!(!*shutdown_rx.borrow_and_update());
+        onion_pipe.shutdown_handle().clone().shutdown();
+        shutdown_rx.changed().await.unwrap();
+        assert!(*shutdown_rx.borrow());
+    }
+
+    fn bootstrap_control_conn(progress: &'static [u8]) -> backend::TorControl {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(server);
+            let mut lines = BufReader::new(read).lines();
+            let mut i = 0;
+            while let Ok(Some(_)) = lines.next_line().await {
+                // Repeat the last progress reported once the list runs out.
+                let p = progress[i.min(progress.len() - 1)];
+                i += 1;
+                write
+                    .write_all(
+                        format!(
+                            "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS={} TAG=t SUMMARY=\"s\"\r\n250 OK\r\n",
+                            p
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+        backend::TorControl::Port(control::ControlConn::new(Box::new(client)))
+    }
+
+    #[tokio::test]
+    async fn bootstrap_progress() {
+        let (events_tx, mut events) = tokio::sync::broadcast::channel(16);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut tor = TorBackend::External {
+            control_addr: "127.0.0.1:9051".parse().unwrap(),
+            socks_addr: "127.0.0.1:9050".parse().unwrap(),
+            auth: ControlAuth::Auto,
+        }
+        .prepare(tmp_dir.path())
+        .unwrap();
+
+        let mut ac = bootstrap_control_conn(&[10, 10, 50, 100]);
+        wait_for_bootstrap(&mut ac, None, &mut tor, &events_tx)
+            .await
+            .unwrap();
+        let mut reported = vec![];
+        while let Ok(OnionPipeEvent::Bootstrap { phase }) = events.try_recv() {
+            reported.push(phase.progress);
+        }
+        assert_eq!(reported, vec![10, 50, 100]);
+
+        let mut ac = bootstrap_control_conn(&[25]);
+        let deadline = Some(tokio::time::Instant::now());
+        assert!(matches!(
+            wait_for_bootstrap(&mut ac, deadline, &mut tor, &events_tx).await,
+            Err(PipeError::BootstrapStalled(25))
+        ));
+    }
+
+    fn fake_control_conn() -> backend::TorControl {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(server);
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(_)) = lines.next_line().await {
+                write.write_all(b"250 OK\r\n").await.unwrap();
+            }
+        });
+        backend::TorControl::Port(control::ControlConn::new(Box::new(client)))
+    }
+
+    #[tokio::test]
+    async fn add_remove_forwards() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut onion_pipe = OnionPipe::defaults()
+            .temp_dir(tmp_dir.path().to_str().unwrap())
+            .tor_backend(TorBackend::External {
+                control_addr: "127.0.0.1:9051".parse().unwrap(),
+                socks_addr: "127.0.0.1:9050".parse().unwrap(),
+                auth: ControlAuth::Auto,
+            })
+            .new()
+            .await
+            .unwrap();
+        let mut ac = fake_control_conn();
+        let mut import_tasks = vec![];
+        let mut events = onion_pipe.subscribe();
+
+        let export = || Export {
+            local_addr: LocalAddr::TCP("127.0.0.1:4566".parse().unwrap()),
+            remote_key: onion::TorSecretKeyV3::from([1u8; 64]),
+            remote_ports: vec![80],
+            authorized_clients: vec![],
+            connection: ConnectionOptions::default(),
+        };
+        let (reply, rx) = tokio::sync::oneshot::channel();
+        onion_pipe
+            .handle_request(
+                &mut ac,
+                &mut import_tasks,
+                Request::AddExport(export(), reply),
+            )
+            .await;
+        let onion_addr = rx.await.unwrap().unwrap();
+        assert_eq!(onion_pipe.exports.len(), 1);
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            OnionPipeEvent::OnionPublished { onion_addr: ref addr, .. } if *addr == onion_addr
+        ));
+
+        let (reply, rx) = tokio::sync::oneshot::channel();
+        onion_pipe
+            .handle_request(
+                &mut ac,
+                &mut import_tasks,
+                Request::AddExport(export(), reply),
+            )
+            .await;
+        assert!(matches!(rx.await.unwrap(), Err(PipeError::Config(_))));
+        assert!(matches!(
+            onion_pipe.forward_statuses(&import_tasks)[0],
+            ForwardStatus::Export {
+                publish_state: PublishState::Pending,
+                ..
+            }
+        ));
+        // Removing the export ends a wait for it to be published.
+        let forward_handle = onion_pipe.forward_handle();
+        let wait_addr = onion_addr;
+        let wait_published =
+            tokio::spawn(async move { forward_handle.wait_published(&wait_addr).await });
+
+        for expect_found in [true, false] {
+            let (reply, rx) = tokio::sync::oneshot::channel();
+            onion_pipe
+                .handle_request(
+                    &mut ac,
+                    &mut import_tasks,
+                    Request::RemoveExport(onion_addr, reply),
+                )
+                .await;
+            let result = rx.await.unwrap();
+            assert_eq!(expect_found, result.is_ok());
+        }
+        assert!(onion_pipe.exports.is_empty());
+        assert!(matches!(
+            wait_published.await.unwrap(),
+            Err(PipeError::ForwardNotFound(_))
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            OnionPipeEvent::OnionRemoved { .. }
+        ));
+
+        let socket_path = tmp_dir.path().join("import.sock");
+        let local_addr = LocalAddr::Unix(socket_path.clone());
+        let import = Import {
+            remote_addr: onion::OnionAddress::V3(onion_addr),
+            remote_port: 80,
+            local_addr: local_addr.clone(),
+            client_key: None,
+            unix_socket: UnixSocketOptions::default(),
+            listen_fd_name: None,
+            retry: RetryPolicy::default(),
+            connection: ConnectionOptions::default(),
+        };
+        let (reply, rx) = tokio::sync::oneshot::channel();
+        onion_pipe
+            .handle_request(
+                &mut ac,
+                &mut import_tasks,
+                Request::AddImport(import, reply),
+            )
+            .await;
+        rx.await.unwrap().unwrap();
+        assert!(socket_path.exists());
+        assert_eq!(import_tasks.len(), 1);
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            OnionPipeEvent::ImportBound {
+                remote_port: 80,
+                ..
+            }
+        ));
+
+        let (reply, rx) = tokio::sync::oneshot::channel();
+        onion_pipe
+            .handle_request(&mut ac, &mut import_tasks, Request::ListForwards(reply))
+            .await;
+        assert_eq!(
+            rx.await.unwrap().unwrap(),
+            vec![ForwardStatus::Import {
+                onion_addr: onion_addr.to_string(),
+                remote_port: 80,
+                local_addr: local_addr.to_string(),
+                bound: true,
+            }]
+        );
+
+        let (reply, rx) = tokio::sync::oneshot::channel();
+        onion_pipe
+            .handle_request(
+                &mut ac,
+                &mut import_tasks,
+                Request::RemoveImport(local_addr, reply),
+            )
+            .await;
+        rx.await.unwrap().unwrap();
+        assert!(!socket_path.exists());
+        assert!(import_tasks.is_empty());
+        assert!(onion_pipe.imports.is_empty());
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            OnionPipeEvent::ImportRemoved { .. }
+        ));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn import_connection_events() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let socks_path = tmp_dir.path().join("socks.sock");
+        let socks_listener = tokio::net::UnixListener::bind(&socks_path).unwrap();
+        // Fake SOCKS server: accepts one connection and echoes, then refuses
+        // the next with a client authorization error.
+        tokio::spawn(async move {
+            for reply in [0x00u8, 0xf4] {
+                let (mut stream, _) = socks_listener.accept().await.unwrap();
+                let mut greeting = [0u8; 3];
+                stream.read_exact(&mut greeting).await.unwrap();
+                stream.write_all(&[0x05, 0x00]).await.unwrap();
+                let mut header = [0u8; 5];
+                stream.read_exact(&mut header).await.unwrap();
+                let mut rest = vec![0u8; header[4] as usize + 2];
+                stream.read_exact(&mut rest).await.unwrap();
+                stream
+                    .write_all(&[0x05, reply, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                if reply == 0 {
+                    let mut buf = [0u8; 5];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    stream.write_all(&buf).await.unwrap();
+                }
+            }
+        });
+
+        let (events_tx, mut events) = tokio::sync::broadcast::channel(16);
+        let proxy = ImportProxy {
+            connector: backend::Connector::Socks(LocalAddr::Unix(socks_path.clone())),
+            remote_addr: onion::OnionAddress::V3(
+                onion::TorSecretKeyV3::from([1u8; 64])
+                    .public()
+                    .get_onion_address(),
+            ),
+            remote_port: 80,
+            local_addr: LocalAddr::TCP("127.0.0.1:8080".parse().unwrap()),
+            retry: RetryPolicy::default(),
+            connection: ConnectionOptions::default(),
+            metrics: None,
+            events: events_tx,
+        };
+
+        let mut connections = tokio::task::JoinSet::new();
+        let (mut local, proxied) = tokio::io::duplex(1024);
+        proxy.proxy(proxied, &mut connections);
+        local.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(local);
+        let id = match events.recv().await.unwrap() {
+            OnionPipeEvent::ConnectionOpened { id, .. } => id,
+            event => panic!("unexpected event {:?}", event),
+        };
+        match events.recv().await.unwrap() {
+            OnionPipeEvent::ConnectionClosed {
+                id: closed_id,
+                bytes_sent,
+                bytes_received,
+            } => {
+                assert_eq!(closed_id, id);
+                assert_eq!((bytes_sent, bytes_received), (5, 5));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        let (_local, proxied) = tokio::io::duplex(1024);
+        proxy.proxy(proxied, &mut connections);
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            OnionPipeEvent::ConnectionOpened { .. }
+        ));
+        let event = events.recv().await.unwrap();
+        assert!(event.is_error());
+        assert!(event
+            .to_string()
+            .contains("remote onion client authorization failed"));
+    }
+
     #[test]
     fn try_into_export_unix() {
         let export_config = config::Export {
             local_addr: "unix:/tmp/foo.sock".to_string(),
             service_name: None,
             remote_ports: vec![4567],
+            authorized_clients: None,
+            connection: None,
         };
         let tmp_dir = tempfile::tempdir().unwrap();
         let secrets_dir = tmp_dir.path().join("secrets");
-        let mut store = secrets::SecretStore::new(secrets_dir.to_str().unwrap());
+        let mut store: Box<dyn secrets::SecretStore> =
+            Box::new(secrets::FileSecretStore::new(secrets_dir.to_str().unwrap()));
+
+        let export: Export = (export_config, Some(store.as_mut())).try_into().unwrap();
+        assert_eq!(
+            LocalAddr::Unix(path::PathBuf::from("/tmp/foo.sock")),
+            export.local_addr
+        );
+        assert_eq!("unix:/tmp/foo.sock", export.local_addr.to_string());
+
+        let export_config = config::Export {
+            local_addr: "unix:foo.sock".to_string(),
+            service_name: None,
+            remote_ports: vec![4567],
+            authorized_clients: None,
+            connection: None,
+        };
+        let result: Result<Export> = (export_config, Some(store.as_mut())).try_into();
+        assert!(matches!(result, Err(PipeError::Config(_))));
+
+        let export_config = config::Export {
+            local_addr: "unix:".to_string(),
+            service_name: None,
+            remote_ports: vec![4567],
+            authorized_clients: None,
+            connection: None,
+        };
+        let result: Result<Export> = (export_config, Some(store.as_mut())).try_into();
+        assert!(matches!(result, Err(PipeError::Config(_))));
+    }
 
-        let result: Result<Export> = (export_config, Some(&mut store)).try_into();
-        // TODO: Improve torut to support local unix sockets.
-        assert!(matches!(result, Err(PipeError::ParseAddr(_))));
+    #[test]
+    fn config_secret_store() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut cfg: config::Config = Vec::<String>::new().try_into().unwrap();
+        cfg.secrets_dir = Some(tmp_dir.path().to_str().unwrap().to_string());
+        cfg.secrets_helper = Some(vec!["onion-keys".to_string()]);
+        assert!(matches!(
+            OnionPipe::defaults().config(cfg),
+            Err(PipeError::Config(_))
+        ));
     }
 }
@@ -0,0 +1,450 @@
+use std::fmt::Write as _;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::{http, Export, ForwardHandle, Import, LocalAddr, TorTraffic};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Prometheus metrics, served in the text exposition format. Per-forward counters are updated as connections are
+// forwarded; Tor's own figures are fetched when scraped.
+
+/// Upper bounds of the import connect latency buckets, in seconds. Onion
+/// connections typically take several seconds to build their circuits.
+const CONNECT_BUCKETS: [f64; 10] = [0.5, 1.0, 2.5, 5.0, 10.0, 15.0, 20.0, 30.0, 60.0, 120.0];
+
+/// How long a scrape waits on the control connection for each of Tor's
+/// figures.
+const TOR_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; CONNECT_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        for (bound, bucket) in CONNECT_BUCKETS.iter().zip(self.buckets.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Connection counters for one forward.
+#[derive(Default)]
+pub(crate) struct ForwardMetrics {
+    accepted: AtomicU64,
+    failed: AtomicU64,
+    active: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    connect_seconds: Histogram,
+}
+
+impl ForwardMetrics {
+    /// Count a newly accepted connection, which is active until the returned
+    /// guard is dropped.
+    pub(crate) fn open(self: &Arc<Self>) -> ActiveConnection {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self.clone())
+    }
+
+    pub(crate) fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long an import took to connect to its onion.
+    pub(crate) fn connected(&self, latency: Duration) {
+        self.connect_seconds.observe(latency);
+    }
+}
+
+pub(crate) struct ActiveConnection(Arc<ForwardMetrics>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The local side of a forwarded connection, counting the bytes read from it
+/// as sent and the bytes written to it as received.
+pub(crate) struct Metered<S> {
+    inner: S,
+    metrics: Option<Arc<ForwardMetrics>>,
+}
+
+impl<S> Metered<S> {
+    pub(crate) fn new(inner: S, metrics: Option<Arc<ForwardMetrics>>) -> Metered<S> {
+        Metered { inner, metrics }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(metrics)) = (&result, &self.metrics) {
+            metrics
+                .bytes_sent
+                .fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(metrics)) = (&result, &self.metrics) {
+            metrics
+                .bytes_received
+                .fetch_add(*n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads one of a forward's counters.
+type Counter = fn(&ForwardMetrics) -> &AtomicU64;
+
+struct Forward {
+    kind: &'static str,
+    onion_addr: String,
+    local_addr: String,
+    metrics: Arc<ForwardMetrics>,
+}
+
+/// Metrics for a running pipe.
+#[derive(Clone, Default)]
+pub(crate) struct Metrics {
+    forwards: Arc<Mutex<Vec<Forward>>>,
+}
+
+impl Metrics {
+    /// Counters for an export, labelled with its onion address.
+    pub(crate) fn export(&self, export: &Export) -> Arc<ForwardMetrics> {
+        self.forward(
+            "export",
+            export.remote_key.public().get_onion_address().to_string(),
+            export.local_addr.to_string(),
+        )
+    }
+
+    /// Counters for an import, labelled with its onion address and port.
+    pub(crate) fn import(&self, import: &Import) -> Arc<ForwardMetrics> {
+        self.forward(
+            "import",
+            format!("{}:{}", import.remote_addr, import.remote_port),
+            import.local_addr.to_string(),
+        )
+    }
+
+    fn forward(
+        &self,
+        kind: &'static str,
+        onion_addr: String,
+        local_addr: String,
+    ) -> Arc<ForwardMetrics> {
+        let mut forwards = self.forwards.lock().unwrap();
+        forwards.retain(|forward| {
+            !(forward.kind == kind
+                && forward.onion_addr == onion_addr
+                && forward.local_addr == local_addr)
+        });
+        let metrics = Arc::new(ForwardMetrics::default());
+        forwards.push(Forward {
+            kind,
+            onion_addr,
+            local_addr,
+            metrics: metrics.clone(),
+        });
+        metrics
+    }
+
+    /// Stop reporting a removed export.
+    pub(crate) fn remove_export(&self, onion_addr: &torut::onion::OnionAddressV3) {
+        let onion_addr = onion_addr.to_string();
+        self.forwards
+            .lock()
+            .unwrap()
+            .retain(|forward| !(forward.kind == "export" && forward.onion_addr == onion_addr));
+    }
+
+    /// Stop reporting a removed import.
+    pub(crate) fn remove_import(&self, local_addr: &LocalAddr) {
+        let local_addr = local_addr.to_string();
+        self.forwards
+            .lock()
+            .unwrap()
+            .retain(|forward| !(forward.kind == "import" && forward.local_addr == local_addr));
+    }
+
+    fn render(&self, bootstrap_progress: Option<u8>, traffic: Option<TorTraffic>) -> String {
+        let mut out = String::new();
+        let forwards = self.forwards.lock().unwrap();
+        let counters: [(&str, &str, &str, Counter); 5] = [
+            (
+                "onionpipe_connections_accepted_total",
+                "counter",
+                "Connections accepted by a forward.",
+                |m| &m.accepted,
+            ),
+            (
+                "onionpipe_connections_failed_total",
+                "counter",
+                "Connections that failed before or while forwarding.",
+                |m| &m.failed,
+            ),
+            (
+                "onionpipe_connections_active",
+                "gauge",
+                "Connections currently being forwarded.",
+                |m| &m.active,
+            ),
+            (
+                "onionpipe_bytes_sent_total",
+                "counter",
+                "Bytes forwarded from the local side to the onion side.",
+                |m| &m.bytes_sent,
+            ),
+            (
+                "onionpipe_bytes_received_total",
+                "counter",
+                "Bytes forwarded from the onion side to the local side.",
+                |m| &m.bytes_received,
+            ),
+        ];
+        for (name, kind, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for forward in forwards.iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{{}}} {}",
+                    name,
+                    forward.labels(),
+                    value(&forward.metrics).load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        let name = "onionpipe_import_connect_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} Time taken by imports to connect to their onion.\n# TYPE {} histogram",
+            name, name
+        );
+        for forward in forwards.iter().filter(|forward| forward.kind == "import") {
+            let labels = forward.labels();
+            let histogram = &forward.metrics.connect_seconds;
+            let count = histogram.count.load(Ordering::Relaxed);
+            for (bound, bucket) in CONNECT_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name,
+                    labels,
+                    bound,
+                    bucket.load(Ordering::Relaxed)
+                );
+            }
+            let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+            let _ = writeln!(
+                out,
+                "{}_sum{{{}}} {}",
+                name,
+                labels,
+                histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+            );
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+        }
+
+        if let Some(bootstrap_progress) = bootstrap_progress {
+            let _ = writeln!(
+                out,
+                "# HELP onionpipe_tor_bootstrap_percent Tor's bootstrap progress.\n\
+                 # TYPE onionpipe_tor_bootstrap_percent gauge\n\
+                 onionpipe_tor_bootstrap_percent {}",
+                bootstrap_progress
+            );
+        }
+        if let Some(traffic) = traffic {
+            let _ = writeln!(
+                out,
+                "# HELP onionpipe_tor_read_bytes_total Bytes read by Tor.\n\
+                 # TYPE onionpipe_tor_read_bytes_total counter\n\
+                 onionpipe_tor_read_bytes_total {}\n\
+                 # HELP onionpipe_tor_written_bytes_total Bytes written by Tor.\n\
+                 # TYPE onionpipe_tor_written_bytes_total counter\n\
+                 onionpipe_tor_written_bytes_total {}",
+                traffic.read, traffic.written
+            );
+        }
+        out
+    }
+}
+
+impl Forward {
+    fn labels(&self) -> String {
+        format!(
+            "forward=\"{}\",onion_addr=\"{}\",local_addr=\"{}\"",
+            self.kind,
+            escape_label(&self.onion_addr),
+            escape_label(&self.local_addr)
+        )
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve metrics to scrapers on `listener`, asking the pipe behind
+/// `forwards` for Tor's figures.
+pub(crate) async fn serve(
+    listener: tokio::net::TcpListener,
+    metrics: Metrics,
+    forwards: ForwardHandle,
+) {
+    let handler = move |path: String| {
+        let (metrics, forwards) = (metrics.clone(), forwards.clone());
+        async move {
+            if path != "/metrics" {
+                return None;
+            }
+            let bootstrap_progress =
+                tokio::time::timeout(TOR_QUERY_TIMEOUT, forwards.bootstrap_phase())
+                    .await
+                    .ok()
+                    .and_then(|result| result.ok())
+                    .map(|phase| phase.progress);
+            let traffic = tokio::time::timeout(TOR_QUERY_TIMEOUT, forwards.tor_traffic())
+                .await
+                .ok()
+                .and_then(|result| result.ok())
+                .flatten();
+            Some(http::Response::new(
+                200,
+                "text/plain; version=0.0.4",
+                metrics.render(bootstrap_progress, traffic),
+            ))
+        }
+    };
+    http::serve(listener, "metrics", handler).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{ConnectionOptions, RetryPolicy, UnixSocketOptions};
+
+    fn import() -> Import {
+        Import {
+            remote_addr: torut::onion::OnionAddress::V3(
+                torut::onion::TorSecretKeyV3::from([1u8; 64])
+                    .public()
+                    .get_onion_address(),
+            ),
+            remote_port: 80,
+            local_addr: LocalAddr::Unix("/run/\"quoted\".sock".into()),
+            client_key: None,
+            unix_socket: UnixSocketOptions::default(),
+            listen_fd_name: None,
+            retry: RetryPolicy::default(),
+            connection: ConnectionOptions::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn metered_counts() {
+        let metrics = Metrics::default();
+        let forward = metrics.import(&import());
+        let (client, local) = tokio::io::duplex(1024);
+        let mut local = Metered::new(local, Some(forward.clone()));
+        let mut client = client;
+        let conn = forward.open();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        local.read_exact(&mut buf).await.unwrap();
+        local.write_all(b"hi").await.unwrap();
+        forward.connected(Duration::from_millis(1500));
+        assert_eq!(forward.active.load(Ordering::Relaxed), 1);
+        drop(conn);
+        forward.failed();
+
+        let text = metrics.render(
+            Some(42),
+            Some(TorTraffic {
+                read: 1024,
+                written: 2048,
+            }),
+        );
+        let labels = format!(
+            "forward=\"import\",onion_addr=\"{}:80\",local_addr=\"unix:/run/\\\"quoted\\\".sock\"",
+            import().remote_addr
+        );
+        for line in [
+            format!("onionpipe_connections_accepted_total{{{}}} 1", labels),
+            format!("onionpipe_connections_failed_total{{{}}} 1", labels),
+            format!("onionpipe_connections_active{{{}}} 0", labels),
+            format!("onionpipe_bytes_sent_total{{{}}} 5", labels),
+            format!("onionpipe_bytes_received_total{{{}}} 2", labels),
+            format!(
+                "onionpipe_import_connect_seconds_bucket{{{},le=\"1\"}} 0",
+                labels
+            ),
+            format!(
+                "onionpipe_import_connect_seconds_bucket{{{},le=\"2.5\"}} 1",
+                labels
+            ),
+            format!("onionpipe_import_connect_seconds_sum{{{}}} 1.5", labels),
+            "onionpipe_tor_bootstrap_percent 42".to_string(),
+            "onionpipe_tor_read_bytes_total 1024".to_string(),
+            "onionpipe_tor_written_bytes_total 2048".to_string(),
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} not in:\n{}",
+                line,
+                text
+            );
+        }
+
+        metrics.remove_import(&import().local_addr);
+        let text = metrics.render(None, None);
+        assert!(!text.contains("onion_addr="));
+        assert!(!text.contains("onionpipe_tor_"));
+    }
+}
@@ -4,13 +4,13 @@ use std::str::FromStr;
 
 use nom::{
     branch::alt,
-    bytes::complete::tag,
+    bytes::complete::{is_not, tag, take_while1},
     character::complete::alphanumeric1,
-    combinator::{eof, opt},
+    combinator::{eof, opt, recognize},
     error::context,
     multi::separated_list1,
     sequence::{preceded, terminated, tuple},
-    Finish,
+    Err as NomErr, Finish,
 };
 
 use crate::config;
@@ -49,6 +49,7 @@ impl fmt::Display for Host {
 #[derive(Debug, PartialEq, Eq)]
 pub enum ExportLocalAddr {
     TCP(ExportLocalTCPAddr),
+    Unix(String),
 }
 
 impl fmt::Display for ExportLocalAddr {
@@ -60,6 +61,7 @@ impl fmt::Display for ExportLocalAddr {
                 addr.host.as_ref().unwrap_or(&Host::IP4([127, 0, 0, 1])),
                 addr.port
             ),
+            ExportLocalAddr::Unix(path) => write!(f, "unix:{}", path),
         }
     }
 }
@@ -74,6 +76,7 @@ pub struct ExportRemoteAddr {
 pub struct ExportForward {
     local: ExportLocalAddr,
     remote: Option<ExportRemoteAddr>,
+    authorized_clients: Option<Vec<String>>,
 }
 
 impl From<ExportForward> for config::Export {
@@ -89,6 +92,8 @@ impl From<ExportForward> for config::Export {
                 Some(remote) => remote.ports,
                 None => vec![80u16],
             },
+            authorized_clients: export.authorized_clients,
+            connection: None,
         }
     }
 }
@@ -101,21 +106,14 @@ pub struct ImportRemoteAddr {
 
 impl fmt::Display for ImportRemoteAddr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}:{}",
-            self.onion,
-            match self.port {
-                Some(port) => port,
-                None => 80u16,
-            }
-        )
+        write!(f, "{}:{}", self.onion, self.port.unwrap_or(80u16))
     }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ImportLocalAddr {
     TCP(ImportLocalTCPAddr),
+    Unix(String),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -138,6 +136,7 @@ impl fmt::Display for ImportLocalAddr {
                 },
                 tcp_addr.port.unwrap_or(80u16),
             ),
+            ImportLocalAddr::Unix(path) => write!(f, "unix:{}", path),
         }
     }
 }
@@ -146,24 +145,27 @@ impl fmt::Display for ImportLocalAddr {
 pub struct ImportForward {
     remote: ImportRemoteAddr,
     local: Option<ImportLocalAddr>,
+    client_key: Option<String>,
 }
 
 impl From<ImportForward> for config::Import {
     fn from(import: ImportForward) -> Self {
         config::Import {
             remote_addr: format!("{}", import.remote),
-            local_addr: format!(
-                "{}",
-                format!(
-                    "{}",
-                    import
-                        .local
-                        .unwrap_or(ImportLocalAddr::TCP(ImportLocalTCPAddr {
-                            host: Some(Host::IP4([127, 0, 0, 1])),
-                            port: Some(8080u16),
-                        }))
-                )
-            ),
+            local_addr: import
+                .local
+                .unwrap_or(ImportLocalAddr::TCP(ImportLocalTCPAddr {
+                    host: Some(Host::IP4([127, 0, 0, 1])),
+                    port: Some(8080u16),
+                }))
+                .to_string(),
+            client_key: import.client_key,
+            unix_mode: None,
+            unix_owner: None,
+            unix_group: None,
+            listen_fd_name: None,
+            retry: None,
+            connection: None,
         }
     }
 }
@@ -205,12 +207,17 @@ fn forward(input: &str) -> Res<&str, Forward> {
 fn export_forward(input: &str) -> Res<&str, Forward> {
     context(
         "export forward",
-        tuple((local_tcp_addr, opt(preceded(tag("~"), export_remote_addr)))),
+        tuple((
+            export_local_addr,
+            opt(preceded(tag("~"), export_remote_addr)),
+            opt(preceded(tag("@"), authorized_clients)),
+        )),
     )(input)
     .map(|(next_input, res)| {
         let result: Forward = Forward::Export(ExportForward {
-            local: ExportLocalAddr::TCP(res.0),
+            local: res.0,
             remote: res.1,
+            authorized_clients: res.2,
         });
         (next_input, result)
     })
@@ -230,6 +237,48 @@ fn export_remote_addr(input: &str) -> Res<&str, ExportRemoteAddr> {
     })
 }
 
+fn authorized_clients(input: &str) -> Res<&str, Vec<String>> {
+    context("authorized clients", separated_list1(tag(","), client))(input)
+}
+
+fn client(input: &str) -> Res<&str, String> {
+    context(
+        "client",
+        alt((
+            recognize(preceded(tag("descriptor:x25519:"), alphanumeric1)),
+            take_while1(is_client_name_char),
+        )),
+    )(input)
+    .map(|(next_input, res)| (next_input, res.to_string()))
+}
+
+/// Whether `name` can name a client in the secret store, so that forwards
+/// can refer to it.
+pub fn is_client_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_client_name_char)
+}
+
+fn is_client_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+fn export_local_addr(input: &str) -> Res<&str, ExportLocalAddr> {
+    // Only fall back to TCP when the input isn't a unix address, so that TCP
+    // parse errors are reported as they always have been.
+    match local_unix_addr(input) {
+        Ok((next_input, res)) => Ok((next_input, ExportLocalAddr::Unix(res))),
+        Err(NomErr::Error(_)) => {
+            local_tcp_addr(input).map(|(next_input, res)| (next_input, ExportLocalAddr::TCP(res)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn local_unix_addr(input: &str) -> Res<&str, String> {
+    context("local unix addr", preceded(tag("unix:"), is_not("~@")))(input)
+        .map(|(next_input, res)| (next_input, res.to_string()))
+}
+
 fn local_tcp_addr(input: &str) -> Res<&str, ExportLocalTCPAddr> {
     context(
         "local tcp addr",
@@ -250,12 +299,14 @@ fn import_forward(input: &str) -> Res<&str, Forward> {
         tuple((
             import_remote_addr,
             opt(preceded(tag("~"), import_local_addr)),
+            opt(preceded(tag("@"), client)),
         )),
     )(input)
     .map(|(next_input, res)| {
         let result: Forward = Forward::Import(ImportForward {
             remote: res.0,
             local: res.1,
+            client_key: res.2,
         });
         (next_input, result)
     })
@@ -284,15 +335,19 @@ fn import_local_addr(input: &str) -> Res<&str, ImportLocalAddr> {
     context(
         "import local addr",
         alt((
-            import_local_addr_host_port,
-            import_local_addr_host_only,
-            import_local_addr_port_only,
+            |i| {
+                local_unix_addr(i).map(|(next_input, res)| (next_input, ImportLocalAddr::Unix(res)))
+            },
+            |i| {
+                alt((
+                    import_local_addr_host_port,
+                    import_local_addr_host_only,
+                    import_local_addr_port_only,
+                ))(i)
+                .map(|(next_input, res)| (next_input, ImportLocalAddr::TCP(res)))
+            },
         )),
     )(input)
-    .map(|(next_input, res)| {
-        let result: ImportLocalAddr = ImportLocalAddr::TCP(res);
-        (next_input, result)
-    })
 }
 
 fn import_local_addr_host_port(input: &str) -> Res<&str, ImportLocalTCPAddr> {
@@ -385,6 +440,7 @@ mod tests {
                         port: 80,
                     }),
                     remote: None,
+                    authorized_clients: None,
                 })
             ))
         );
@@ -398,6 +454,7 @@ mod tests {
                         port: 80,
                     }),
                     remote: None,
+                    authorized_clients: None,
                 })
             ))
         );
@@ -414,6 +471,7 @@ mod tests {
                         onion_alias: None,
                         ports: vec![80],
                     }),
+                    authorized_clients: None,
                 })
             ))
         );
@@ -430,6 +488,7 @@ mod tests {
                         onion_alias: None,
                         ports: vec![80, 81, 8080, 28000],
                     }),
+                    authorized_clients: None,
                 })
             ))
         );
@@ -446,6 +505,7 @@ mod tests {
                         onion_alias: Some("mastodon".to_string()),
                         ports: vec![80, 81, 8080, 28000],
                     }),
+                    authorized_clients: None,
                 })
             ))
         );
@@ -462,6 +522,102 @@ mod tests {
                         onion_alias: Some("mastodon".to_string()),
                         ports: vec![80, 81, 8080, 28000],
                     }),
+                    authorized_clients: None,
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_export_forward_unix() {
+        assert_eq!(
+            forward("unix:/run/php/php-fpm.sock~80"),
+            Ok((
+                "",
+                Forward::Export(ExportForward {
+                    local: ExportLocalAddr::Unix("/run/php/php-fpm.sock".to_string()),
+                    remote: Some(ExportRemoteAddr {
+                        onion_alias: None,
+                        ports: vec![80],
+                    }),
+                    authorized_clients: None,
+                })
+            ))
+        );
+        let export: config::Export = match "unix:/tmp/app.sock~myapp:80@alice"
+            .parse::<Forward>()
+            .unwrap()
+        {
+            Forward::Export(export) => export.into(),
+            _ => panic!("expected export"),
+        };
+        assert_eq!(
+            export,
+            config::Export {
+                local_addr: "unix:/tmp/app.sock".to_string(),
+                service_name: Some("myapp".to_string()),
+                remote_ports: vec![80],
+                authorized_clients: Some(vec!["alice".to_string()]),
+                connection: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_export_forward_authorized_clients() {
+        assert_eq!(
+            forward("80@alice"),
+            Ok((
+                "",
+                Forward::Export(ExportForward {
+                    local: ExportLocalAddr::TCP(ExportLocalTCPAddr {
+                        host: None,
+                        port: 80,
+                    }),
+                    remote: None,
+                    authorized_clients: Some(vec!["alice".to_string()]),
+                })
+            ))
+        );
+        assert_eq!(
+            forward("80@alice-laptop,bob_2"),
+            Ok((
+                "",
+                Forward::Export(ExportForward {
+                    local: ExportLocalAddr::TCP(ExportLocalTCPAddr {
+                        host: None,
+                        port: 80,
+                    }),
+                    remote: None,
+                    authorized_clients: Some(vec!["alice-laptop".to_string(), "bob_2".to_string()]),
+                })
+            ))
+        );
+        assert!(is_client_name("alice-laptop"));
+        assert!(!is_client_name(""));
+        assert!(!is_client_name("alice laptop"));
+        assert!(!is_client_name("../alice"));
+        assert!(!is_client_name(
+            "descriptor:x25519:N2NU7BSRL6YODZCYPN4CREB54TYLKGIE2KYOQWLFYC23ZJVCE5DQ"
+        ));
+        assert_eq!(
+            forward("8000~mastodon:80@alice,descriptor:x25519:N2NU7BSRL6YODZCYPN4CREB54TYLKGIE2KYOQWLFYC23ZJVCE5DQ"),
+            Ok((
+                "",
+                Forward::Export(ExportForward {
+                    local: ExportLocalAddr::TCP(ExportLocalTCPAddr {
+                        host: None,
+                        port: 8000,
+                    }),
+                    remote: Some(ExportRemoteAddr {
+                        onion_alias: Some("mastodon".to_string()),
+                        ports: vec![80],
+                    }),
+                    authorized_clients: Some(vec![
+                        "alice".to_string(),
+                        "descriptor:x25519:N2NU7BSRL6YODZCYPN4CREB54TYLKGIE2KYOQWLFYC23ZJVCE5DQ"
+                            .to_string()
+                    ]),
                 })
             ))
         );
@@ -516,6 +672,7 @@ mod tests {
                     port: None,
                 },
                 local: None,
+                client_key: None,
             }))
         );
         assert_eq!(
@@ -526,6 +683,7 @@ mod tests {
                     port: Some(9001),
                 },
                 local: None,
+                client_key: None,
             }))
         );
         assert_eq!(
@@ -539,6 +697,7 @@ mod tests {
                     host: None,
                     port: Some(9002),
                 })),
+                client_key: None,
             }))
         );
         assert_eq!(
@@ -552,6 +711,64 @@ mod tests {
                     host: Some(Host::IP4([172, 18, 0, 1])),
                     port: None,
                 })),
+                client_key: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_import_forward_unix() {
+        assert_eq!(
+            "xyz123.onion:22~unix:/run/onion-ssh.sock".parse::<Forward>(),
+            Ok(Forward::Import(ImportForward {
+                remote: ImportRemoteAddr {
+                    onion: "xyz123".to_string(),
+                    port: Some(22),
+                },
+                local: Some(ImportLocalAddr::Unix("/run/onion-ssh.sock".to_string())),
+                client_key: None,
+            }))
+        );
+        let import: config::Import = match "xyz123.onion:22~unix:/run/onion-ssh.sock@bob"
+            .parse::<Forward>()
+            .unwrap()
+        {
+            Forward::Import(import) => import.into(),
+            _ => panic!("expected import"),
+        };
+        assert_eq!(import.local_addr, "unix:/run/onion-ssh.sock");
+        assert_eq!(import.client_key, Some("bob".to_string()));
+    }
+
+    #[test]
+    fn test_import_forward_client_key() {
+        assert_eq!(
+            "xyz123.onion:9001~9002@bob".parse::<Forward>(),
+            Ok(Forward::Import(ImportForward {
+                remote: ImportRemoteAddr {
+                    onion: "xyz123".to_string(),
+                    port: Some(9001),
+                },
+                local: Some(ImportLocalAddr::TCP(ImportLocalTCPAddr {
+                    host: None,
+                    port: Some(9002),
+                })),
+                client_key: Some("bob".to_string()),
+            }))
+        );
+        assert_eq!(
+            "xyz123.onion@descriptor:x25519:N2NU7BSRL6YODZCYPN4CREB54TYLKGIE2KYOQWLFYC23ZJVCE5DQ"
+                .parse::<Forward>(),
+            Ok(Forward::Import(ImportForward {
+                remote: ImportRemoteAddr {
+                    onion: "xyz123".to_string(),
+                    port: None,
+                },
+                local: None,
+                client_key: Some(
+                    "descriptor:x25519:N2NU7BSRL6YODZCYPN4CREB54TYLKGIE2KYOQWLFYC23ZJVCE5DQ"
+                        .to_string()
+                ),
             }))
         );
     }
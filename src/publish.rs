@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, watch};
+use torut::onion;
+
+use crate::control::ControlConn;
+use crate::OnionPipeEvent;
+
+// Tor reports the progress of onion service descriptor uploads in HS_DESC
+// events. An export is only reachable once its descriptor has been uploaded
+// to at least one HSDir.
+
+/// Whether an export's descriptor has been published.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PublishState {
+    /// The descriptor has not been uploaded yet.
+    Pending,
+    /// The descriptor has been uploaded to this many HSDirs.
+    Uploaded { hs_dirs: usize },
+    /// Every upload attempted so far has failed.
+    Failed { reason: String },
+}
+
+impl std::fmt::Display for PublishState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PublishState::Pending => write!(f, "pending"),
+            PublishState::Uploaded { hs_dirs } => write!(f, "uploaded to {} HSDirs", hs_dirs),
+            PublishState::Failed { reason } => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct HsDescEvent<'a> {
+    action: &'a str,
+    service_id: &'a str,
+    reason: Option<&'a str>,
+}
+
+fn parse_hs_desc(line: &str) -> Option<HsDescEvent<'_>> {
+    let mut fields = line.split(' ');
+    if fields.next()? != "HS_DESC" {
+        return None;
+    }
+    let action = fields.next()?;
+    let service_id = fields.next()?;
+    let reason = fields.find_map(|field| field.strip_prefix("REASON="));
+    Some(HsDescEvent {
+        action,
+        service_id,
+        reason,
+    })
+}
+
+struct Tracker {
+    attempted: usize,
+    uploaded: usize,
+    failed: usize,
+    tx: watch::Sender<PublishState>,
+}
+
+impl Tracker {
+    fn update(&mut self, event: &HsDescEvent) -> Option<PublishState> {
+        match event.action {
+            "UPLOAD" => self.attempted += 1,
+            "UPLOADED" => self.uploaded += 1,
+            "FAILED" => self.failed += 1,
+            _ => return None,
+        }
+        let state = if self.uploaded > 0 {
+            PublishState::Uploaded {
+                hs_dirs: self.uploaded,
+            }
+        } else if self.failed > 0 && self.failed >= self.attempted {
+            PublishState::Failed {
+                reason: event.reason.unwrap_or("UNKNOWN").to_string(),
+            }
+        } else {
+            PublishState::Pending
+        };
+        self.set(state)
+    }
+
+    /// Set the state, returning it if it differs in kind from the last.
+    fn set(&mut self, state: PublishState) -> Option<PublishState> {
+        let changed = std::mem::discriminant(&*self.tx.borrow()) != std::mem::discriminant(&state);
+        self.tx.send_replace(state.clone());
+        if changed {
+            Some(state)
+        } else {
+            None
+        }
+    }
+}
+
+/// Publish state of each export, by service ID.
+#[derive(Clone, Default)]
+pub struct PublishStates {
+    trackers: Arc<Mutex<HashMap<String, Tracker>>>,
+}
+
+impl PublishStates {
+    /// Start tracking an export, before it is published.
+    pub fn register(&self, onion_addr: &onion::OnionAddressV3) {
+        let (tx, _) = watch::channel(PublishState::Pending);
+        self.trackers.lock().unwrap().insert(
+            onion_addr.get_address_without_dot_onion(),
+            Tracker {
+                attempted: 0,
+                uploaded: 0,
+                failed: 0,
+                tx,
+            },
+        );
+    }
+
+    pub fn remove(&self, onion_addr: &onion::OnionAddressV3) {
+        self.trackers
+            .lock()
+            .unwrap()
+            .remove(&onion_addr.get_address_without_dot_onion());
+    }
+
+    /// Stop tracking all exports, which ends any waits on them.
+    pub fn clear(&self) {
+        self.trackers.lock().unwrap().clear();
+    }
+
+    pub fn get(&self, onion_addr: &onion::OnionAddressV3) -> Option<PublishState> {
+        self.trackers
+            .lock()
+            .unwrap()
+            .get(&onion_addr.get_address_without_dot_onion())
+            .map(|tracker| tracker.tx.borrow().clone())
+    }
+
+    pub fn subscribe(
+        &self,
+        onion_addr: &onion::OnionAddressV3,
+    ) -> Option<watch::Receiver<PublishState>> {
+        self.trackers
+            .lock()
+            .unwrap()
+            .get(&onion_addr.get_address_without_dot_onion())
+            .map(|tracker| tracker.tx.subscribe())
+    }
+
+    /// Set the state of an export, for backends that report it directly.
+    /// Returns the state if it changed in kind.
+    #[cfg(any(feature = "arti", test))]
+    pub fn set(
+        &self,
+        onion_addr: &onion::OnionAddressV3,
+        state: PublishState,
+    ) -> Option<PublishState> {
+        self.trackers
+            .lock()
+            .unwrap()
+            .get_mut(&onion_addr.get_address_without_dot_onion())?
+            .set(state)
+    }
+
+    fn update(&self, event: &HsDescEvent) -> Option<PublishState> {
+        self.trackers
+            .lock()
+            .unwrap()
+            .get_mut(event.service_id)?
+            .update(event)
+    }
+}
+
+/// Track descriptor uploads from HS_DESC events read on `conn`, which must
+/// be dedicated to events.
+pub async fn watch_uploads<S>(
+    mut conn: ControlConn<S>,
+    states: PublishStates,
+    events: broadcast::Sender<OnionPipeEvent>,
+) -> crate::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    conn.set_events(&["HS_DESC"]).await?;
+    loop {
+        let lines = conn.next_event().await?;
+        let event = match lines.first().and_then(|line| parse_hs_desc(line)) {
+            Some(event) => event,
+            None => continue,
+        };
+        tracing::debug!("{}", lines[0]);
+        let state = match states.update(&event) {
+            Some(state) => state,
+            None => continue,
+        };
+        let onion_addr = match onion::OnionAddressV3::from_str(event.service_id) {
+            Ok(onion_addr) => onion_addr,
+            Err(_) => continue,
+        };
+        if let Some(event) = state_event(onion_addr, state) {
+            let _ = events.send(event);
+        }
+    }
+}
+
+/// The event reporting a change to `state`, if any.
+pub fn state_event(
+    onion_addr: onion::OnionAddressV3,
+    state: PublishState,
+) -> Option<OnionPipeEvent> {
+    match state {
+        PublishState::Uploaded { hs_dirs } => Some(OnionPipeEvent::DescriptorUploaded {
+            onion_addr,
+            hs_dirs,
+        }),
+        PublishState::Failed { reason } => {
+            Some(OnionPipeEvent::DescriptorFailed { onion_addr, reason })
+        }
+        PublishState::Pending => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn onion_addr() -> onion::OnionAddressV3 {
+        onion::TorSecretKeyV3::from([1u8; 64])
+            .public()
+            .get_onion_address()
+    }
+
+    #[test]
+    fn test_parse_hs_desc() {
+        assert_eq!(
+            parse_hs_desc("HS_DESC FAILED abc UNKNOWN $AAAA~relay REASON=UPLOAD_REJECTED"),
+            Some(HsDescEvent {
+                action: "FAILED",
+                service_id: "abc",
+                reason: Some("UPLOAD_REJECTED"),
+            })
+        );
+        assert_eq!(parse_hs_desc("STATUS_CLIENT NOTICE BOOTSTRAP"), None);
+    }
+
+    #[test]
+    fn test_publish_states() {
+        let states = PublishStates::default();
+        let onion_addr = onion_addr();
+        let service_id = onion_addr.get_address_without_dot_onion();
+        assert_eq!(states.get(&onion_addr), None);
+        states.register(&onion_addr);
+        assert_eq!(states.get(&onion_addr), Some(PublishState::Pending));
+
+        let event = |action, reason| HsDescEvent {
+            action,
+            service_id: &service_id,
+            reason,
+        };
+        assert_eq!(states.update(&event("UPLOAD", None)), None);
+        assert_eq!(states.update(&event("UPLOAD", None)), None);
+        // One of two uploads failing is not a failure yet.
+        assert_eq!(
+            states.update(&event("FAILED", Some("UPLOAD_REJECTED"))),
+            None
+        );
+        assert_eq!(
+            states.update(&event("FAILED", Some("UPLOAD_REJECTED"))),
+            Some(PublishState::Failed {
+                reason: "UPLOAD_REJECTED".to_string()
+            })
+        );
+        states.update(&event("UPLOAD", None));
+        assert_eq!(
+            states.update(&event("UPLOADED", None)),
+            Some(PublishState::Uploaded { hs_dirs: 1 })
+        );
+        assert_eq!(states.update(&event("UPLOADED", None)), None);
+        assert_eq!(
+            states.get(&onion_addr),
+            Some(PublishState::Uploaded { hs_dirs: 2 })
+        );
+
+        states.remove(&onion_addr);
+        assert_eq!(states.update(&event("UPLOADED", None)), None);
+    }
+
+    #[tokio::test]
+    async fn test_watch_uploads() {
+        let (client, server) = tokio::io::duplex(4096);
+        let states = PublishStates::default();
+        let onion_addr = onion_addr();
+        states.register(&onion_addr);
+        let (events_tx, mut events_rx) = broadcast::channel(16);
+        tokio::spawn(watch_uploads(
+            ControlConn::new(client),
+            states.clone(),
+            events_tx,
+        ));
+
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        let (read, mut write) = tokio::io::split(server);
+        let mut lines = BufReader::new(read).lines();
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "SETEVENTS HS_DESC"
+        );
+        let service_id = onion_addr.get_address_without_dot_onion();
+        write
+            .write_all(
+                format!(
+                    "250 OK\r\n650 HS_DESC UPLOAD {0} UNKNOWN $AAAA~relay\r\n650 HS_DESC UPLOADED {0} UNKNOWN $AAAA~relay\r\n",
+                    service_id
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        match events_rx.recv().await.unwrap() {
+            OnionPipeEvent::DescriptorUploaded {
+                onion_addr: addr,
+                hs_dirs,
+            } => {
+                assert_eq!(addr, onion_addr);
+                assert_eq!(hs_dirs, 1);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(
+            states.get(&onion_addr),
+            Some(PublishState::Uploaded { hs_dirs: 1 })
+        );
+    }
+}
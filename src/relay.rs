@@ -0,0 +1,475 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+use crate::metrics::{ForwardMetrics, Metered};
+use crate::{backend, config, LocalAddr, PipeError, Result};
+
+// Byte relaying between local connections and onion streams, and the limits
+// and socket options applied to it.
+
+/// Limits and socket options for forwarded connections.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionOptions {
+    /// Close a connection after this long without data in either direction.
+    pub idle_timeout: Option<Duration>,
+    /// Close a connection this long after it was opened.
+    pub max_lifetime: Option<Duration>,
+    /// Enable TCP keepalive on the local socket, probing after this long
+    /// idle.
+    pub keepalive: Option<Duration>,
+    /// Set TCP_NODELAY on the local socket.
+    pub nodelay: bool,
+}
+
+impl ConnectionOptions {
+    pub fn is_default(&self) -> bool {
+        *self == ConnectionOptions::default()
+    }
+
+    /// Apply the socket options to a local TCP connection.
+    pub(crate) fn configure(&self, stream: &tokio::net::TcpStream) -> std::io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            socket2::SockRef::from(stream)
+                .set_tcp_keepalive(&socket2::TcpKeepalive::new().with_time(keepalive))?;
+        }
+        Ok(())
+    }
+
+    /// Connect to a local address, applying the socket options.
+    pub(crate) async fn connect(
+        &self,
+        addr: &LocalAddr,
+    ) -> std::io::Result<Box<dyn backend::TorStream>> {
+        match addr {
+            LocalAddr::TCP(addr) => {
+                let stream = tokio::net::TcpStream::connect(addr).await?;
+                self.configure(&stream)?;
+                Ok(Box::new(stream))
+            }
+            LocalAddr::Unix(_) => backend::connect(addr).await,
+        }
+    }
+}
+
+fn positive_ms(name: &str, ms: Option<u64>) -> Result<Option<Duration>> {
+    match ms {
+        Some(0) => Err(PipeError::Config(format!(
+            "connection {} must be greater than 0",
+            name
+        ))),
+        ms => Ok(ms.map(Duration::from_millis)),
+    }
+}
+
+impl TryInto<ConnectionOptions> for config::Connection {
+    type Error = PipeError;
+
+    fn try_into(self) -> Result<ConnectionOptions> {
+        Ok(ConnectionOptions {
+            idle_timeout: positive_ms("idle_timeout_ms", self.idle_timeout_ms)?,
+            max_lifetime: positive_ms("max_lifetime_ms", self.max_lifetime_ms)?,
+            keepalive: positive_ms("keepalive_ms", self.keepalive_ms)?,
+            nodelay: self.nodelay.unwrap_or(false),
+        })
+    }
+}
+
+/// Whether a connection was closed for reaching one of its limits, rather
+/// than failing.
+pub(crate) fn is_timeout(err: &PipeError) -> bool {
+    matches!(
+        err,
+        PipeError::IdleTimeout(_) | PipeError::LifetimeExceeded(_)
+    )
+}
+
+/// When data last moved in either direction of a connection.
+struct Activity {
+    start: Instant,
+    last_ms: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            start: Instant::now(),
+            last_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        // Rounded up, so that the idle timeout never fires early.
+        let elapsed_ms = self.start.elapsed().as_micros().div_ceil(1000) as u64;
+        self.last_ms.store(elapsed_ms, Ordering::Relaxed);
+    }
+
+    /// Resolve once there has been no activity for `timeout`.
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            let deadline =
+                self.start + Duration::from_millis(self.last_ms.load(Ordering::Relaxed)) + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+async fn sleep_or_pending(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Relay between a local connection and a remote onion stream, counting the
+/// bytes sent to and received from the remote.
+///
+/// Each direction runs until its reader reaches EOF, which is passed on as a
+/// shutdown of the other side's write half, so a peer that half-closes still
+/// receives the rest of the response. A connection that reaches the idle
+/// timeout or lifetime in `options` is closed with
+/// [`PipeError::IdleTimeout`] or [`PipeError::LifetimeExceeded`].
+pub(crate) async fn forward_stream<L, R>(
+    local: L,
+    remote: R,
+    options: &ConnectionOptions,
+    bytes_sent: &mut u64,
+    bytes_received: &mut u64,
+) -> Result<()>
+where
+    L: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut local_read, mut local_write) = tokio::io::split(local);
+    let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+    let activity = Activity::new();
+    let copy = async {
+        tokio::try_join!(
+            copy_counted(
+                &mut remote_read,
+                &mut local_write,
+                bytes_received,
+                &activity
+            ),
+            copy_counted(&mut local_read, &mut remote_write, bytes_sent, &activity),
+        )
+    };
+    let idle = async {
+        match options.idle_timeout {
+            Some(timeout) => activity.idle(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        result = copy => result.map(|_| ()),
+        _ = idle => Err(PipeError::IdleTimeout(options.idle_timeout.unwrap_or_default())),
+        _ = sleep_or_pending(options.max_lifetime) => {
+            Err(PipeError::LifetimeExceeded(options.max_lifetime.unwrap_or_default()))
+        }
+    }
+}
+
+/// Copy until `reader` reaches EOF, then shut down `writer`.
+async fn copy_counted<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    count: &mut u64,
+    activity: &Activity,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 8192];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        activity.touch();
+        writer.write_all(&buf[..n]).await?;
+        // Onion streams buffer writes into cells; don't hold back a partial one.
+        writer.flush().await?;
+        *count += n as u64;
+    }
+    match writer.shutdown().await {
+        // The peer may have already closed its end entirely.
+        Err(err) if err.kind() == std::io::ErrorKind::NotConnected => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Carry a stream from an onion client to an export's local address, logging
+/// how it ends.
+pub(crate) async fn relay_export<L, S>(
+    local_stream: L,
+    onion_stream: S,
+    local_addr: &LocalAddr,
+    options: &ConnectionOptions,
+    metrics: Option<Arc<ForwardMetrics>>,
+) where
+    L: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut bytes_sent, mut bytes_received) = (0, 0);
+    match forward_stream(
+        Metered::new(local_stream, metrics.clone()),
+        onion_stream,
+        options,
+        &mut bytes_sent,
+        &mut bytes_received,
+    )
+    .await
+    {
+        Err(err) if is_timeout(&err) => {
+            tracing::info!("onion stream to {} closed: {}", local_addr, err)
+        }
+        Err(err) => {
+            if let Some(metrics) = metrics {
+                metrics.failed();
+            }
+            tracing::debug!("onion stream to {} failed: {}", local_addr, err)
+        }
+        Ok(()) => {}
+    }
+}
+
+/// Relays an export's connections from a Tor control port backend, which
+/// would otherwise connect to the local address itself, so that its
+/// connection options apply. Stops accepting, and closes the connections it
+/// relays, when dropped.
+pub(crate) struct ExportRelay {
+    addr: LocalAddr,
+    task: tokio::task::JoinHandle<()>,
+    // Holds the relay's socket, and is removed with it.
+    _dir: tempfile::TempDir,
+}
+
+impl ExportRelay {
+    /// Start relaying on a unix socket in a new private directory in `dir`.
+    /// A TCP port would let any local user reach the export, bypassing the
+    /// permissions of its unix socket and its onion's client authorization.
+    pub(crate) async fn start(
+        dir: &path::Path,
+        local_addr: &LocalAddr,
+        options: &ConnectionOptions,
+        metrics: Option<Arc<ForwardMetrics>>,
+    ) -> Result<ExportRelay> {
+        let relay_dir = tempfile::Builder::new().prefix("relay").tempdir_in(dir)?;
+        std::fs::set_permissions(relay_dir.path(), std::fs::Permissions::from_mode(0o700))?;
+        let socket_path = relay_dir.path().join("relay.sock");
+        let listener = tokio::net::UnixListener::bind(&socket_path)?;
+        let addr = LocalAddr::Unix(socket_path);
+        let local_addr = local_addr.clone();
+        let options = options.clone();
+        let task = tokio::spawn(async move {
+            let mut connections = tokio::task::JoinSet::new();
+            loop {
+                let onion_stream = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((onion_stream, _)) => onion_stream,
+                        Err(err) => {
+                            tracing::warn!("export relay for {} failed: {}", local_addr, err);
+                            return;
+                        }
+                    },
+                    Some(_) = connections.join_next() => continue,
+                };
+                let (local_addr, options, metrics) =
+                    (local_addr.clone(), options.clone(), metrics.clone());
+                connections.spawn(async move {
+                    let _active = metrics.as_ref().map(|metrics| metrics.open());
+                    match options.connect(&local_addr).await {
+                        Ok(local_stream) => {
+                            relay_export(local_stream, onion_stream, &local_addr, &options, metrics)
+                                .await
+                        }
+                        Err(err) => {
+                            if let Some(metrics) = metrics {
+                                metrics.failed();
+                            }
+                            tracing::debug!("failed to connect to {}: {}", local_addr, err)
+                        }
+                    }
+                });
+            }
+        });
+        Ok(ExportRelay {
+            addr,
+            task,
+            _dir: relay_dir,
+        })
+    }
+
+    /// The address Tor should forward the export's connections to.
+    pub(crate) fn addr(&self) -> &LocalAddr {
+        &self.addr
+    }
+}
+
+impl Drop for ExportRelay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_into_connection_options() {
+        let options: ConnectionOptions = config::Connection {
+            idle_timeout_ms: Some(300000),
+            max_lifetime_ms: None,
+            keepalive_ms: Some(60000),
+            nodelay: Some(true),
+        }
+        .try_into()
+        .unwrap();
+        assert_eq!(
+            options,
+            ConnectionOptions {
+                idle_timeout: Some(Duration::from_secs(300)),
+                max_lifetime: None,
+                keepalive: Some(Duration::from_secs(60)),
+                nodelay: true,
+            }
+        );
+
+        let result: Result<ConnectionOptions> = config::Connection {
+            idle_timeout_ms: Some(0),
+            max_lifetime_ms: None,
+            keepalive_ms: None,
+            nodelay: None,
+        }
+        .try_into();
+        assert!(matches!(result, Err(PipeError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn export_relay_socket() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = LocalAddr::TCP(server.local_addr().unwrap());
+        let relay = ExportRelay::start(
+            tmp_dir.path(),
+            &local_addr,
+            &ConnectionOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // The relay is only reachable through a directory private to its user.
+        let socket_path = match relay.addr() {
+            LocalAddr::Unix(socket_path) => socket_path.clone(),
+            addr => panic!("relay listens on {}", addr),
+        };
+        let relay_dir = socket_path.parent().unwrap().to_path_buf();
+        assert!(relay_dir.starts_with(tmp_dir.path()));
+        assert_eq!(
+            std::fs::metadata(&relay_dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+
+        let mut client = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        let (mut local, _) = server.accept().await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        drop(relay);
+        assert!(!relay_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn forward_stream_half_close() {
+        let (mut client, local) = tokio::io::duplex(1024);
+        let (remote, mut server) = tokio::io::duplex(1024);
+        let forward = tokio::spawn(async move {
+            let (mut bytes_sent, mut bytes_received) = (0, 0);
+            forward_stream(
+                local,
+                remote,
+                &ConnectionOptions::default(),
+                &mut bytes_sent,
+                &mut bytes_received,
+            )
+            .await
+            .map(|_| (bytes_sent, bytes_received))
+        });
+
+        // The client half-closes after its request, and the server only
+        // responds once it has read the whole request.
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = vec![];
+        server.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        server.write_all(b"response").await.unwrap();
+        drop(server);
+
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+        assert_eq!(forward.await.unwrap().unwrap(), (7, 8));
+    }
+
+    #[tokio::test]
+    async fn forward_stream_timeouts() {
+        let options = ConnectionOptions {
+            idle_timeout: Some(Duration::from_millis(100)),
+            max_lifetime: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let (mut bytes_sent, mut bytes_received) = (0, 0);
+
+        let (_client, local) = tokio::io::duplex(1024);
+        let (remote, _server) = tokio::io::duplex(1024);
+        let result = forward_stream(
+            local,
+            remote,
+            &options,
+            &mut bytes_sent,
+            &mut bytes_received,
+        )
+        .await;
+        assert!(matches!(result, Err(PipeError::IdleTimeout(_))));
+
+        // Activity keeps the idle timeout from firing, but not the lifetime.
+        let (mut client, local) = tokio::io::duplex(1024);
+        let (remote, _server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                if client.write_all(b"ping").await.is_err() {
+                    return;
+                }
+            }
+        });
+        let started = Instant::now();
+        let result = forward_stream(
+            local,
+            remote,
+            &options,
+            &mut bytes_sent,
+            &mut bytes_received,
+        )
+        .await;
+        assert!(matches!(result, Err(PipeError::LifetimeExceeded(_))));
+        assert!(started.elapsed() >= Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use crate::{config, PipeError, Result};
+
+/// How an import retries connecting to its onion, holding the accepted local
+/// connection open in the meantime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Connection attempts before giving up, including the first.
+    pub attempts: u32,
+    /// Wait before the first retry, doubled for each retry after it.
+    pub backoff: Duration,
+    /// Longest wait between retries.
+    pub max_backoff: Duration,
+    /// Give up on a connection this long after it was accepted.
+    pub deadline: Option<Duration>,
+}
+
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+impl Default for RetryPolicy {
+    /// A single attempt, without retries.
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 1,
+            backoff: DEFAULT_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before retry number `retry`, counting from 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+impl TryInto<RetryPolicy> for config::Retry {
+    type Error = PipeError;
+
+    fn try_into(self) -> Result<RetryPolicy> {
+        if self.attempts == 0 {
+            return Err(PipeError::Config(
+                "import retry attempts must be at least 1".to_string(),
+            ));
+        }
+        Ok(RetryPolicy {
+            attempts: self.attempts,
+            backoff: self
+                .backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_BACKOFF),
+            max_backoff: self
+                .max_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_MAX_BACKOFF),
+            deadline: self.deadline_ms.map(Duration::from_millis),
+        })
+    }
+}
+
+/// Whether a failed onion connection might succeed if tried again, such as
+/// while a descriptor is still propagating. Client authorization failures and
+/// bad addresses won't.
+pub(crate) fn is_retryable(err: &PipeError) -> bool {
+    match err {
+        PipeError::Socks(err) => err.is_transient(),
+        // Tor's SOCKS port may not be accepting connections yet.
+        PipeError::IO { .. } => true,
+        #[cfg(feature = "arti")]
+        PipeError::Arti(err) => {
+            use arti_client::{ErrorKind, HasKind};
+            !matches!(
+                err.kind(),
+                ErrorKind::OnionServiceMissingClientAuth
+                    | ErrorKind::OnionServiceWrongClientAuth
+                    | ErrorKind::OnionServiceAddressInvalid
+                    | ErrorKind::InvalidStreamTarget
+            )
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks::SocksError;
+
+    #[test]
+    fn delay() {
+        let policy = RetryPolicy {
+            attempts: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+            deadline: None,
+        };
+        let delays: Vec<_> = (1..5).map(|retry| policy.delay(retry)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 350, 350].map(Duration::from_millis).to_vec()
+        );
+        assert_eq!(policy.delay(100), Duration::from_millis(350));
+    }
+
+    #[test]
+    fn try_into_retry_policy() {
+        let policy: RetryPolicy = config::Retry {
+            attempts: 3,
+            backoff_ms: None,
+            max_backoff_ms: Some(2000),
+            deadline_ms: Some(30000),
+        }
+        .try_into()
+        .unwrap();
+        assert_eq!(
+            policy,
+            RetryPolicy {
+                attempts: 3,
+                backoff: DEFAULT_BACKOFF,
+                max_backoff: Duration::from_secs(2),
+                deadline: Some(Duration::from_secs(30)),
+            }
+        );
+
+        let result: Result<RetryPolicy> = config::Retry {
+            attempts: 0,
+            backoff_ms: None,
+            max_backoff_ms: None,
+            deadline_ms: None,
+        }
+        .try_into();
+        assert!(matches!(result, Err(PipeError::Config(_))));
+    }
+
+    #[test]
+    fn retryable_errors() {
+        assert!(is_retryable(&SocksError::DescriptorNotFound.into()));
+        assert!(is_retryable(&SocksError::IntroTimeout.into()));
+        assert!(!is_retryable(&SocksError::MissingClientAuth.into()));
+        assert!(!is_retryable(&SocksError::BadAddress.into()));
+        assert!(!is_retryable(&PipeError::OnionConnectTimeout));
+    }
+}
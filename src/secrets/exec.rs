@@ -0,0 +1,172 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use super::{Result, SecretKind, SecretStore, SecretsError};
+
+// A secret store backed by an external helper program, in the manner of git
+// credential helpers.
+//
+// The helper is run with its arguments followed by an operation: `get`,
+// `store`, `erase` or `list`. The request is written to its stdin as
+// `key=value` lines: `kind` (`service` or `client`), `name` except for
+// `list`, and `secret` in base64 for `store`. For `get`, the helper prints a
+// `secret=` line, or nothing if there is no such secret. For `list`, it prints
+// a `name=` line for each secret of the kind. A helper that exits non-zero
+// fails the operation, with what it wrote to stderr.
+
+/// Keys fetched and stored by running an external helper program.
+#[derive(Clone)]
+pub struct ExecSecretStore {
+    program: String,
+    args: Vec<String>,
+}
+
+impl ExecSecretStore {
+    pub fn new(program: &str, args: &[String]) -> ExecSecretStore {
+        ExecSecretStore {
+            program: program.to_string(),
+            args: args.to_vec(),
+        }
+    }
+
+    /// Run the helper for `op` with the `request` lines, returning the
+    /// `key=value` lines it answers with.
+    fn call(&self, op: &str, request: &[(&str, &str)]) -> Result<Vec<(String, String)>> {
+        let mut input = String::new();
+        for (key, value) in request {
+            if value.contains('\n') {
+                return Err(SecretsError::Helper(format!(
+                    "{} may not contain a newline",
+                    key
+                )));
+            }
+            input.push_str(&format!("{}={}\n", key, value));
+        }
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .arg(op)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| SecretsError::Helper(format!("{}: {}", self.program, err)))?;
+        // The helper may exit without reading its request.
+        let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(SecretsError::Helper(format!(
+                "{} {}: {}: {}",
+                self.program,
+                op,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect())
+    }
+}
+
+impl SecretStore for ExecSecretStore {
+    fn get(&self, kind: SecretKind, name: &str) -> Result<Option<Vec<u8>>> {
+        let kind = kind.to_string();
+        let response = self.call("get", &[("kind", &kind), ("name", name)])?;
+        match response.into_iter().find(|(key, _)| key == "secret") {
+            Some((_, secret)) => Ok(Some(base64::decode(secret.trim()).map_err(|err| {
+                SecretsError::Helper(format!("invalid secret for {} {}: {}", kind, name, err))
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&mut self, kind: SecretKind, name: &str, secret: &[u8]) -> Result<()> {
+        let kind = kind.to_string();
+        let secret = base64::encode(secret);
+        self.call(
+            "store",
+            &[("kind", &kind), ("name", name), ("secret", &secret)],
+        )?;
+        Ok(())
+    }
+
+    fn delete(&mut self, kind: SecretKind, name: &str) -> Result<Option<()>> {
+        if self.get(kind, name)?.is_none() {
+            return Ok(None);
+        }
+        let kind = kind.to_string();
+        self.call("erase", &[("kind", &kind), ("name", name)])?;
+        Ok(Some(()))
+    }
+
+    fn list(&self, kind: SecretKind) -> Result<Vec<String>> {
+        let kind = kind.to_string();
+        let response = self.call("list", &[("kind", &kind)])?;
+        Ok(response
+            .into_iter()
+            .filter(|(key, _)| key == "name")
+            .map(|(_, name)| name)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keeps each secret in a file named kind.name under the directory given
+    // as its first argument.
+    const HELPER: &str = r#"
+dir=$1; op=$2
+while read -r line; do
+    case $line in
+        kind=*) kind=${line#kind=} ;;
+        name=*) name=${line#name=} ;;
+        secret=*) secret=${line#secret=} ;;
+    esac
+done
+case $op in
+    get) [ -f "$dir/$kind.$name" ] && echo "secret=$(cat "$dir/$kind.$name")" ;;
+    store) echo "$secret" > "$dir/$kind.$name" ;;
+    erase) rm "$dir/$kind.$name" ;;
+    list) for f in "$dir/$kind".*; do [ -f "$f" ] && echo "name=${f#$dir/$kind.}"; done ;;
+    *) echo "unknown operation $op" >&2; exit 1 ;;
+esac
+exit 0
+"#;
+
+    #[test]
+    fn test_exec_store() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = tmp_dir.path().to_str().unwrap().to_string();
+        let mut store = ExecSecretStore::new(
+            "sh",
+            &["-c".to_string(), HELPER.to_string(), "sh".to_string(), dir],
+        );
+        assert_eq!(store.get_service("web").unwrap(), None);
+        let service_key = store.ensure_service("web").unwrap();
+        assert_eq!(store.get_service("web").unwrap(), Some(service_key));
+        let client_key = store.ensure_client("alice").unwrap();
+        assert_eq!(store.get_client("alice").unwrap(), Some(client_key));
+        assert_eq!(store.list_services().unwrap(), vec!["web"]);
+        assert_eq!(store.list_clients().unwrap(), vec!["alice"]);
+        assert_eq!(store.delete_client("alice").unwrap(), Some(()));
+        assert_eq!(store.delete_client("alice").unwrap(), None);
+        assert!(store.list_clients().unwrap().is_empty());
+
+        assert!(matches!(
+            store.put(SecretKind::Client, "bad\nname", &[0u8; 32]),
+            Err(SecretsError::Helper(_))
+        ));
+        let broken = ExecSecretStore::new(
+            "sh",
+            &["-c".to_string(), "echo nope >&2; exit 3".to_string()],
+        );
+        match broken.get_service("web") {
+            Err(SecretsError::Helper(msg)) => assert!(msg.contains("nope")),
+            _ => panic!("expected helper error"),
+        }
+    }
+}
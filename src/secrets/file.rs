@@ -0,0 +1,502 @@
+use std::time::SystemTime;
+use std::{fs, io, path};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::XChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use super::{format, Result, SecretKind, SecretStore, SecretsError};
+
+// The default secret store: a directory with a file per key, optionally
+// encrypted with a passphrase.
+
+/// Keys kept in files under a directory. The store may be encrypted with a
+/// passphrase, in which case it must be unlocked before keys can be read or
+/// written.
+#[derive(Clone)]
+pub struct FileSecretStore {
+    secrets_dir: String,
+    key: Option<Zeroizing<[u8; 32]>>,
+}
+
+const SERVICES_DIR: &str = "services";
+const CLIENTS_DIR: &str = "clients";
+
+/// Present in an encrypted store, with the parameters its key is derived by.
+const STORE_FILE: &str = "store.json";
+/// Entries are written under this suffix, then renamed into place.
+const PENDING_SUFFIX: &str = ".pending";
+/// Encrypted in [`StoreParams::check`] to recognize a wrong passphrase.
+const CHECK_PLAINTEXT: &[u8] = b"onionpipe secret store";
+
+#[derive(Serialize, Deserialize)]
+struct StoreParams {
+    kdf: String,
+    salt: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    check: String,
+}
+
+impl FileSecretStore {
+    pub fn new(secrets_dir: &str) -> FileSecretStore {
+        FileSecretStore {
+            secrets_dir: secrets_dir.to_owned(),
+            key: None,
+        }
+    }
+
+    /// Whether the store is encrypted with a passphrase.
+    pub fn is_encrypted(&self) -> bool {
+        self.store_file().exists()
+    }
+
+    /// Unlock an encrypted store with `passphrase`.
+    pub fn unlock(&mut self, passphrase: &str) -> Result<()> {
+        let params: StoreParams = match fs::read(self.store_file()) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|err| SecretsError::Params(err.to_string()))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(SecretsError::NotEncrypted)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let key = derive_key(passphrase, &params)?;
+        let check =
+            base64::decode(&params.check).map_err(|err| SecretsError::Params(err.to_string()))?;
+        match open(&key, STORE_FILE, &check) {
+            Some(plaintext) if plaintext == CHECK_PLAINTEXT => {}
+            _ => return Err(SecretsError::WrongPassphrase),
+        }
+        self.key = Some(key);
+        self.finish_pending()
+    }
+
+    /// Encrypt a plaintext store in place with `passphrase`, leaving it
+    /// unlocked.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        self.encrypt_with(passphrase, argon2::Params::DEFAULT)
+    }
+
+    /// When the key `kind` named `name` was written, if known. Keys written
+    /// by earlier versions of onionpipe don't record it.
+    pub fn created(&self, kind: SecretKind, name: &str) -> Result<Option<SystemTime>> {
+        Ok(self.read_entry(kind, name)?.and_then(|entry| entry.created))
+    }
+
+    fn encrypt_with(&mut self, passphrase: &str, argon2_params: argon2::Params) -> Result<()> {
+        if self.is_encrypted() {
+            return Err(SecretsError::AlreadyEncrypted);
+        }
+        let mut entries = vec![];
+        for kind in [SecretKind::Service, SecretKind::Client] {
+            for name in self.list(kind)? {
+                if let Some(entry) = self.read_entry(kind, &name)? {
+                    entries.push((kind, name, entry));
+                }
+            }
+        }
+
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut params = StoreParams {
+            kdf: "argon2id".to_string(),
+            salt: base64::encode(salt),
+            m_cost: argon2_params.m_cost(),
+            t_cost: argon2_params.t_cost(),
+            p_cost: argon2_params.p_cost(),
+            check: String::new(),
+        };
+        let key = derive_key(passphrase, &params)?;
+        params.check = base64::encode(seal(&key, STORE_FILE, CHECK_PLAINTEXT));
+        self.key = Some(key);
+
+        // Entries are encrypted alongside the plaintext, and only replace it
+        // once the store file is written, which unlock() completes should
+        // this be interrupted.
+        for (kind, name, entry) in entries {
+            let created = entry.created.unwrap_or_else(SystemTime::now);
+            self.write_pending(kind, &name, &format::encode(kind, created, &entry.key))?;
+        }
+        let store_file = self.store_file();
+        let pending_file = pending(&store_file);
+        super::write_private(&pending_file, &serde_json::to_vec_pretty(&params).unwrap())?;
+        fs::rename(pending_file, store_file)?;
+        self.finish_pending()
+    }
+
+    fn store_file(&self) -> path::PathBuf {
+        path::PathBuf::from(&self.secrets_dir).join(STORE_FILE)
+    }
+
+    fn dir(&self, kind: SecretKind) -> path::PathBuf {
+        path::PathBuf::from(&self.secrets_dir).join(kind_dir(kind))
+    }
+
+    fn entry_file(&self, kind: SecretKind, name: &str) -> path::PathBuf {
+        self.dir(kind).join(name)
+    }
+
+    /// The key to encrypt entries with, `None` if the store is plaintext.
+    fn entry_key(&self) -> Result<Option<&[u8; 32]>> {
+        match self.key {
+            Some(ref key) => Ok(Some(key)),
+            None if self.is_encrypted() => Err(SecretsError::Locked),
+            None => Ok(None),
+        }
+    }
+
+    fn read_entry(&self, kind: SecretKind, name: &str) -> Result<Option<format::Entry>> {
+        let entry_file = self.entry_file(kind, name);
+        let contents = match fs::read(&entry_file) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let contents = match self.entry_key()? {
+            Some(key) => match open(key, &entry_aad(kind, name), &contents) {
+                Some(plaintext) => plaintext,
+                None => return Err(SecretsError::Decrypt(entry_file.display().to_string())),
+            },
+            None => contents,
+        };
+        match format::decode(kind, &contents) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(reason) => Err(SecretsError::Corrupt {
+                path: entry_file.display().to_string(),
+                reason,
+            }),
+        }
+    }
+
+    /// Write an encoded entry alongside its file, encrypted if the store is.
+    fn write_pending(&self, kind: SecretKind, name: &str, entry: &[u8]) -> Result<path::PathBuf> {
+        let contents = match self.entry_key()? {
+            Some(key) => seal(key, &entry_aad(kind, name), entry),
+            None => entry.to_vec(),
+        };
+        fs::create_dir_all(self.dir(kind))?;
+        let pending_file = pending(&self.entry_file(kind, name));
+        let _ = fs::remove_file(&pending_file);
+        super::write_private(&pending_file, &contents)?;
+        Ok(pending_file)
+    }
+
+    /// Move encrypted entries left pending into place, and discard any that
+    /// were not completely written.
+    fn finish_pending(&self) -> Result<()> {
+        let key = self.entry_key()?.ok_or(SecretsError::NotEncrypted)?;
+        for kind in [SecretKind::Service, SecretKind::Client] {
+            let dir = match fs::read_dir(self.dir(kind)) {
+                Ok(dir) => dir,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            for entry in dir {
+                let entry = entry?;
+                let file_name = entry.file_name();
+                let Some(name) = file_name
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(PENDING_SUFFIX))
+                else {
+                    continue;
+                };
+                let contents = fs::read(entry.path())?;
+                if open(key, &entry_aad(kind, name), &contents).is_some() {
+                    fs::rename(entry.path(), self.entry_file(kind, name))?;
+                } else {
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SecretStore for FileSecretStore {
+    fn get(&self, kind: SecretKind, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_entry(kind, name)?.map(|entry| entry.key))
+    }
+
+    fn put(&mut self, kind: SecretKind, name: &str, secret: &[u8]) -> Result<()> {
+        if secret.len() != kind.key_len() {
+            return Err(SecretsError::InvalidKey {
+                kind,
+                name: name.to_string(),
+            });
+        }
+        let entry = format::encode(kind, SystemTime::now(), secret);
+        let pending_file = self.write_pending(kind, name, &entry)?;
+        fs::rename(pending_file, self.entry_file(kind, name))?;
+        Ok(())
+    }
+
+    fn delete(&mut self, kind: SecretKind, name: &str) -> Result<Option<()>> {
+        match fs::remove_file(self.entry_file(kind, name)) {
+            Ok(()) => Ok(Some(())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn list(&self, kind: SecretKind) -> Result<Vec<String>> {
+        let mut names: Vec<String> = vec![];
+        let dir = match fs::read_dir(self.dir(kind)) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(names),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in dir {
+            let entry = entry?;
+            if let Some(fname) = entry.file_name().to_str() {
+                if !fname.ends_with(PENDING_SUFFIX) {
+                    names.push(fname.to_owned());
+                }
+            }
+        }
+        Ok(names)
+    }
+}
+
+fn derive_key(passphrase: &str, params: &StoreParams) -> Result<Zeroizing<[u8; 32]>> {
+    if params.kdf != "argon2id" {
+        return Err(SecretsError::Params(format!("unknown kdf {}", params.kdf)));
+    }
+    let salt = base64::decode(&params.salt).map_err(|err| SecretsError::Params(err.to_string()))?;
+    let argon2_params = argon2::Params::new(params.m_cost, params.t_cost, params.p_cost, None)
+        .map_err(|err| SecretsError::Params(err.to_string()))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    argon2::Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        argon2_params,
+    )
+    .hash_password_into(passphrase.as_bytes(), &salt, &mut *key)
+    .map_err(|err| SecretsError::Params(err.to_string()))?;
+    Ok(key)
+}
+
+/// Entries are bound to their name, so that one can't be swapped for another.
+fn entry_aad(kind: SecretKind, name: &str) -> String {
+    format!("{}/{}", kind_dir(kind), name)
+}
+
+fn kind_dir(kind: SecretKind) -> &'static str {
+    match kind {
+        SecretKind::Service => SERVICES_DIR,
+        SecretKind::Client => CLIENTS_DIR,
+    }
+}
+
+/// Encrypt `plaintext` as a random nonce followed by the ciphertext.
+fn seal(key: &[u8; 32], aad: &str, plaintext: &[u8]) -> Vec<u8> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: aad.as_bytes(),
+            },
+        )
+        .expect("encryption failed");
+    [&nonce[..], &ciphertext].concat()
+}
+
+fn open(key: &[u8; 32], aad: &str, contents: &[u8]) -> Option<Vec<u8>> {
+    let nonce_len = 24;
+    if contents.len() < nonce_len {
+        return None;
+    }
+    let (nonce, ciphertext) = contents.split_at(nonce_len);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(
+            nonce.into(),
+            Payload {
+                msg: ciphertext,
+                aad: aad.as_bytes(),
+            },
+        )
+        .ok()
+}
+
+fn pending(file: &path::Path) -> path::PathBuf {
+    let mut pending = file.as_os_str().to_owned();
+    pending.push(PENDING_SUFFIX);
+    path::PathBuf::from(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_service() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let secrets_dir = tmp_dir.path().join("secrets");
+        let mut store = FileSecretStore::new(secrets_dir.to_str().unwrap());
+        let key1 = store.ensure_service("test").unwrap();
+        assert!(secrets_dir.join(SERVICES_DIR).join("test").exists());
+        let key2 = store.ensure_service("test").unwrap();
+        assert_eq!(key1, key2);
+    }
+
+    #[test]
+    fn test_delete_service() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let secrets_dir = tmp_dir.path().join("secrets");
+        let mut store = FileSecretStore::new(secrets_dir.to_str().unwrap());
+        store.ensure_service("test").unwrap();
+        assert!(secrets_dir.join("services").join("test").exists());
+        let result = store.delete_service("test").unwrap();
+        assert!(result.is_some());
+        assert!(!secrets_dir.join("services").join("test").exists());
+        let result = store.delete_service("test").unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_ensure_client() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let secrets_dir = tmp_dir.path().join("secrets");
+        let mut store = FileSecretStore::new(secrets_dir.to_str().unwrap());
+        let key1 = store.ensure_client("test").unwrap();
+        assert!(secrets_dir.join(CLIENTS_DIR).join("test").exists());
+        let key2 = store.ensure_client("test").unwrap();
+        assert_eq!(key1, key2);
+    }
+
+    #[test]
+    fn test_get_client() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let secrets_dir = tmp_dir.path().join("secrets");
+        let mut store = FileSecretStore::new(secrets_dir.to_str().unwrap());
+        assert_eq!(store.get_client("test").unwrap(), None);
+        let key = store.ensure_client("test").unwrap();
+        assert_eq!(store.get_client("test").unwrap(), Some(key));
+        assert!(store.created(SecretKind::Client, "test").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_legacy_and_corrupt_entries() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let secrets_dir = tmp_dir.path().join("secrets");
+        let mut store = FileSecretStore::new(secrets_dir.to_str().unwrap());
+        fs::create_dir_all(secrets_dir.join(SERVICES_DIR)).unwrap();
+        fs::create_dir_all(secrets_dir.join(CLIENTS_DIR)).unwrap();
+
+        // Raw keys written by earlier versions are still read.
+        fs::write(secrets_dir.join(SERVICES_DIR).join("old"), [1u8; 64]).unwrap();
+        fs::write(secrets_dir.join(CLIENTS_DIR).join("old"), [2u8; 32]).unwrap();
+        assert_eq!(store.get_service("old").unwrap(), Some([1u8; 64]));
+        assert_eq!(store.get_client("old").unwrap(), Some([2u8; 32]));
+        assert_eq!(store.created(SecretKind::Service, "old").unwrap(), None);
+
+        let truncated = secrets_dir.join(SERVICES_DIR).join("truncated");
+        fs::write(&truncated, [1u8; 40]).unwrap();
+        match store.ensure_service("truncated") {
+            Err(SecretsError::Corrupt { path, .. }) => {
+                assert_eq!(path, truncated.display().to_string())
+            }
+            _ => panic!("expected corrupt entry"),
+        }
+        assert!(matches!(
+            store.put(SecretKind::Client, "short", &[0u8; 16]),
+            Err(SecretsError::InvalidKey { .. })
+        ));
+    }
+
+    #[test]
+    fn test_delete_client() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let secrets_dir = tmp_dir.path().join("secrets");
+        let mut store = FileSecretStore::new(secrets_dir.to_str().unwrap());
+        store.ensure_client("test").unwrap();
+        assert!(secrets_dir.join("clients").join("test").exists());
+        let result = store.delete_client("test").unwrap();
+        assert!(result.is_some());
+        assert!(!secrets_dir.join("clients").join("test").exists());
+        let result = store.delete_client("test").unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_encrypted_store() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let secrets_dir = tmp_dir.path().join("secrets");
+        let mut store = FileSecretStore::new(secrets_dir.to_str().unwrap());
+        let service_key = store.ensure_service("web").unwrap();
+        let client_key = store.ensure_client("alice").unwrap();
+
+        let cheap = argon2::Params::new(8, 1, 1, None).unwrap();
+        store.encrypt_with("hunter2", cheap.clone()).unwrap();
+        assert!(store.is_encrypted());
+        let service_file = secrets_dir.join(SERVICES_DIR).join("web");
+        assert_ne!(fs::read(&service_file).unwrap(), service_key);
+        assert_eq!(store.get_service("web").unwrap(), Some(service_key));
+        assert!(matches!(
+            store.encrypt_with("hunter2", cheap),
+            Err(SecretsError::AlreadyEncrypted)
+        ));
+
+        let mut store = FileSecretStore::new(secrets_dir.to_str().unwrap());
+        assert!(matches!(
+            store.get_service("web"),
+            Err(SecretsError::Locked)
+        ));
+        assert!(matches!(
+            store.ensure_client("bob"),
+            Err(SecretsError::Locked)
+        ));
+        assert!(matches!(
+            store.unlock("hunter3"),
+            Err(SecretsError::WrongPassphrase)
+        ));
+        store.unlock("hunter2").unwrap();
+        assert_eq!(store.get_service("web").unwrap(), Some(service_key));
+        assert_eq!(store.get_client("alice").unwrap(), Some(client_key));
+        let bob_key = store.ensure_client("bob").unwrap();
+        assert_eq!(store.get_client("bob").unwrap(), Some(bob_key));
+        let mut clients = store.list_clients().unwrap();
+        clients.sort();
+        assert_eq!(clients, vec!["alice", "bob"]);
+
+        // An entry copied to another name doesn't decrypt.
+        fs::copy(&service_file, secrets_dir.join(SERVICES_DIR).join("copy")).unwrap();
+        assert!(matches!(
+            store.get_service("copy"),
+            Err(SecretsError::Decrypt(_))
+        ));
+
+        // Unlocking finishes complete pending writes and drops partial ones.
+        let pending_file = pending(&secrets_dir.join(CLIENTS_DIR).join("carol"));
+        fs::write(
+            &pending_file,
+            seal(
+                store.key.as_ref().unwrap(),
+                &entry_aad(SecretKind::Client, "carol"),
+                &[7u8; 32],
+            ),
+        )
+        .unwrap();
+        fs::write(
+            pending(&secrets_dir.join(CLIENTS_DIR).join("dave")),
+            b"partial",
+        )
+        .unwrap();
+        let mut store = FileSecretStore::new(secrets_dir.to_str().unwrap());
+        store.unlock("hunter2").unwrap();
+        assert_eq!(store.get_client("carol").unwrap(), Some([7u8; 32]));
+        let mut clients = store.list_clients().unwrap();
+        clients.sort();
+        assert_eq!(clients, vec!["alice", "bob", "carol"]);
+        assert_eq!(
+            fs::read_dir(secrets_dir.join(CLIENTS_DIR)).unwrap().count(),
+            3
+        );
+    }
+}
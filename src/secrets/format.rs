@@ -0,0 +1,136 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use super::SecretKind;
+
+// The format of a secret file, version 1:
+//
+//   magic      8 bytes  "OPSECRET"
+//   version    1 byte   1
+//   key type   1 byte   1 for a service's ed25519 key, 2 for a client's x25519 key
+//   created    8 bytes  seconds since the Unix epoch, big-endian
+//   key        64 or 32 bytes, as given by the key type
+//   checksum   8 bytes  the start of the SHA-256 of everything before it
+//
+// Files written before this format hold just the raw key. As the header and
+// checksum make an entry longer than its key, raw keys are told apart by
+// their length.
+
+const MAGIC: &[u8; 8] = b"OPSECRET";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 8;
+const CHECKSUM_LEN: usize = 8;
+
+/// A key read from a secret file.
+#[derive(Debug)]
+pub(super) struct Entry {
+    pub key: Vec<u8>,
+    /// When the key was written, unknown for a raw key.
+    pub created: Option<SystemTime>,
+}
+
+pub(super) fn encode(kind: SecretKind, created: SystemTime, key: &[u8]) -> Vec<u8> {
+    let created = created
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut contents = Vec::with_capacity(HEADER_LEN + key.len() + CHECKSUM_LEN);
+    contents.extend_from_slice(MAGIC);
+    contents.push(VERSION);
+    contents.push(key_type(kind));
+    contents.extend_from_slice(&created.to_be_bytes());
+    contents.extend_from_slice(key);
+    let checksum = checksum(&contents);
+    contents.extend_from_slice(&checksum);
+    contents
+}
+
+/// Decode the contents of a secret file holding a key of `kind`, or else the
+/// reason it is corrupt.
+pub(super) fn decode(kind: SecretKind, contents: &[u8]) -> Result<Entry, String> {
+    if contents.len() == kind.key_len() {
+        return Ok(Entry {
+            key: contents.to_vec(),
+            created: None,
+        });
+    }
+    let Some(header) = contents.get(..HEADER_LEN) else {
+        return Err(format!("truncated to {} bytes", contents.len()));
+    };
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err("not a secret file".to_string());
+    }
+    let version = header[MAGIC.len()];
+    if version != VERSION {
+        return Err(format!("unsupported version {}", version));
+    }
+    let file_kind = match header[MAGIC.len() + 1] {
+        1 => SecretKind::Service,
+        2 => SecretKind::Client,
+        key_type => return Err(format!("unknown key type {}", key_type)),
+    };
+    if file_kind != kind {
+        return Err(format!("holds a {} key, not a {} key", file_kind, kind));
+    }
+    if contents.len() != HEADER_LEN + kind.key_len() + CHECKSUM_LEN {
+        return Err(format!("wrong length {}", contents.len()));
+    }
+    let (body, file_checksum) = contents.split_at(contents.len() - CHECKSUM_LEN);
+    if file_checksum != checksum(body) {
+        return Err("checksum mismatch".to_string());
+    }
+    let created: [u8; 8] = header[MAGIC.len() + 2..].try_into().unwrap();
+    Ok(Entry {
+        key: body[HEADER_LEN..].to_vec(),
+        created: Some(UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(created))),
+    })
+}
+
+fn key_type(kind: SecretKind) -> u8 {
+    match kind {
+        SecretKind::Service => 1,
+        SecretKind::Client => 2,
+    }
+}
+
+fn checksum(body: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Sha256::digest(body);
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    checksum.copy_from_slice(&digest[..CHECKSUM_LEN]);
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let created = UNIX_EPOCH + Duration::from_secs(1700000000);
+        let contents = encode(SecretKind::Service, created, &[3u8; 64]);
+        assert_eq!(contents.len(), HEADER_LEN + 64 + CHECKSUM_LEN);
+        assert!(contents.starts_with(MAGIC));
+        let entry = decode(SecretKind::Service, &contents).unwrap();
+        assert_eq!(entry.key, vec![3u8; 64]);
+        assert_eq!(entry.created, Some(created));
+
+        // Raw keys are read as they are.
+        let entry = decode(SecretKind::Client, &[5u8; 32]).unwrap();
+        assert_eq!(entry.key, vec![5u8; 32]);
+        assert_eq!(entry.created, None);
+
+        let corrupt = |contents: &[u8]| decode(SecretKind::Service, contents).unwrap_err();
+        assert_eq!(corrupt(&contents[..40]), "wrong length 40");
+        assert_eq!(corrupt(&contents[..10]), "truncated to 10 bytes");
+        assert_eq!(corrupt(&[0u8; 100]), "not a secret file");
+        let mut flipped = contents.clone();
+        flipped[HEADER_LEN] ^= 1;
+        assert_eq!(corrupt(&flipped), "checksum mismatch");
+        let mut future = contents.clone();
+        future[MAGIC.len()] = 2;
+        assert_eq!(corrupt(&future), "unsupported version 2");
+        let client = encode(SecretKind::Client, created, &[5u8; 32]);
+        assert_eq!(corrupt(&client), "holds a client key, not a service key");
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::{Result, SecretKind, SecretStore};
+
+type Secrets = HashMap<(SecretKind, String), Vec<u8>>;
+
+/// Keys kept in memory, lost when the last clone of the store is dropped.
+/// Useful for tests, and for embedders that keep keys elsewhere.
+#[derive(Clone, Default)]
+pub struct MemorySecretStore {
+    secrets: Arc<Mutex<Secrets>>,
+}
+
+impl MemorySecretStore {
+    pub fn new() -> MemorySecretStore {
+        MemorySecretStore::default()
+    }
+}
+
+impl SecretStore for MemorySecretStore {
+    fn get(&self, kind: SecretKind, name: &str) -> Result<Option<Vec<u8>>> {
+        let secrets = self.secrets.lock().unwrap();
+        Ok(secrets.get(&(kind, name.to_string())).cloned())
+    }
+
+    fn put(&mut self, kind: SecretKind, name: &str, secret: &[u8]) -> Result<()> {
+        let mut secrets = self.secrets.lock().unwrap();
+        secrets.insert((kind, name.to_string()), secret.to_vec());
+        Ok(())
+    }
+
+    fn delete(&mut self, kind: SecretKind, name: &str) -> Result<Option<()>> {
+        let mut secrets = self.secrets.lock().unwrap();
+        Ok(secrets.remove(&(kind, name.to_string())).map(|_| ()))
+    }
+
+    fn list(&self, kind: SecretKind) -> Result<Vec<String>> {
+        let secrets = self.secrets.lock().unwrap();
+        let mut names: Vec<String> = secrets
+            .keys()
+            .filter(|(entry_kind, _)| *entry_kind == kind)
+            .map(|(_, name)| name.clone())
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::SecretsError;
+
+    #[test]
+    fn test_memory_store() {
+        let mut store = MemorySecretStore::new();
+        let service_key = store.ensure_service("web").unwrap();
+        assert_eq!(store.ensure_service("web").unwrap(), service_key);
+        let client_key = store.ensure_client("alice").unwrap();
+        store.ensure_client("bob").unwrap();
+
+        // Clones share their keys.
+        let clone = store.clone();
+        assert_eq!(clone.get_service("web").unwrap(), Some(service_key));
+        assert_eq!(clone.get_client("alice").unwrap(), Some(client_key));
+        assert_eq!(clone.list_services().unwrap(), vec!["web"]);
+        assert_eq!(clone.list_clients().unwrap(), vec!["alice", "bob"]);
+
+        assert_eq!(store.delete_client("bob").unwrap(), Some(()));
+        assert_eq!(store.delete_client("bob").unwrap(), None);
+        assert_eq!(clone.list_clients().unwrap(), vec!["alice"]);
+
+        store.put(SecretKind::Service, "short", &[0u8; 32]).unwrap();
+        assert!(matches!(
+            store.get_service("short"),
+            Err(SecretsError::InvalidKey { .. })
+        ));
+    }
+}
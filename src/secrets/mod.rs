@@ -0,0 +1,384 @@
+use std;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path;
+use std::{fmt, fs, io, result};
+
+use thiserror::Error;
+
+mod exec;
+mod file;
+mod format;
+mod memory;
+
+pub use exec::ExecSecretStore;
+pub use file::FileSecretStore;
+pub use memory::MemorySecretStore;
+
+#[derive(Error, Debug)]
+pub enum SecretsError {
+    #[error("i/o error: {0}")]
+    IO(#[from] io::Error),
+    #[error("service {0} already exists")]
+    ServiceExists(String),
+    #[error("{0} already contains a hidden service key")]
+    HsDirExists(String),
+    #[error("{path}: {reason}")]
+    InvalidHsDir { path: String, reason: String },
+    #[error("secret store is encrypted, a passphrase is needed to unlock it")]
+    Locked,
+    #[error("wrong passphrase for secret store")]
+    WrongPassphrase,
+    #[error("secret store is already encrypted")]
+    AlreadyEncrypted,
+    #[error("secret store is not encrypted")]
+    NotEncrypted,
+    #[error("failed to decrypt {0}")]
+    Decrypt(String),
+    #[error("invalid secret store parameters: {0}")]
+    Params(String),
+    #[error("{kind} key {name} has the wrong length")]
+    InvalidKey { kind: SecretKind, name: String },
+    #[error("secrets helper failed: {0}")]
+    Helper(String),
+    #[error("corrupt secret {path}: {reason}")]
+    Corrupt { path: String, reason: String },
+}
+
+pub type Result<T> = result::Result<T, SecretsError>;
+
+// Files of a C Tor HiddenServiceDir, each key prefixed with a 32 byte tag.
+const HS_SECRET_KEY_FILE: &str = "hs_ed25519_secret_key";
+const HS_PUBLIC_KEY_FILE: &str = "hs_ed25519_public_key";
+const HS_HOSTNAME_FILE: &str = "hostname";
+const HS_SECRET_KEY_TAG: &[u8; 32] = b"== ed25519v1-secret: type0 ==\0\0\0";
+const HS_PUBLIC_KEY_TAG: &[u8; 32] = b"== ed25519v1-public: type0 ==\0\0\0";
+
+/// The kinds of key kept in a secret store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SecretKind {
+    /// An onion service's ed25519 secret key, 64 bytes.
+    Service,
+    /// A client authorization x25519 secret key, 32 bytes.
+    Client,
+}
+
+impl SecretKind {
+    /// The length of a key of this kind.
+    pub fn key_len(&self) -> usize {
+        match self {
+            SecretKind::Service => 64,
+            SecretKind::Client => 32,
+        }
+    }
+}
+
+impl fmt::Display for SecretKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretKind::Service => write!(f, "service"),
+            SecretKind::Client => write!(f, "client"),
+        }
+    }
+}
+
+impl std::str::FromStr for SecretKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "service" => Ok(SecretKind::Service),
+            "client" => Ok(SecretKind::Client),
+            _ => Err(format!("unknown secret kind {}", s)),
+        }
+    }
+}
+
+/// Where service and client keys are kept, by name.
+///
+/// Backends implement the raw storage of keys; the provided methods check
+/// key lengths and generate new keys on top of it.
+pub trait SecretStore: Send {
+    /// The secret `kind` named `name`, if there is one.
+    fn get(&self, kind: SecretKind, name: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Store `secret` as `kind` named `name`, replacing any existing one.
+    fn put(&mut self, kind: SecretKind, name: &str, secret: &[u8]) -> Result<()>;
+
+    /// Remove the secret `kind` named `name`, `None` if there was none.
+    fn delete(&mut self, kind: SecretKind, name: &str) -> Result<Option<()>>;
+
+    /// The names of all secrets of `kind`.
+    fn list(&self, kind: SecretKind) -> Result<Vec<String>>;
+
+    fn get_service(&self, name: &str) -> Result<Option<[u8; 64]>> {
+        get_key(self, SecretKind::Service, name)
+    }
+
+    fn ensure_service(&mut self, name: &str) -> Result<[u8; 64]> {
+        if let Some(key) = self.get_service(name)? {
+            return Ok(key);
+        }
+        let key = torut::onion::TorSecretKeyV3::generate().as_bytes();
+        self.put(SecretKind::Service, name, &key)?;
+        Ok(key)
+    }
+
+    /// Add service `name` with the key of the C Tor HiddenServiceDir
+    /// `hs_dir`, keeping its onion address.
+    fn import_hs_dir(&mut self, name: &str, hs_dir: &path::Path) -> Result<[u8; 64]> {
+        if self.get_service(name)?.is_some() {
+            return Err(SecretsError::ServiceExists(name.to_string()));
+        }
+        let key = read_hs_dir(hs_dir)?;
+        self.put(SecretKind::Service, name, &key)?;
+        Ok(key)
+    }
+
+    /// Write the key of service `name` to `hs_dir` as a C Tor
+    /// HiddenServiceDir.
+    fn export_hs_dir(&self, name: &str, hs_dir: &path::Path) -> Result<Option<[u8; 64]>> {
+        match self.get_service(name)? {
+            Some(key) => {
+                write_hs_dir(hs_dir, &key)?;
+                Ok(Some(key))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn delete_service(&mut self, name: &str) -> Result<Option<()>> {
+        self.delete(SecretKind::Service, name)
+    }
+
+    fn list_services(&self) -> Result<Vec<String>> {
+        self.list(SecretKind::Service)
+    }
+
+    fn get_client(&self, name: &str) -> Result<Option<[u8; 32]>> {
+        get_key(self, SecretKind::Client, name)
+    }
+
+    fn ensure_client(&mut self, name: &str) -> Result<[u8; 32]> {
+        if let Some(key) = self.get_client(name)? {
+            return Ok(key);
+        }
+        let key = *crypto_box::SecretKey::generate(&mut crypto_box::aead::OsRng).as_bytes();
+        self.put(SecretKind::Client, name, &key)?;
+        Ok(key)
+    }
+
+    fn delete_client(&mut self, name: &str) -> Result<Option<()>> {
+        self.delete(SecretKind::Client, name)
+    }
+
+    fn list_clients(&self) -> Result<Vec<String>> {
+        self.list(SecretKind::Client)
+    }
+}
+
+fn get_key<S: SecretStore + ?Sized, const N: usize>(
+    store: &S,
+    kind: SecretKind,
+    name: &str,
+) -> Result<Option<[u8; N]>> {
+    match store.get(kind, name)? {
+        Some(secret) => Ok(Some(secret.try_into().map_err(|_| {
+            SecretsError::InvalidKey {
+                kind,
+                name: name.to_string(),
+            }
+        })?)),
+        None => Ok(None),
+    }
+}
+
+/// Read the service key of a C Tor HiddenServiceDir, checking it against the
+/// public key and hostname found alongside.
+pub fn read_hs_dir(hs_dir: &path::Path) -> Result<[u8; 64]> {
+    let secret_file = hs_dir.join(HS_SECRET_KEY_FILE);
+    let contents = fs::read(&secret_file)?;
+    let key: [u8; 64] = match contents.split_at_checked(HS_SECRET_KEY_TAG.len()) {
+        Some((tag, key)) if tag == HS_SECRET_KEY_TAG => key
+            .try_into()
+            .map_err(|_| invalid_hs_dir(&secret_file, "wrong key length"))?,
+        _ => return Err(invalid_hs_dir(&secret_file, "not an ed25519v1 secret key")),
+    };
+    let public_key = torut::onion::TorSecretKeyV3::from(key).public();
+
+    let public_file = hs_dir.join(HS_PUBLIC_KEY_FILE);
+    if public_file.exists() {
+        let contents = fs::read(&public_file)?;
+        match contents.split_at_checked(HS_PUBLIC_KEY_TAG.len()) {
+            Some((tag, public)) if tag == HS_PUBLIC_KEY_TAG => {
+                if public != public_key.as_bytes() {
+                    return Err(invalid_hs_dir(
+                        &public_file,
+                        "does not match the secret key",
+                    ));
+                }
+            }
+            _ => return Err(invalid_hs_dir(&public_file, "not an ed25519v1 public key")),
+        }
+    }
+
+    let hostname_file = hs_dir.join(HS_HOSTNAME_FILE);
+    if hostname_file.exists() {
+        let hostname = fs::read_to_string(&hostname_file)?;
+        if hostname.trim() != public_key.get_onion_address().to_string() {
+            return Err(invalid_hs_dir(
+                &hostname_file,
+                "does not match the secret key",
+            ));
+        }
+    }
+    Ok(key)
+}
+
+/// Write a service key as a C Tor HiddenServiceDir, which Tor requires to be
+/// private to its user. An existing key in `hs_dir` is not replaced, and
+/// nothing is left behind should writing fail.
+pub fn write_hs_dir(hs_dir: &path::Path, key: &[u8; 64]) -> Result<()> {
+    let secret_file = hs_dir.join(HS_SECRET_KEY_FILE);
+    if secret_file.exists() {
+        return Err(SecretsError::HsDirExists(hs_dir.display().to_string()));
+    }
+    let created = !hs_dir.exists();
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(hs_dir)?;
+    // The mode above only applies to a directory that didn't exist.
+    fs::set_permissions(hs_dir, fs::Permissions::from_mode(0o700))?;
+    let public_key = torut::onion::TorSecretKeyV3::from(*key).public();
+    let files = [
+        (secret_file, [&HS_SECRET_KEY_TAG[..], key].concat()),
+        (
+            hs_dir.join(HS_PUBLIC_KEY_FILE),
+            [&HS_PUBLIC_KEY_TAG[..], public_key.as_bytes()].concat(),
+        ),
+        (
+            hs_dir.join(HS_HOSTNAME_FILE),
+            format!("{}\n", public_key.get_onion_address()).into_bytes(),
+        ),
+    ];
+    for (i, (file, contents)) in files.iter().enumerate() {
+        if let Err(err) = write_private(file, contents) {
+            for (written, _) in &files[..i] {
+                let _ = fs::remove_file(written);
+            }
+            if created {
+                let _ = fs::remove_dir(hs_dir);
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Write a new file only its owner can read, removing it if writing fails.
+fn write_private(file: &path::Path, contents: &[u8]) -> Result<()> {
+    use std::io::Write;
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(file)?;
+    if let Err(err) = f.write_all(contents) {
+        let _ = fs::remove_file(file);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+fn invalid_hs_dir(file: &path::Path, reason: &str) -> SecretsError {
+    SecretsError::InvalidHsDir {
+        path: file.display().to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_hs_dir() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let key = [7u8; 64];
+
+        // An existing directory is made private.
+        let hs_dir = tmp_dir.path().join("hs");
+        fs::create_dir(&hs_dir).unwrap();
+        fs::set_permissions(&hs_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        write_hs_dir(&hs_dir, &key).unwrap();
+        assert_eq!(
+            fs::metadata(&hs_dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+
+        // Files written before a failure are removed, leaving what was there.
+        let hs_dir = tmp_dir.path().join("partial");
+        fs::create_dir(&hs_dir).unwrap();
+        fs::write(hs_dir.join(HS_HOSTNAME_FILE), "taken\n").unwrap();
+        assert!(matches!(
+            write_hs_dir(&hs_dir, &key),
+            Err(SecretsError::IO(_))
+        ));
+        assert!(!hs_dir.join(HS_SECRET_KEY_FILE).exists());
+        assert!(!hs_dir.join(HS_PUBLIC_KEY_FILE).exists());
+        assert_eq!(
+            fs::read_to_string(hs_dir.join(HS_HOSTNAME_FILE)).unwrap(),
+            "taken\n"
+        );
+    }
+
+    #[test]
+    fn test_hs_dir() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let secrets_dir = tmp_dir.path().join("secrets");
+        let hs_dir = tmp_dir.path().join("hs");
+        let mut store = FileSecretStore::new(secrets_dir.to_str().unwrap());
+        let key = store.ensure_service("test").unwrap();
+        assert_eq!(store.export_hs_dir("test", &hs_dir).unwrap(), Some(key));
+        assert_eq!(store.export_hs_dir("nope", &hs_dir).unwrap(), None);
+        let onion_addr = torut::onion::TorSecretKeyV3::from(key)
+            .public()
+            .get_onion_address();
+        assert_eq!(
+            fs::read_to_string(hs_dir.join(HS_HOSTNAME_FILE)).unwrap(),
+            format!("{}\n", onion_addr)
+        );
+        assert_eq!(
+            fs::metadata(&hs_dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        assert!(matches!(
+            store.export_hs_dir("test", &hs_dir),
+            Err(SecretsError::HsDirExists(_))
+        ));
+
+        assert_eq!(store.import_hs_dir("copy", &hs_dir).unwrap(), key);
+        assert_eq!(store.get_service("copy").unwrap(), Some(key));
+        assert!(matches!(
+            store.import_hs_dir("copy", &hs_dir),
+            Err(SecretsError::ServiceExists(_))
+        ));
+
+        // Keys that don't agree with the hostname, or lack the tag, are refused.
+        let other_addr = torut::onion::TorSecretKeyV3::generate()
+            .public()
+            .get_onion_address();
+        fs::write(hs_dir.join(HS_HOSTNAME_FILE), format!("{}\n", other_addr)).unwrap();
+        assert!(matches!(
+            read_hs_dir(&hs_dir),
+            Err(SecretsError::InvalidHsDir { .. })
+        ));
+        fs::remove_file(hs_dir.join(HS_HOSTNAME_FILE)).unwrap();
+        assert_eq!(read_hs_dir(&hs_dir).unwrap(), key);
+        fs::write(hs_dir.join(HS_SECRET_KEY_FILE), &key[..]).unwrap();
+        assert!(matches!(
+            read_hs_dir(&hs_dir),
+            Err(SecretsError::InvalidHsDir { .. })
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+use std::io;
+
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Minimal SOCKS5 client for connecting to onions through Tor's SocksPort.
+// Tor's extended error codes (SocksPort ExtendedErrors) are surfaced so that
+// callers can tell why an onion connection failed.
+
+#[derive(Error, Debug)]
+pub enum SocksError {
+    #[error("i/o error: {0}")]
+    IO(#[from] io::Error),
+    #[error("invalid socks response: {0}")]
+    Protocol(&'static str),
+    #[error("invalid target address: {0}")]
+    Target(String),
+    #[error("onion service descriptor not found; it may not be published yet")]
+    DescriptorNotFound,
+    #[error("onion service descriptor is invalid")]
+    DescriptorInvalid,
+    #[error("onion service introduction failed; its introduction points are unreachable")]
+    IntroFailed,
+    #[error("onion service rendezvous failed")]
+    RendezvousFailed,
+    #[error("onion service requires client authorization")]
+    MissingClientAuth,
+    #[error("onion service client authorization was rejected")]
+    BadClientAuth,
+    #[error("invalid onion address")]
+    BadAddress,
+    #[error("onion service introduction timed out; its introduction points are unreachable")]
+    IntroTimeout,
+    #[error("socks connect failed with reply code {0:#04x}")]
+    Reply(u8),
+}
+
+impl SocksError {
+    pub fn is_client_auth(&self) -> bool {
+        matches!(
+            self,
+            SocksError::MissingClientAuth | SocksError::BadClientAuth
+        )
+    }
+
+    /// Whether the onion may be reachable later: its descriptor may still be
+    /// propagating, or its introduction points may come back.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            SocksError::IO(_)
+                | SocksError::DescriptorNotFound
+                | SocksError::IntroFailed
+                | SocksError::RendezvousFailed
+                | SocksError::IntroTimeout
+                | SocksError::Reply(_)
+        )
+    }
+
+    fn from_reply(code: u8) -> SocksError {
+        match code {
+            0xf0 => SocksError::DescriptorNotFound,
+            0xf1 => SocksError::DescriptorInvalid,
+            0xf2 => SocksError::IntroFailed,
+            0xf3 => SocksError::RendezvousFailed,
+            0xf4 => SocksError::MissingClientAuth,
+            0xf5 => SocksError::BadClientAuth,
+            0xf6 => SocksError::BadAddress,
+            0xf7 => SocksError::IntroTimeout,
+            code => SocksError::Reply(code),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, SocksError>;
+
+/// Perform a SOCKS5 CONNECT to `host:port` over `stream`, returning the
+/// stream ready to carry the proxied connection.
+pub async fn connect<S>(mut stream: S, host: &str, port: u16) -> Result<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if host.is_empty() || host.len() > 255 {
+        return Err(SocksError::Target(host.to_string()));
+    }
+
+    // Greeting, offering no authentication.
+    stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 {
+        return Err(SocksError::Protocol("version"));
+    }
+    if reply[1] != 0x00 {
+        return Err(SocksError::Protocol("no acceptable auth methods"));
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 {
+        return Err(SocksError::Protocol("version"));
+    }
+    if reply[1] != 0x00 {
+        return Err(SocksError::from_reply(reply[1]));
+    }
+    let addr_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        _ => return Err(SocksError::Protocol("address type")),
+    };
+    // Discard the bound address and port.
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn serve_reply(code: u8) -> Result<tokio::io::DuplexStream> {
+        let (client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut greeting = [0u8; 3];
+            server.read_exact(&mut greeting).await.unwrap();
+            server.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut header = [0u8; 5];
+            server.read_exact(&mut header).await.unwrap();
+            let mut rest = vec![0u8; header[4] as usize + 2];
+            server.read_exact(&mut rest).await.unwrap();
+            server
+                .write_all(&[0x05, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            server.write_all(b"hello").await.unwrap();
+        });
+        connect(client, "xyz.onion", 80).await
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let mut stream = serve_reply(0x00).await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_connect_extended_errors() {
+        let err = serve_reply(0xf4).await.unwrap_err();
+        assert!(matches!(err, SocksError::MissingClientAuth));
+        assert!(err.is_client_auth());
+        let err = serve_reply(0xf0).await.unwrap_err();
+        assert!(matches!(err, SocksError::DescriptorNotFound));
+        assert!(!err.is_client_auth());
+        let err = serve_reply(0x04).await.unwrap_err();
+        assert!(matches!(err, SocksError::Reply(0x04)));
+    }
+}
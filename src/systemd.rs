@@ -0,0 +1,337 @@
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use crate::{ForwardHandle, Health, LocalAddr, OnionPipeEvent, PipeError, Result};
+
+// Integration with systemd services: readiness and status notifications, the
+// service watchdog, and sockets passed in by socket activation. See
+// sd_notify(3) and sd_listen_fds(3).
+
+/// The first file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// How often readiness is checked while starting up.
+const READY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sends notifications to the service manager over `$NOTIFY_SOCKET`.
+#[derive(Clone)]
+pub struct Notifier {
+    socket: Arc<UnixDatagram>,
+    addr: std::os::unix::net::SocketAddr,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// The notifier of the service manager that started this process, if any.
+    pub fn from_env() -> Option<Notifier> {
+        let notify_socket = std::env::var_os("NOTIFY_SOCKET")?;
+        let watchdog = watchdog_interval(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        );
+        match Notifier::new(notify_socket.to_string_lossy().as_ref(), watchdog) {
+            Ok(notifier) => Some(notifier),
+            Err(err) => {
+                tracing::warn!("cannot notify systemd: {}", err);
+                None
+            }
+        }
+    }
+
+    fn new(notify_socket: &str, watchdog: Option<Duration>) -> std::io::Result<Notifier> {
+        let addr = match notify_socket.strip_prefix('@') {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                std::os::unix::net::SocketAddr::from_abstract_name(name)?
+            }
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            Some(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "abstract notify socket",
+                ))
+            }
+            None => std::os::unix::net::SocketAddr::from_pathname(notify_socket)?,
+        };
+        Ok(Notifier {
+            socket: Arc::new(UnixDatagram::unbound()?),
+            addr,
+            watchdog,
+        })
+    }
+
+    /// Send `state`, newline-separated assignments such as `READY=1`.
+    pub fn notify(&self, state: &str) {
+        if let Err(err) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            tracing::debug!("failed to notify systemd: {}", err);
+        }
+    }
+
+    /// How often to ping the watchdog: half its timeout, as systemd advises.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|timeout| timeout / 2)
+    }
+}
+
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+    match usec?.parse::<u64>().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// Keep the service manager informed about the pipe behind `forwards`.
+///
+/// Bootstrap progress is reported as the service status, and `READY=1` is sent
+/// once the pipe is ready, as in [`Health::check`]. If the watchdog is enabled,
+/// it is pinged for as long as the pipe keeps answering.
+pub async fn supervise(
+    notifier: Notifier,
+    forwards: ForwardHandle,
+    mut events: broadcast::Receiver<OnionPipeEvent>,
+) {
+    let mut watchdog = notifier.watchdog_interval().map(tokio::time::interval);
+    let mut ready_check = tokio::time::interval(READY_CHECK_INTERVAL);
+    let mut ready = false;
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(OnionPipeEvent::Bootstrap { phase }) if !ready => notifier.notify(&format!(
+                    "STATUS=Bootstrapping Tor {}%: {}",
+                    phase.progress, phase.summary
+                )),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = ready_check.tick(), if !ready => {
+                let health = Health::check(&forwards).await;
+                if health.ready {
+                    ready = true;
+                    notifier.notify(&format!(
+                        "READY=1\nSTATUS=Serving {} forwards",
+                        health.forwards.len()
+                    ));
+                }
+            }
+            Some(_) = async { Some(watchdog.as_mut()?.tick().await) } => {
+                // Tor may take a while to bootstrap, so only a pipe which has
+                // started is expected to answer.
+                let timeout = notifier.watchdog_interval().unwrap_or_default();
+                if !ready || tokio::time::timeout(timeout, forwards.forwards()).await.is_ok() {
+                    notifier.notify("WATCHDOG=1");
+                } else {
+                    tracing::warn!("onionpipe is not responding, skipping watchdog ping");
+                }
+            }
+        }
+    }
+}
+
+/// Listening sockets passed in by socket activation, which imports use instead
+/// of binding their local address.
+#[derive(Default)]
+pub struct ListenFds {
+    sockets: Mutex<Vec<ListenFd>>,
+}
+
+struct ListenFd {
+    name: String,
+    fd: OwnedFd,
+}
+
+impl ListenFds {
+    /// Take ownership of the sockets passed to this process in `$LISTEN_FDS`,
+    /// named by `$LISTEN_FDNAMES`. The variables are then unset, so that they
+    /// are not inherited by any child process.
+    ///
+    /// This modifies the environment, so it must be called before any other
+    /// threads are started, such as those of a tokio runtime.
+    pub fn from_env() -> ListenFds {
+        let count = listen_fds_count(
+            std::env::var("LISTEN_PID").ok().as_deref(),
+            std::env::var("LISTEN_FDS").ok().as_deref(),
+            std::process::id(),
+        );
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(var);
+        }
+        let mut names = names.split(':');
+        let fds = (LISTEN_FDS_START..LISTEN_FDS_START + count as RawFd)
+            .map(|raw_fd| {
+                unsafe { libc::fcntl(raw_fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+                let name = names.next().unwrap_or("unknown").to_string();
+                (name, unsafe { OwnedFd::from_raw_fd(raw_fd) })
+            })
+            .collect();
+        ListenFds::new(fds)
+    }
+
+    pub(crate) fn new(fds: Vec<(String, OwnedFd)>) -> ListenFds {
+        ListenFds {
+            sockets: Mutex::new(
+                fds.into_iter()
+                    .map(|(name, fd)| ListenFd { name, fd })
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sockets.lock().unwrap().is_empty()
+    }
+
+    /// Take the passed stream socket for an import of `local_addr`: the one
+    /// named `name` if given, which must exist, or else the one bound to
+    /// `local_addr`, if there is one.
+    pub(crate) fn take(
+        &self,
+        local_addr: &LocalAddr,
+        name: Option<&str>,
+    ) -> Result<Option<OwnedFd>> {
+        let mut sockets = self.sockets.lock().unwrap();
+        let found = sockets.iter().position(|socket| {
+            let sock_ref = socket2::SockRef::from(&socket.fd);
+            if !matches!(sock_ref.r#type(), Ok(socket2::Type::STREAM)) {
+                return false;
+            }
+            let Ok(bound_addr) = sock_ref.local_addr() else {
+                return false;
+            };
+            match (name, local_addr) {
+                (Some(name), LocalAddr::TCP(_)) => {
+                    socket.name == name && bound_addr.as_socket().is_some()
+                }
+                (Some(name), LocalAddr::Unix(_)) => socket.name == name && bound_addr.is_unix(),
+                (None, LocalAddr::TCP(addr)) => bound_addr.as_socket() == Some(*addr),
+                (None, LocalAddr::Unix(path)) => bound_addr.as_pathname() == Some(path.as_path()),
+            }
+        });
+        let i = match (found, name) {
+            (Some(i), _) => i,
+            (None, Some(name)) => {
+                return Err(PipeError::Config(format!(
+                    "no stream socket named {} for {} was passed by systemd",
+                    name, local_addr
+                )))
+            }
+            (None, None) => return Ok(None),
+        };
+        let socket = sockets.remove(i);
+        tracing::info!(
+            "using socket {} passed by systemd for {}",
+            socket.name,
+            local_addr
+        );
+        Ok(Some(socket.fd))
+    }
+
+    /// The names of the passed sockets that have not been taken.
+    pub(crate) fn untaken(&self) -> Vec<String> {
+        let sockets = self.sockets.lock().unwrap();
+        sockets.iter().map(|socket| socket.name.clone()).collect()
+    }
+}
+
+fn listen_fds_count(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> usize {
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(own_pid) {
+        return 0;
+    }
+    fds.and_then(|fds| fds.parse().ok()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_parsing() {
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("7"), 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval(None, None, 42), None);
+
+        assert_eq!(listen_fds_count(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fds_count(Some("7"), Some("2"), 42), 0);
+        assert_eq!(listen_fds_count(None, Some("2"), 42), 0);
+        assert_eq!(listen_fds_count(Some("42"), Some("x"), 42), 0);
+    }
+
+    #[test]
+    fn notify() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let socket_path = tmp_dir.path().join("notify.sock");
+        let receiver = UnixDatagram::bind(&socket_path).unwrap();
+        let notifier =
+            Notifier::new(socket_path.to_str().unwrap(), Some(Duration::from_secs(10))).unwrap();
+        assert_eq!(notifier.watchdog_interval(), Some(Duration::from_secs(5)));
+        notifier.notify("READY=1");
+        let mut buf = [0u8; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn take_listen_fds() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let socket_path = tmp_dir.path().join("import.sock");
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let unix = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+        let listen_fds = ListenFds::new(vec![
+            ("web".to_string(), tcp.into()),
+            ("ssh".to_string(), unix.into()),
+        ]);
+
+        let other_addr = LocalAddr::TCP("127.0.0.1:1".parse().unwrap());
+        assert!(listen_fds.take(&other_addr, None).unwrap().is_none());
+        let fd = listen_fds.take(&LocalAddr::TCP(tcp_addr), None).unwrap();
+        let tcp = std::net::TcpListener::from(fd.unwrap());
+        assert_eq!(tcp.local_addr().unwrap(), tcp_addr);
+        assert!(listen_fds
+            .take(&LocalAddr::TCP(tcp_addr), None)
+            .unwrap()
+            .is_none());
+        assert_eq!(listen_fds.untaken(), vec!["ssh"]);
+        assert!(listen_fds
+            .take(&LocalAddr::Unix(socket_path), None)
+            .unwrap()
+            .is_some());
+        assert!(listen_fds.is_empty());
+
+        // A socket may be chosen by name instead, whatever it is bound to.
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let listen_fds = ListenFds::new(vec![("web".to_string(), tcp.into())]);
+        let unix_addr = LocalAddr::Unix(tmp_dir.path().join("web.sock"));
+        assert!(matches!(
+            listen_fds.take(&unix_addr, Some("web")),
+            Err(PipeError::Config(_))
+        ));
+        assert!(matches!(
+            listen_fds.take(&other_addr, Some("ssh")),
+            Err(PipeError::Config(_))
+        ));
+        let fd = listen_fds.take(&other_addr, Some("web")).unwrap().unwrap();
+        let tcp = std::net::TcpListener::from(fd);
+        assert_eq!(tcp.local_addr().unwrap(), tcp_addr);
+    }
+}
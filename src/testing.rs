@@ -0,0 +1,1010 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::UnixListener;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use torut::onion;
+
+use crate::{backend, control, ControlAuth, LocalAddr, Result, TorBackend};
+
+// FakeTor stands in for a bootstrapped Tor, so that pipes can be tested
+// end-to-end without a network. Its control port understands the commands
+// onionpipe sends, and its SOCKS port connects `<service id>.onion:port`
+// straight to the target of an onion added on the control port, enforcing
+// client authorization the way Tor would.
+
+/// An in-process fake Tor, serving control and SOCKS ports on unix sockets.
+/// It stops when dropped.
+pub struct FakeTor {
+    dir: tempfile::TempDir,
+    state: Arc<Mutex<State>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct State {
+    onions: HashMap<String, Onion>,
+    /// Client keys added with ONION_CLIENT_AUTH_ADD, by service ID.
+    client_keys: HashMap<String, crypto_box::SecretKey>,
+    /// Bytes relayed from and to SOCKS clients, reported as Tor's traffic.
+    read: u64,
+    written: u64,
+}
+
+struct Onion {
+    ports: HashMap<u16, LocalAddr>,
+    authorized_clients: Vec<crypto_box::PublicKey>,
+}
+
+impl FakeTor {
+    pub fn start() -> Result<FakeTor> {
+        let dir = tempfile::tempdir()?;
+        let control_listener = UnixListener::bind(dir.path().join("control.sock"))?;
+        let socks_listener = UnixListener::bind(dir.path().join("socks.sock"))?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let (hs_desc, _) = broadcast::channel(64);
+        let tasks = vec![
+            tokio::spawn(serve_control(control_listener, state.clone(), hs_desc)),
+            tokio::spawn(serve_socks(socks_listener, state.clone())),
+        ];
+        Ok(FakeTor { dir, state, tasks })
+    }
+
+    pub fn control_addr(&self) -> LocalAddr {
+        LocalAddr::Unix(self.dir.path().join("control.sock"))
+    }
+
+    pub fn socks_addr(&self) -> LocalAddr {
+        LocalAddr::Unix(self.dir.path().join("socks.sock"))
+    }
+
+    /// A backend that uses this Tor.
+    pub fn backend(&self) -> TorBackend {
+        TorBackend::External {
+            control_addr: self.control_addr(),
+            socks_addr: self.socks_addr(),
+            auth: ControlAuth::Auto,
+        }
+    }
+
+    /// The onions currently added, in no particular order.
+    pub fn onions(&self) -> Vec<onion::OnionAddressV3> {
+        self.state
+            .lock()
+            .unwrap()
+            .onions
+            .keys()
+            .filter_map(|service_id| onion::OnionAddressV3::from_str(service_id).ok())
+            .collect()
+    }
+
+    /// Where an added onion sends the connections to each of its ports.
+    pub fn targets(&self, onion_addr: &onion::OnionAddressV3) -> HashMap<u16, LocalAddr> {
+        let service_id = onion_addr.get_address_without_dot_onion();
+        let state = self.state.lock().unwrap();
+        state
+            .onions
+            .get(&service_id)
+            .map(|onion| onion.ports.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for FakeTor {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+    }
+}
+
+async fn serve_control(
+    listener: UnixListener,
+    state: Arc<Mutex<State>>,
+    hs_desc: broadcast::Sender<String>,
+) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let state = state.clone();
+        let hs_desc = hs_desc.clone();
+        tokio::spawn(async move {
+            let mut conn = ControlSession {
+                state,
+                hs_desc,
+                authenticated: false,
+                added: HashSet::new(),
+            };
+            let _ = conn.serve(stream).await;
+            // Like Tor, remove the onions a connection added when it closes.
+            let mut state = conn.state.lock().unwrap();
+            for service_id in conn.added.iter() {
+                state.onions.remove(service_id);
+            }
+        });
+    }
+}
+
+struct ControlSession {
+    state: Arc<Mutex<State>>,
+    hs_desc: broadcast::Sender<String>,
+    authenticated: bool,
+    added: HashSet<String>,
+}
+
+impl ControlSession {
+    async fn serve<S>(&mut self, stream: S) -> std::io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let (read, mut write) = tokio::io::split(stream);
+        let mut lines = BufReader::new(read).lines();
+        let mut events: Option<broadcast::Receiver<String>> = None;
+        loop {
+            let event = async {
+                match events.as_mut() {
+                    Some(events) => events.recv().await.ok(),
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                line = lines.next_line() => {
+                    let line = match line? {
+                        Some(line) => line,
+                        None => return Ok(()),
+                    };
+                    let reply = self.command(line.trim_end(), &mut events);
+                    write.write_all(reply.as_bytes()).await?;
+                }
+                Some(event) = event => {
+                    write.write_all(format!("650 {}\r\n", event).as_bytes()).await?;
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str, events: &mut Option<broadcast::Receiver<String>>) -> String {
+        let (keyword, args) = line.split_once(' ').unwrap_or((line, ""));
+        match keyword {
+            "PROTOCOLINFO" => "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n250-VERSION Tor=\"0.4.7.13\"\r\n250 OK\r\n".to_string(),
+            "AUTHENTICATE" => {
+                self.authenticated = true;
+                ok()
+            }
+            _ if !self.authenticated => "514 Authentication required.\r\n".to_string(),
+            "TAKEOWNERSHIP" => ok(),
+            "GETINFO" if args == "status/bootstrap-phase" => "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"\r\n250 OK\r\n".to_string(),
+            "GETINFO" if args == "traffic/read" || args == "traffic/written" => {
+                let state = self.state.lock().unwrap();
+                let bytes = if args == "traffic/read" {
+                    state.read
+                } else {
+                    state.written
+                };
+                format!("250-{}={}\r\n250 OK\r\n", args, bytes)
+            }
+            "GETINFO" => format!("552 Unrecognized key \"{}\"\r\n", args),
+            "SETEVENTS" => {
+                let mut reply = ok();
+                if args.split(' ').any(|event| event == "HS_DESC") {
+                    *events = Some(self.hs_desc.subscribe());
+                    // Descriptors are "uploaded" as soon as an onion is added,
+                    // so report those added before this subscription too.
+                    for service_id in self.state.lock().unwrap().onions.keys() {
+                        for event in uploaded(service_id) {
+                            reply.push_str(&format!("650 {}\r\n", event));
+                        }
+                    }
+                } else {
+                    *events = None;
+                }
+                reply
+            }
+            "ADD_ONION" => self.add_onion(args),
+            "DEL_ONION" => {
+                self.added.remove(args);
+                match self.state.lock().unwrap().onions.remove(args) {
+                    Some(_) => ok(),
+                    None => "552 Unknown Onion Service id\r\n".to_string(),
+                }
+            }
+            "ONION_CLIENT_AUTH_ADD" => {
+                let key = args.split_once(' ').and_then(|(service_id, key)| {
+                    let key: [u8; 32] = base64::decode(key.strip_prefix("x25519:")?)
+                        .ok()?
+                        .try_into()
+                        .ok()?;
+                    Some((service_id.to_string(), crypto_box::SecretKey::from(key)))
+                });
+                match key {
+                    Some((service_id, key)) => {
+                        self.state.lock().unwrap().client_keys.insert(service_id, key);
+                        ok()
+                    }
+                    None => "512 Invalid key\r\n".to_string(),
+                }
+            }
+            _ => format!("510 Unrecognized command \"{}\"\r\n", keyword),
+        }
+    }
+
+    fn add_onion(&mut self, args: &str) -> String {
+        let (key, onion) = match parse_add_onion(args) {
+            Some(parsed) => parsed,
+            None => return "512 Invalid ADD_ONION arguments\r\n".to_string(),
+        };
+        let service_id = key
+            .public()
+            .get_onion_address()
+            .get_address_without_dot_onion();
+        let mut state = self.state.lock().unwrap();
+        if state.onions.contains_key(&service_id) {
+            return "550 Onion address collision\r\n".to_string();
+        }
+        state.onions.insert(service_id.clone(), onion);
+        for event in uploaded(&service_id) {
+            let _ = self.hs_desc.send(event);
+        }
+        self.added.insert(service_id.clone());
+        format!("250-ServiceID={}\r\n250 OK\r\n", service_id)
+    }
+}
+
+fn ok() -> String {
+    "250 OK\r\n".to_string()
+}
+
+fn uploaded(service_id: &str) -> [String; 2] {
+    [
+        format!("HS_DESC UPLOAD {} UNKNOWN $FAKE", service_id),
+        format!("HS_DESC UPLOADED {} UNKNOWN $FAKE", service_id),
+    ]
+}
+
+fn parse_add_onion(args: &str) -> Option<(onion::TorSecretKeyV3, Onion)> {
+    let mut args = args.split(' ');
+    let key: [u8; 64] = base64::decode(args.next()?.strip_prefix("ED25519-V3:")?)
+        .ok()?
+        .try_into()
+        .ok()?;
+    let mut onion = Onion {
+        ports: HashMap::new(),
+        authorized_clients: vec![],
+    };
+    for arg in args {
+        let (name, value) = arg.split_once('=')?;
+        match name {
+            "Port" => {
+                let (port, target) = match value.split_once(',') {
+                    Some((port, target)) => (port.parse().ok()?, target.parse().ok()?),
+                    None => {
+                        let port = value.parse().ok()?;
+                        (port, LocalAddr::TCP(([127, 0, 0, 1], port).into()))
+                    }
+                };
+                onion.ports.insert(port, target);
+            }
+            "ClientAuthV3" => onion
+                .authorized_clients
+                .push(crypto_box::PublicKey::from(control::decode_x25519(value)?)),
+            _ => {}
+        }
+    }
+    Some((onion::TorSecretKeyV3::from(key), onion))
+}
+
+async fn serve_socks(listener: UnixListener, state: Arc<Mutex<State>>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(serve_socks_conn(stream, state.clone()));
+    }
+}
+
+async fn serve_socks_conn<S>(mut stream: S, state: Arc<Mutex<State>>) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    stream.write_all(&[0x05, 0x00]).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[3] != 0x03 {
+        // Only hostnames are supported.
+        return reply(&mut stream, 0x08).await;
+    }
+    let mut host = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut host).await?;
+    let port = stream.read_u16().await?;
+
+    let host = String::from_utf8_lossy(&host).to_lowercase();
+    let target = match route(&state, &host, port) {
+        Ok(target) => target,
+        Err(code) => return reply(&mut stream, code).await,
+    };
+    let mut target_stream = match backend::connect(&target).await {
+        Ok(target_stream) => target_stream,
+        // Connection refused.
+        Err(_) => return reply(&mut stream, 0x05).await,
+    };
+    reply(&mut stream, 0x00).await?;
+    let (read, written) = tokio::io::copy_bidirectional(&mut stream, &mut target_stream).await?;
+    let mut state = state.lock().unwrap();
+    state.read += read;
+    state.written += written;
+    Ok(())
+}
+
+/// Find the target of `host:port`, or the SOCKS reply code explaining why
+/// there isn't one, using Tor's extended error codes.
+fn route(state: &Mutex<State>, host: &str, port: u16) -> std::result::Result<LocalAddr, u8> {
+    let service_id = match host.strip_suffix(".onion") {
+        Some(service_id) if onion::OnionAddressV3::from_str(service_id).is_ok() => service_id,
+        _ => return Err(0xf6),
+    };
+    let state = state.lock().unwrap();
+    let onion = state.onions.get(service_id).ok_or(0xf0u8)?;
+    if !onion.authorized_clients.is_empty() {
+        match state.client_keys.get(service_id) {
+            None => return Err(0xf4),
+            Some(key) if !onion.authorized_clients.contains(&key.public_key()) => return Err(0xf5),
+            Some(_) => {}
+        }
+    }
+    // The service closes streams to ports it doesn't listen on.
+    onion.ports.get(&port).cloned().ok_or(0x05)
+}
+
+async fn reply<S>(stream: &mut S, code: u8) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&[0x05, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+/// Log output captured by [`capture_logs`].
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct Logs(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Logs {
+    /// The lines logged so far, each as its level, target and message.
+    pub(crate) fn lines(&self) -> Vec<String> {
+        String::from_utf8_lossy(&self.0.lock().unwrap())
+            .lines()
+            .map(|line| line.trim().to_string())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Capture what is logged on this thread, until the guard is dropped.
+#[cfg(test)]
+pub(crate) fn capture_logs() -> (Logs, tracing::subscriber::DefaultGuard) {
+    let logs = Logs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .without_time()
+        .with_writer(move || writer.clone())
+        .finish();
+    (logs, tracing::subscriber::set_default(subscriber))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::systemd::ListenFds;
+    use crate::{
+        ConnectionOptions, Export, ForwardHandle, Health, Import, OnionPipe, OnionPipeBuilder,
+        OnionPipeEvent, RetryPolicy, ShutdownHandle, UnixSocketOptions,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Serve an echo service on a local TCP port.
+    async fn echo_server() -> LocalAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = LocalAddr::TCP(listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+        local_addr
+    }
+
+    /// Make a GET request over HTTP, returning the whole response.
+    async fn http_get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        response
+    }
+
+    async fn next_event<F, T>(events: &mut broadcast::Receiver<OnionPipeEvent>, f: F) -> T
+    where
+        F: Fn(OnionPipeEvent) -> Option<T>,
+    {
+        tokio::time::timeout(TIMEOUT, async {
+            loop {
+                if let Some(value) = f(events.recv().await.unwrap()) {
+                    return value;
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for event")
+    }
+
+    /// Export `local_addr` on port 80 of the onion of `remote_key`.
+    fn export(local_addr: LocalAddr, remote_key: onion::TorSecretKeyV3) -> Export {
+        Export {
+            local_addr,
+            remote_key,
+            remote_ports: vec![80],
+            authorized_clients: vec![],
+            connection: ConnectionOptions::default(),
+        }
+    }
+
+    /// Import port 80 of `onion_addr` to `local_addr`.
+    fn import(onion_addr: onion::OnionAddressV3, local_addr: LocalAddr) -> Import {
+        Import {
+            remote_addr: onion::OnionAddress::V3(onion_addr),
+            remote_port: 80,
+            local_addr,
+            client_key: None,
+            unix_socket: UnixSocketOptions::default(),
+            listen_fd_name: None,
+            retry: RetryPolicy::default(),
+            connection: ConnectionOptions::default(),
+        }
+    }
+
+    /// A pipe running in the background.
+    struct TestPipe {
+        events: broadcast::Receiver<OnionPipeEvent>,
+        forwards: ForwardHandle,
+        shutdown: ShutdownHandle,
+        task: JoinHandle<Result<()>>,
+    }
+
+    impl TestPipe {
+        async fn stop(self) {
+            self.shutdown.shutdown();
+            self.task.await.unwrap().unwrap();
+        }
+    }
+
+    /// Run the pipe of `builder` on `fake_tor`, returning once its forwards
+    /// have started.
+    async fn start(
+        builder: OnionPipeBuilder,
+        fake_tor: &FakeTor,
+        temp_dir: &std::path::Path,
+    ) -> TestPipe {
+        let (mut exports, mut imports) = (builder.exports.len(), builder.imports.len());
+        let mut onion_pipe = builder
+            .temp_dir(temp_dir.to_str().unwrap())
+            .tor_backend(fake_tor.backend())
+            .new()
+            .await
+            .unwrap();
+        let mut events = onion_pipe.subscribe();
+        let forwards = onion_pipe.forward_handle();
+        let shutdown = onion_pipe.shutdown_handle();
+        let task = tokio::spawn(async move { onion_pipe.run().await });
+        while exports + imports > 0 {
+            let published = next_event(&mut events, |event| match event {
+                OnionPipeEvent::OnionPublished { .. } => Some(true),
+                OnionPipeEvent::ImportBound { .. } => Some(false),
+                _ => None,
+            })
+            .await;
+            if published {
+                exports -= 1;
+            } else {
+                imports -= 1;
+            }
+        }
+        TestPipe {
+            events,
+            forwards,
+            shutdown,
+            task,
+        }
+    }
+
+    async fn echo(socket_path: &std::path::Path) -> std::io::Result<Vec<u8>> {
+        let mut stream = tokio::net::UnixStream::connect(socket_path).await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = vec![0u8; 5];
+        tokio::time::timeout(TIMEOUT, stream.read_exact(&mut buf)).await??;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn export_import_round_trip() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fake_tor = FakeTor::start().unwrap();
+        let remote_key = onion::TorSecretKeyV3::from([3u8; 64]);
+        let onion_addr = remote_key.public().get_onion_address();
+        let socket_path = tmp_dir.path().join("import.sock");
+
+        let builder = OnionPipe::defaults()
+            .export(export(echo_server().await, remote_key))
+            .import(import(onion_addr, LocalAddr::Unix(socket_path.clone())));
+        let mut pipe = start(builder, &fake_tor, tmp_dir.path()).await;
+        assert_eq!(fake_tor.onions(), vec![onion_addr]);
+        assert_eq!(
+            tokio::time::timeout(TIMEOUT, pipe.forwards.wait_published(&onion_addr))
+                .await
+                .unwrap()
+                .unwrap(),
+            1
+        );
+
+        assert_eq!(echo(&socket_path).await.unwrap(), b"hello");
+        let (bytes_sent, bytes_received) = next_event(&mut pipe.events, |event| match event {
+            OnionPipeEvent::ConnectionClosed {
+                bytes_sent,
+                bytes_received,
+                ..
+            } => Some((bytes_sent, bytes_received)),
+            _ => None,
+        })
+        .await;
+        assert_eq!((bytes_sent, bytes_received), (5, 5));
+
+        // A port the onion doesn't serve is refused.
+        pipe.forwards
+            .add_import(Import {
+                remote_port: 8080,
+                ..import(
+                    onion_addr,
+                    LocalAddr::Unix(tmp_dir.path().join("closed.sock")),
+                )
+            })
+            .await
+            .unwrap();
+        assert!(echo(&tmp_dir.path().join("closed.sock")).await.is_err());
+
+        // Removing an import closes the connections it carries.
+        let mut stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = vec![0u8; 5];
+        tokio::time::timeout(TIMEOUT, stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        pipe.forwards
+            .remove_import(&LocalAddr::Unix(socket_path.clone()))
+            .await
+            .unwrap();
+        let n = tokio::time::timeout(TIMEOUT, stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+
+        pipe.stop().await;
+        assert!(fake_tor.onions().is_empty());
+        assert!(!socket_path.exists());
+    }
+
+    #[tokio::test]
+    async fn client_authorization() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fake_tor = FakeTor::start().unwrap();
+        let client_key = crypto_box::SecretKey::from([5u8; 32]);
+        let remote_key = onion::TorSecretKeyV3::from([4u8; 64]);
+        let onion_addr = remote_key.public().get_onion_address();
+
+        let builder = OnionPipe::defaults().export(Export {
+            authorized_clients: vec![client_key.public_key()],
+            ..export(echo_server().await, remote_key)
+        });
+        let mut pipe = start(builder, &fake_tor, tmp_dir.path()).await;
+
+        let import = |name: &str, client_key: Option<crypto_box::SecretKey>| Import {
+            client_key,
+            ..import(onion_addr, LocalAddr::Unix(tmp_dir.path().join(name)))
+        };
+        pipe.forwards
+            .add_import(import("anonymous.sock", None))
+            .await
+            .unwrap();
+        assert!(echo(&tmp_dir.path().join("anonymous.sock")).await.is_err());
+        let error = next_event(&mut pipe.events, |event| match event {
+            OnionPipeEvent::ConnectionError { error, .. } => Some(error),
+            _ => None,
+        })
+        .await;
+        assert!(matches!(
+            *error,
+            crate::PipeError::Socks(crate::socks::SocksError::MissingClientAuth)
+        ));
+
+        pipe.forwards
+            .add_import(import("alice.sock", Some(client_key)))
+            .await
+            .unwrap();
+        assert_eq!(
+            echo(&tmp_dir.path().join("alice.sock")).await.unwrap(),
+            b"hello"
+        );
+
+        pipe.stop().await;
+    }
+
+    #[tokio::test]
+    async fn import_retries_until_published() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fake_tor = FakeTor::start().unwrap();
+        let remote_key = onion::TorSecretKeyV3::from([7u8; 64]);
+        let onion_addr = remote_key.public().get_onion_address();
+        let socket_path = tmp_dir.path().join("import.sock");
+
+        let builder = OnionPipe::defaults().import(Import {
+            retry: RetryPolicy {
+                attempts: 20,
+                backoff: Duration::from_millis(50),
+                max_backoff: Duration::from_millis(100),
+                deadline: Some(TIMEOUT),
+            },
+            ..import(onion_addr, LocalAddr::Unix(socket_path.clone()))
+        });
+        let pipe = start(builder, &fake_tor, tmp_dir.path()).await;
+
+        // The onion isn't exported yet, so the first attempts fail while the
+        // local connection is held open.
+        let client = tokio::spawn(async move { echo(&socket_path).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!client.is_finished());
+        pipe.forwards
+            .add_export(export(echo_server().await, remote_key))
+            .await
+            .unwrap();
+        assert_eq!(client.await.unwrap().unwrap(), b"hello");
+
+        pipe.stop().await;
+    }
+
+    #[tokio::test]
+    async fn export_idle_timeout() {
+        let (logs, _guard) = capture_logs();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fake_tor = FakeTor::start().unwrap();
+        let remote_key = onion::TorSecretKeyV3::from([8u8; 64]);
+        let onion_addr = remote_key.public().get_onion_address();
+        let socket_path = tmp_dir.path().join("import.sock");
+        let local_addr = echo_server().await;
+        let idle_timeout = Duration::from_millis(200);
+
+        let builder = OnionPipe::defaults()
+            .export(Export {
+                connection: ConnectionOptions {
+                    idle_timeout: Some(idle_timeout),
+                    nodelay: true,
+                    ..Default::default()
+                },
+                ..export(local_addr.clone(), remote_key)
+            })
+            .import(import(onion_addr, LocalAddr::Unix(socket_path.clone())));
+        let pipe = start(builder, &fake_tor, tmp_dir.path()).await;
+
+        // The relay closes the connection once it has been idle, which the
+        // import passes on to the local client.
+        let mut stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        let last_sent = tokio::time::Instant::now();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = vec![0u8; 5];
+        tokio::time::timeout(TIMEOUT, stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, b"hello");
+        let n = tokio::time::timeout(TIMEOUT, stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(n, 0);
+        assert!(last_sent.elapsed() >= idle_timeout);
+        let closed = format!(
+            "INFO onionpipe::relay: onion stream to {} closed: {}",
+            local_addr,
+            crate::PipeError::IdleTimeout(idle_timeout)
+        );
+        assert!(logs.lines().contains(&closed), "{:?}", logs.lines());
+
+        pipe.stop().await;
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fake_tor = FakeTor::start().unwrap();
+        let remote_key = onion::TorSecretKeyV3::from([9u8; 64]);
+        let onion_addr = remote_key.public().get_onion_address();
+        let socket_path = tmp_dir.path().join("import.sock");
+        let metrics_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let builder = OnionPipe::defaults()
+            .metrics_addr(metrics_addr)
+            .export(export(echo_server().await, remote_key))
+            .import(import(onion_addr, LocalAddr::Unix(socket_path.clone())));
+        let mut pipe = start(builder, &fake_tor, tmp_dir.path()).await;
+        // The export is counted through a relay which, unlike a local TCP
+        // port, only onionpipe's user can reach.
+        match fake_tor.targets(&onion_addr).get(&80) {
+            Some(LocalAddr::Unix(relay_path)) => assert!(relay_path.starts_with(tmp_dir.path())),
+            target => panic!("export relayed to {:?}", target),
+        }
+
+        assert_eq!(echo(&socket_path).await.unwrap(), b"hello");
+        next_event(&mut pipe.events, |event| match event {
+            OnionPipeEvent::ConnectionClosed { .. } => Some(()),
+            _ => None,
+        })
+        .await;
+
+        let response = http_get(metrics_addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let import_labels = format!(
+            "forward=\"import\",onion_addr=\"{}:80\",local_addr=\"unix:{}\"",
+            onion_addr,
+            socket_path.display()
+        );
+        for line in [
+            format!(
+                "onionpipe_connections_accepted_total{{{}}} 1",
+                import_labels
+            ),
+            format!("onionpipe_connections_active{{{}}} 0", import_labels),
+            format!("onionpipe_bytes_sent_total{{{}}} 5", import_labels),
+            format!("onionpipe_bytes_received_total{{{}}} 5", import_labels),
+            format!(
+                "onionpipe_import_connect_seconds_count{{{}}} 1",
+                import_labels
+            ),
+            "onionpipe_tor_bootstrap_percent 100".to_string(),
+        ] {
+            assert!(
+                response.lines().any(|l| l == line),
+                "{} not in:\n{}",
+                line,
+                response
+            );
+        }
+        assert!(response
+            .lines()
+            .any(|l| l.starts_with("onionpipe_tor_read_bytes_total ")));
+        // The export is relayed so that its connections are counted too.
+        assert!(response.lines().any(|l| l.starts_with(&format!(
+            "onionpipe_connections_accepted_total{{forward=\"export\",onion_addr=\"{}\"",
+            onion_addr
+        )) && l.ends_with(" 1")));
+
+        pipe.stop().await;
+    }
+
+    #[tokio::test]
+    async fn health() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fake_tor = FakeTor::start().unwrap();
+        let remote_key = onion::TorSecretKeyV3::from([10u8; 64]);
+        let onion_addr = remote_key.public().get_onion_address();
+        let socket_path = tmp_dir.path().join("import.sock");
+        let local_addr = echo_server().await;
+        let health_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let builder = OnionPipe::defaults()
+            .health_addr(health_addr)
+            .export(export(local_addr.clone(), remote_key))
+            .import(import(onion_addr, LocalAddr::Unix(socket_path.clone())));
+        let pipe = start(builder, &fake_tor, tmp_dir.path()).await;
+        tokio::time::timeout(TIMEOUT, pipe.forwards.wait_published(&onion_addr))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(http_get(health_addr, "/healthz")
+            .await
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+        let response = http_get(health_addr, "/readyz").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let health: Health = serde_json::from_str(body).unwrap();
+        assert!(health.ready);
+        assert_eq!(health.bootstrap.map(|phase| phase.progress), Some(100));
+        assert_eq!(
+            health
+                .forwards
+                .iter()
+                .map(|forward| (forward.ready, forward.status.to_string()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    true,
+                    format!(
+                        "export {} => {}:80 (uploaded to 1 HSDirs)",
+                        local_addr, onion_addr
+                    )
+                ),
+                (
+                    true,
+                    format!("import {}:80 => unix:{}", onion_addr, socket_path.display())
+                ),
+            ]
+        );
+
+        pipe.stop().await;
+    }
+
+    #[tokio::test]
+    async fn socket_activation() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fake_tor = FakeTor::start().unwrap();
+        let remote_key = onion::TorSecretKeyV3::from([11u8; 64]);
+        let onion_addr = remote_key.public().get_onion_address();
+        // A socket bound by the service manager, as if passed in LISTEN_FDS.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let import_addr = listener.local_addr().unwrap();
+
+        let builder = OnionPipe::defaults()
+            .listen_fds(ListenFds::new(vec![("web".to_string(), listener.into())]))
+            .export(export(echo_server().await, remote_key))
+            .import(import(onion_addr, LocalAddr::TCP(import_addr)));
+        let pipe = start(builder, &fake_tor, tmp_dir.path()).await;
+
+        let mut stream = tokio::net::TcpStream::connect(import_addr).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = vec![0u8; 5];
+        tokio::time::timeout(TIMEOUT, stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, b"hello");
+        pipe.stop().await;
+
+        // A passed socket that no import takes is refused.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut onion_pipe = OnionPipe::defaults()
+            .temp_dir(tmp_dir.path().to_str().unwrap())
+            .tor_backend(fake_tor.backend())
+            .listen_fds(ListenFds::new(vec![("admin".to_string(), listener.into())]))
+            .new()
+            .await
+            .unwrap();
+        match onion_pipe.run().await {
+            Err(crate::PipeError::Config(msg)) => assert!(msg.contains("admin"), "{}", msg),
+            result => panic!("expected a config error, got {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn startup_failure_stops_servers() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let health_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut onion_pipe = OnionPipe::defaults()
+            .temp_dir(tmp_dir.path().to_str().unwrap())
+            .tor_backend(TorBackend::External {
+                control_addr: LocalAddr::Unix(tmp_dir.path().join("missing.sock")),
+                socks_addr: LocalAddr::Unix(tmp_dir.path().join("socks.sock")),
+                auth: ControlAuth::Auto,
+            })
+            .health_addr(health_addr)
+            .new()
+            .await
+            .unwrap();
+        assert!(onion_pipe.run().await.is_err());
+
+        // The health server started before Tor is stopped with it.
+        tokio::time::timeout(TIMEOUT, async {
+            while tokio::net::TcpStream::connect(health_addr).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("health server still running");
+    }
+
+    #[tokio::test]
+    async fn forward_failure_stops_started_forwards() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fake_tor = FakeTor::start().unwrap();
+        let remote_key = onion::TorSecretKeyV3::from([12u8; 64]);
+        let onion_addr = remote_key.public().get_onion_address();
+        let socket_path = tmp_dir.path().join("import.sock");
+        // The second import can't bind an address that is already in use.
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut onion_pipe = OnionPipe::defaults()
+            .temp_dir(tmp_dir.path().to_str().unwrap())
+            .tor_backend(fake_tor.backend())
+            .export(export(echo_server().await, remote_key))
+            .import(import(onion_addr, LocalAddr::Unix(socket_path.clone())))
+            .import(import(
+                onion_addr,
+                LocalAddr::TCP(taken.local_addr().unwrap()),
+            ))
+            .new()
+            .await
+            .unwrap();
+        let mut events = onion_pipe.subscribe();
+        assert!(onion_pipe.run().await.is_err());
+
+        // The onion and the import started before the failure are removed.
+        let removed = next_event(&mut events, |event| match event {
+            OnionPipeEvent::OnionRemoved { onion_addr } => Some(onion_addr),
+            _ => None,
+        })
+        .await;
+        assert_eq!(removed, onion_addr);
+        assert!(!socket_path.exists());
+        assert!(fake_tor.onions().is_empty());
+    }
+
+    #[test]
+    fn route_errors() {
+        let state = Mutex::new(State::default());
+        let service_id = onion::TorSecretKeyV3::from([6u8; 64])
+            .public()
+            .get_onion_address()
+            .get_address_without_dot_onion();
+        let host = format!("{}.onion", service_id);
+        assert_eq!(route(&state, "example.com", 80), Err(0xf6));
+        assert_eq!(route(&state, &host, 80), Err(0xf0));
+
+        let target = LocalAddr::Unix("/tmp/target.sock".into());
+        state.lock().unwrap().onions.insert(
+            service_id.clone(),
+            Onion {
+                ports: HashMap::from([(80, target.clone())]),
+                authorized_clients: vec![],
+            },
+        );
+        assert_eq!(route(&state, &host, 80), Ok(target));
+        assert_eq!(route(&state, &host, 22), Err(0x05));
+    }
+}
@@ -0,0 +1,134 @@
+use std::ffi::CString;
+use std::io::BufRead;
+use std::os::unix::ffi::OsStrExt;
+use std::{fs, io, path, thread};
+
+use regex::Regex;
+use tracing::Level;
+
+use crate::Result;
+
+// Tor logs to a FIFO in its data directory, which is read back here and
+// re-emitted as tracing events with the "tor" target, so that Tor's logs end
+// up wherever onionpipe's own logs go.
+
+/// The Tor log level that produces the events enabled at `level`.
+pub fn tor_log_level(level: Level) -> libtor::log::LogLevel {
+    match level {
+        Level::ERROR => libtor::log::LogLevel::Err,
+        Level::WARN => libtor::log::LogLevel::Warn,
+        Level::INFO => libtor::log::LogLevel::Notice,
+        Level::DEBUG => libtor::log::LogLevel::Info,
+        Level::TRACE => libtor::log::LogLevel::Debug,
+    }
+}
+
+/// Create a FIFO at `fifo_path` for Tor to log to, and re-emit each line
+/// written to it to the current subscriber.
+pub fn capture(fifo_path: &path::Path) -> Result<()> {
+    let c_path = CString::new(fifo_path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let fifo_path = fifo_path.to_path_buf();
+    let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+    thread::Builder::new()
+        .name("tor-log".to_string())
+        .spawn(move || {
+            tracing::dispatcher::with_default(&dispatch, || {
+                // Blocks until Tor opens its end.
+                let fifo = match fs::File::open(&fifo_path) {
+                    Ok(fifo) => fifo,
+                    Err(err) => {
+                        tracing::warn!("failed to open tor log: {}", err);
+                        return;
+                    }
+                };
+                for line in io::BufReader::new(fifo).lines() {
+                    match line {
+                        Ok(line) => emit(&line),
+                        Err(_) => break,
+                    }
+                }
+            })
+        })?;
+    Ok(())
+}
+
+fn emit(line: &str) {
+    match parse_line(line) {
+        Some((Level::ERROR, message)) => tracing::error!(target: "tor", "{}", message),
+        Some((Level::WARN, message)) => tracing::warn!(target: "tor", "{}", message),
+        Some((Level::INFO, message)) => tracing::info!(target: "tor", "{}", message),
+        Some((Level::DEBUG, message)) => tracing::debug!(target: "tor", "{}", message),
+        Some((Level::TRACE, message)) => tracing::trace!(target: "tor", "{}", message),
+        None => tracing::info!(target: "tor", "{}", line),
+    }
+}
+
+/// Split a Tor log line such as
+/// `Oct 17 10:00:00.000 [notice] Bootstrapped 5%` into its level and message.
+fn parse_line(line: &str) -> Option<(Level, &str)> {
+    let re = Regex::new(r"^\w+ +\d+ [\d:.]+ \[(\w+)\] (.*)$").ok()?;
+    let captures = re.captures(line)?;
+    let level = match &captures[1] {
+        "err" => Level::ERROR,
+        "warn" => Level::WARN,
+        "notice" => Level::INFO,
+        "info" => Level::DEBUG,
+        "debug" => Level::TRACE,
+        _ => return None,
+    };
+    Some((level, captures.get(2)?.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            parse_line("Oct 17 10:00:00.000 [notice] Bootstrapped 5% (conn): Connecting"),
+            Some((Level::INFO, "Bootstrapped 5% (conn): Connecting"))
+        );
+        assert_eq!(
+            parse_line("Oct  7 10:00:00.000 [warn] Something odd"),
+            Some((Level::WARN, "Something odd"))
+        );
+        assert_eq!(parse_line("not a tor log line"), None);
+    }
+
+    #[test]
+    fn test_capture() {
+        use std::io::Write;
+        use std::os::unix::fs::FileTypeExt;
+        use std::time::{Duration, Instant};
+
+        let (logs, _guard) = crate::testing::capture_logs();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fifo_path = tmp_dir.path().join("tor.log");
+        capture(&fifo_path).unwrap();
+        assert!(fs::metadata(&fifo_path).unwrap().file_type().is_fifo());
+        let mut writer = fs::OpenOptions::new()
+            .append(true)
+            .open(&fifo_path)
+            .unwrap();
+        writer
+            .write_all(b"Oct 17 10:00:00.000 [warn] hello\nnot a tor log line\n")
+            .unwrap();
+        assert!(capture(&fifo_path).is_err());
+
+        // The lines are read back on another thread.
+        let expected = vec![
+            "WARN tor: hello".to_string(),
+            "INFO tor: not a tor log line".to_string(),
+        ];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while logs.lines() != expected {
+            assert!(Instant::now() < deadline, "{:?}", logs.lines());
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
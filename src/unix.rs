@@ -0,0 +1,181 @@
+use std::ffi::CString;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::{fs, io, path, ptr};
+
+use crate::{PipeError, Result};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UnixSocketOptions {
+    pub mode: Option<u32>,
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+}
+
+impl UnixSocketOptions {
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.owner.is_none() && self.group.is_none()
+    }
+}
+
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(PipeError::Config(format!(
+            "invalid unix socket mode {}",
+            mode
+        ))),
+    }
+}
+
+pub fn lookup_user(user: &str) -> Result<u32> {
+    if let Ok(uid) = user.parse::<u32>() {
+        return Ok(uid);
+    }
+    let name = CString::new(user).map_err(|_| unknown_user(user))?;
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let rc = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match rc {
+            0 if !result.is_null() => return Ok(pwd.pw_uid),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            _ => return Err(unknown_user(user)),
+        }
+    }
+}
+
+pub fn lookup_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| unknown_group(group))?;
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let rc = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut grp,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match rc {
+            0 if !result.is_null() => return Ok(grp.gr_gid),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            _ => return Err(unknown_group(group)),
+        }
+    }
+}
+
+fn unknown_user(user: &str) -> PipeError {
+    PipeError::Config(format!("unknown user {}", user))
+}
+
+fn unknown_group(group: &str) -> PipeError {
+    PipeError::Config(format!("unknown group {}", group))
+}
+
+/// Bind a unix socket listener, replacing a stale socket left behind at the
+/// same path, then apply the requested ownership and mode. A socket that
+/// still accepts connections belongs to a live process and is left alone.
+pub fn bind(
+    socket_path: &path::Path,
+    options: &UnixSocketOptions,
+) -> Result<tokio::net::UnixListener> {
+    match fs::symlink_metadata(socket_path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(socket_path) {
+                Ok(_) => {
+                    return Err(PipeError::IO {
+                        source: io::Error::new(
+                            io::ErrorKind::AddrInUse,
+                            format!("{} is in use by another process", socket_path.display()),
+                        ),
+                    })
+                }
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                    fs::remove_file(socket_path)?
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(_) => {
+            return Err(PipeError::IO {
+                source: io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", socket_path.display()),
+                ),
+            })
+        }
+        Err(_) => {}
+    }
+    let listener = tokio::net::UnixListener::bind(socket_path)?;
+    if options.owner.is_some() || options.group.is_some() {
+        std::os::unix::fs::chown(socket_path, options.owner, options.group)?;
+    }
+    if let Some(mode) = options.mode {
+        fs::set_permissions(socket_path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0660").unwrap(), 0o660);
+        assert_eq!(parse_mode("600").unwrap(), 0o600);
+        assert!(parse_mode("0999").is_err());
+        assert!(parse_mode("77777").is_err());
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root").unwrap(), 0);
+        assert_eq!(lookup_user("1234").unwrap(), 1234);
+        assert!(lookup_user("no-such-user-onionpipe").is_err());
+        assert_eq!(lookup_group("0").unwrap(), 0);
+        assert!(lookup_group("no-such-group-onionpipe").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let socket_path = tmp_dir.path().join("import.sock");
+        let options = UnixSocketOptions {
+            mode: Some(0o660),
+            ..Default::default()
+        };
+        let listener = bind(&socket_path, &options).unwrap();
+        let meta = fs::metadata(&socket_path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o660);
+
+        // A live socket is not replaced.
+        match bind(&socket_path, &options) {
+            Err(PipeError::IO { source }) => assert_eq!(source.kind(), io::ErrorKind::AddrInUse),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert!(socket_path.exists());
+
+        // A stale socket is replaced, other files are not.
+        drop(listener);
+        bind(&socket_path, &options).unwrap();
+        let file_path = tmp_dir.path().join("not-a-socket");
+        fs::write(&file_path, b"").unwrap();
+        assert!(bind(&file_path, &options).is_err());
+    }
+}